@@ -1,8 +1,7 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
-use std::iter::Zip;
 
 use tempfile::TempDir;
 
@@ -20,7 +19,7 @@ fn generate_random_strings(rng: &mut ThreadRng, size: usize, item_size_max: usiz
 
 pub fn kvs_write_benchmark(c: &mut Criterion) {
     let temp_dir = TempDir::new().unwrap();
-    let mut engine: KvStore = KvStore::open(temp_dir.path()).unwrap();
+    let engine: KvStore = KvStore::open(temp_dir.path()).unwrap();
     let mut rng = rand::thread_rng();
     let random_keys: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
     let random_values: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
@@ -37,7 +36,7 @@ pub fn kvs_write_benchmark(c: &mut Criterion) {
 
 pub fn kvs_read_benchmark(c: &mut Criterion) {
     let temp_dir = TempDir::new().unwrap();
-    let mut engine: KvStore = KvStore::open(temp_dir.path()).unwrap();
+    let engine: KvStore = KvStore::open(temp_dir.path()).unwrap();
     let mut rng = rand::thread_rng();
     let random_keys: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
     let random_values: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
@@ -57,7 +56,7 @@ pub fn kvs_read_benchmark(c: &mut Criterion) {
 
 pub fn sled_write_benchmark(c: &mut Criterion) {
     let temp_dir = TempDir::new().unwrap();
-    let mut engine: SledKvsEngine = SledKvsEngine::open(temp_dir.path()).unwrap();
+    let engine: SledKvsEngine = SledKvsEngine::open(temp_dir.path()).unwrap();
     let mut rng = rand::thread_rng();
     let random_keys: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
     let random_values: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
@@ -74,7 +73,7 @@ pub fn sled_write_benchmark(c: &mut Criterion) {
 
 pub fn sled_read_benchmark(c: &mut Criterion) {
     let temp_dir = TempDir::new().unwrap();
-    let mut engine: SledKvsEngine = SledKvsEngine::open(temp_dir.path()).unwrap();
+    let engine: SledKvsEngine = SledKvsEngine::open(temp_dir.path()).unwrap();
     let mut rng = rand::thread_rng();
     let random_keys: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
     let random_values: Vec<String> = generate_random_strings(&mut rng, 100, 100000);
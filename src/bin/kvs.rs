@@ -1,8 +1,9 @@
 use clap::{App, Arg, SubCommand};
-use kvs::{KvStore, Repr, Result, KvsEngine};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
 
 use std::path::Path;
 use std::process;
+use std::time::Duration;
 
 /// Basic behavior:
 /// "set"
@@ -12,6 +13,7 @@ use std::process;
 ///   - It then appends the serialized command to a file containing the log.
 ///   - If that succeeds, it exits silently with error code 0.
 ///   - If it fails, it exits by printing the error and returning a non-zero error code.
+///
 /// "get"
 ///   - The user invokes `kvs get mykey`
 ///   - `kvs` reads the entire log, one command at a time, recording the affected key and
@@ -21,6 +23,7 @@ use std::process;
 ///   - If it succeeds:
 ///      - It deserializes the command to get the last recorded value of the key.
 ///      - It prints the value to stdout and exits with exit code 0.
+///
 /// "rm"
 ///   - The user invokes `kvs rm mykey`.
 ///   - Same as the "get" command, `kvs` reads the entire log to build the in-memory index.
@@ -53,6 +56,14 @@ fn get_app() -> App<'static, 'static> {
                         .help("relative value")
                         .takes_value(true)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .help("seconds before the key expires")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .required(false),
                 ),
         )
         .subcommand(
@@ -92,7 +103,15 @@ fn main() -> Result<()> {
     if let Some(sub_matches) = matches.subcommand_matches("set") {
         let key: &str = sub_matches.value_of("key").unwrap();
         let value: &str = sub_matches.value_of("value").unwrap();
-        do_set(&mut store, key, value)?;
+        match sub_matches.value_of("ttl") {
+            Some(ttl) => {
+                let ttl: u64 = ttl.parse::<u64>().map_err(|e| {
+                    KvsError::from_string(&format!("Invalid ttl {}: {}", ttl, e))
+                })?;
+                do_set_with_ttl(&mut store, key, value, Duration::from_secs(ttl))?;
+            }
+            None => do_set(&mut store, key, value)?,
+        }
     } else if let Some(sub_matches) = matches.subcommand_matches("get") {
         let key: &str = sub_matches.value_of("key").unwrap();
 
@@ -111,13 +130,11 @@ fn main() -> Result<()> {
         let key: &str = sub_matches.value_of("key").unwrap();
 
         if let Err(e) = do_remove(&mut store, key) {
-            match e.repr() {
-                Repr::CommandError(_) => {
-                    println!("Key not found");
-                    process::exit(1);
-                }
-                _ => return Err(e),
+            if !e.is_key_not_found() {
+                return Err(e);
             }
+            println!("Key not found");
+            process::exit(1);
         }
     } else {
         process::exit(1);
@@ -130,6 +147,11 @@ fn do_set(store: &mut KvStore, key: &str, value: &str) -> Result<()> {
     store.set(String::from(key), String::from(value))
 }
 
+/// execute kvs set command with --ttl
+fn do_set_with_ttl(store: &mut KvStore, key: &str, value: &str, ttl: Duration) -> Result<()> {
+    store.set_with_ttl(String::from(key), String::from(value), ttl)
+}
+
 /// execute kvs remove command
 fn do_remove(store: &mut KvStore, key: &str) -> Result<()> {
     store.remove(String::from(key))
@@ -1,30 +1,350 @@
 //! The kvs-client executable supports the following command line arguments:
 //!
-//!     kvs-client set <KEY> <VALUE> [--addr IP-PORT]
+//!     kvs-client set <KEY> <VALUE> [--addr IP-PORT | --servers IP-PORT,IP-PORT,...] [--ttl SECONDS]
 //!
 //!     Set the value of a string key to a string.
+//!     --ttl makes the key expire after the given seconds, which are counted from when the server receives the request.
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address.
 //!
-//!     kvs-client get <KEY> [--addr IP-PORT]
+//!     kvs-client get <KEY> [--addr IP-PORT | --servers IP-PORT,IP-PORT,...]
 //!     Get the string value of a given string key.
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address.
 //!
-//!     kvs-client rm <KEY> [--addr IP-PORT]
+//!     kvs-client rm <KEY> [--addr IP-PORT | --servers IP-PORT,IP-PORT,...]
 //!     Remove a given string key.
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address. A "key not found" is also treated as an error in the "rm" command.
 //!
+//!     --encoding accepts "text", "hex" or "base64", it's how keys and values on the command line and the printed value are
+//!     encoded, so binary data can be stored.  If --encoding is not specified then keys and values are text.
+//!
+//!     --timeout accepts seconds, fractions are allowed, it's how long to wait for connecting to the server, sending a
+//!     request and receiving a response.  A "get" is retried a few times if it fails.  If --timeout is not specified
+//!     then wait forever.
+//!
+//!     --tls-ca accepts a PEM file of CAs, the client connects over TLS and trusts servers whose certificates are signed by
+//!     them.  The certificate must be valid for the host of --addr.  --tls-cert and --tls-key accept PEM files of the
+//!     certificate chain and the private key of the client, they are presented to servers which require client certificates.
+//!
+//!     --token, or --user and --password, are credentials to log in to servers which require authentication.
+//!
+//!     --servers accepts addresses of several servers separated by commas, instead of --addr.  Keys are sharded across them
+//!     by consistent hashing, every client must list the same servers.
+//!
+//!     kvs-client pipe [--addr IP-PORT]
+//!     Read commands from stdin, one per line, in the form of `set <KEY> <VALUE>`, `get <KEY>` or `rm <KEY>`.  They are
+//!     pipelined over a single connection, and results of "get" commands are printed in order.  Return a non-zero exit
+//!     code if any "set" or "rm" command fails.
+//!
+//!     kvs-client watch <PREFIX> [--addr IP-PORT]
+//!     Watch changes of keys with the prefix, an empty prefix watches all keys.  A line is printed for every change, in
+//!     the form of `set <KEY> <VALUE>` or `rm <KEY>`, until the server is stopped.  Keys which expire are not printed.
+//!
 //!     kvs-client -V
 //!     Print the version.
 //! All error messages should be printed to stderr.
 
-use clap::{App, Arg, SubCommand};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use clap::{App, Arg, ArgMatches, SubCommand};
 use kvs::command::Instruction;
-use kvs::Response;
-use kvs::{Client, Result};
+use kvs::{
+    Client, ClientConfig, ClientPool, ClientTls, Credentials, KvsEngine, KvsError, Result,
+    ShardedClient, WatchEvent,
+};
+use std::io::{self, BufRead};
+use std::path::Path;
 use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Encoding of keys and values on the command line.
+enum Encoding {
+    Text,
+    Hex,
+    Base64,
+}
+
+impl FromStr for Encoding {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Encoding::Text),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            _ => Err(KvsError::from_string(&format!(
+                "Unsupported encoding {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Encoding {
+    fn from_matches(matches: &ArgMatches) -> Result<Encoding> {
+        Encoding::from_str(matches.value_of("encoding").unwrap_or("text"))
+    }
+
+    fn decode(&self, input: &str) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Text => Ok(input.as_bytes().to_vec()),
+            Encoding::Hex => hex::decode(input)
+                .map_err(|e| KvsError::from_string(&format!("Invalid hex {}: {}", input, e))),
+            Encoding::Base64 => BASE64
+                .decode(input)
+                .map_err(|e| KvsError::from_string(&format!("Invalid base64 {}: {}", input, e))),
+        }
+    }
+
+    fn encode(&self, output: &[u8]) -> String {
+        match self {
+            Encoding::Text => String::from_utf8_lossy(output).into_owned(),
+            Encoding::Hex => hex::encode(output),
+            Encoding::Base64 => BASE64.encode(output),
+        }
+    }
+}
+
+fn client_config(matches: &ArgMatches) -> Result<ClientConfig> {
+    let mut config: ClientConfig = ClientConfig::default();
+    if let Some(timeout) = matches.value_of("timeout") {
+        let timeout: Duration = timeout
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| *seconds > 0.0)
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| KvsError::from_string(&format!("Invalid timeout {}", timeout)))?;
+        config.connect_timeout = Some(timeout);
+        config.read_timeout = Some(timeout);
+        config.write_timeout = Some(timeout);
+    }
+    config.tls = client_tls(matches)?;
+    config.credentials = credentials(matches);
+    Ok(config)
+}
+
+fn credentials(matches: &ArgMatches) -> Option<Credentials> {
+    match (
+        matches.value_of("token"),
+        matches.value_of("user"),
+        matches.value_of("password"),
+    ) {
+        (Some(token), _, _) => Some(Credentials::Token(token.to_owned())),
+        (None, Some(user), Some(password)) => Some(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+fn client_tls(matches: &ArgMatches) -> Result<Option<ClientTls>> {
+    let ca: &Path = match matches.value_of("tls-ca") {
+        Some(ca) => Path::new(ca),
+        None => return Ok(None),
+    };
+    let tls: ClientTls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => ClientTls::with_identity(ca, Path::new(cert), Path::new(key))?,
+        _ => ClientTls::from_pem_file(ca)?,
+    };
+    Ok(Some(tls))
+}
+
+/// Argument of the server address, of all subcommands.
+fn addr_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("addr")
+        .long("addr")
+        .help("address to connect to server")
+        .takes_value(true)
+        .value_name("IP-PORT")
+        .required(false)
+}
+
+/// Argument of the encoding of keys and values, of all subcommands.
+fn encoding_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("encoding")
+        .long("encoding")
+        .help("encoding of keys and values, for binary data")
+        .takes_value(true)
+        .value_name("ENCODING")
+        .possible_values(&["text", "hex", "base64"])
+        .required(false)
+}
+
+/// Argument of the timeout of requests, of all subcommands.
+fn timeout_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("timeout")
+        .long("timeout")
+        .help("seconds to wait for connecting, sending and receiving")
+        .takes_value(true)
+        .value_name("SECONDS")
+        .required(false)
+}
+
+/// Authentication arguments of all subcommands.
+fn auth_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("token")
+            .long("token")
+            .help("token to log in")
+            .takes_value(true)
+            .value_name("TOKEN")
+            .conflicts_with("user")
+            .required(false),
+        Arg::with_name("user")
+            .long("user")
+            .help("user name to log in")
+            .takes_value(true)
+            .value_name("USER")
+            .requires("password")
+            .required(false),
+        Arg::with_name("password")
+            .long("password")
+            .help("password to log in")
+            .takes_value(true)
+            .value_name("PASSWORD")
+            .requires("user")
+            .required(false),
+    ]
+}
+
+/// TLS arguments of all subcommands.
+fn tls_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("tls-ca")
+            .long("tls-ca")
+            .help("PEM file of CAs which sign the server certificate, to connect over TLS")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(false),
+        Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("PEM file of the client certificate chain, for mutual TLS")
+            .takes_value(true)
+            .value_name("PATH")
+            .requires_all(&["tls-ca", "tls-key"])
+            .required(false),
+        Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("PEM file of the client private key, for mutual TLS")
+            .takes_value(true)
+            .value_name("PATH")
+            .requires_all(&["tls-ca", "tls-cert"])
+            .required(false),
+    ]
+}
+
+/// Argument of servers to shard keys across, for `set`, `get` and `rm`.
+fn servers_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("servers")
+        .long("servers")
+        .help("addresses of servers to shard keys across, separated by commas")
+        .takes_value(true)
+        .value_name("IP-PORT,IP-PORT,...")
+        .conflicts_with("addr")
+        .required(false)
+}
+
+fn parse_ttl(ttl: &str) -> Result<u64> {
+    ttl.parse::<u64>()
+        .map_err(|e| KvsError::from_string(&format!("Invalid ttl {}: {}", ttl, e)))
+}
+
+/// Parse a line of `pipe` command, it's `set <KEY> <VALUE>`, `get <KEY>` or `rm <KEY>`.
+fn parse_command(encoding: &Encoding, line: &str) -> Result<Instruction> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["set", key, value] => Ok(Instruction::Set {
+            key: encoding.decode(key)?,
+            value: encoding.decode(value)?,
+            expire_at: None,
+        }),
+        ["get", key] => Ok(Instruction::Get {
+            key: encoding.decode(key)?,
+        }),
+        ["rm", key] => Ok(Instruction::Rm {
+            key: encoding.decode(key)?,
+        }),
+        _ => Err(KvsError::from_string(&format!("Invalid command {}", line))),
+    }
+}
+
+fn client_pool(matches: &ArgMatches, default_addr: &str) -> Result<ClientPool> {
+    Ok(ClientPool::new(
+        matches.value_of("addr").unwrap_or(default_addr),
+        client_config(matches)?,
+    ))
+}
+
+// Client of `--servers` if it's given.
+fn sharded_client(matches: &ArgMatches) -> Result<Option<ShardedClient>> {
+    match matches.value_of("servers") {
+        Some(servers) => {
+            let servers: Vec<String> = servers
+                .split(',')
+                .map(|server| server.trim().to_owned())
+                .collect();
+            Ok(Some(ShardedClient::new(servers, client_config(matches)?)?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn run_set<E: KvsEngine>(client: &E, sub_m: &ArgMatches) -> Result<()> {
+    let encoding: Encoding = Encoding::from_matches(sub_m)?;
+    let key: Vec<u8> = encoding.decode(sub_m.value_of("key").unwrap())?;
+    let value: Vec<u8> = encoding.decode(sub_m.value_of("value").unwrap())?;
+    let result: Result<()> = match sub_m.value_of("ttl") {
+        Some(ttl) => client.set_bytes_with_ttl(key, value, Duration::from_secs(parse_ttl(ttl)?)),
+        None => client.set_bytes(key, value),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_get<E: KvsEngine>(client: &E, sub_m: &ArgMatches) -> Result<()> {
+    let encoding: Encoding = Encoding::from_matches(sub_m)?;
+    let key: Vec<u8> = encoding.decode(sub_m.value_of("key").unwrap())?;
+
+    match client.get_bytes(&key)? {
+        Some(value) => println!("{}", encoding.encode(&value)),
+        None => println!("Key not found"),
+    }
+    Ok(())
+}
+
+fn run_rm<E: KvsEngine>(client: &E, sub_m: &ArgMatches) -> Result<()> {
+    let encoding: Encoding = Encoding::from_matches(sub_m)?;
+    let key: Vec<u8> = encoding.decode(sub_m.value_of("key").unwrap())?;
+
+    if let Err(e) = client.remove_bytes(&key) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_watch(client: Client, sub_m: &ArgMatches) -> Result<()> {
+    let encoding: Encoding = Encoding::from_matches(sub_m)?;
+    let prefix: Vec<u8> = encoding.decode(sub_m.value_of("prefix").unwrap())?;
+
+    for event in client.watch(&prefix)? {
+        match event? {
+            WatchEvent::Set { key, value } => {
+                println!("set {} {}", encoding.encode(&key), encoding.encode(&value))
+            }
+            WatchEvent::Remove { key } => println!("rm {}", encoding.encode(&key)),
+        }
+    }
+    Ok(())
+}
 
 fn main() -> Result<()> {
     let app: App = App::new(env!("CARGO_PKG_NAME"))
@@ -46,13 +366,19 @@ fn main() -> Result<()> {
                         .required(true),
                 )
                 .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .help("address to connect to server")
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .help("seconds before the key expires")
                         .takes_value(true)
-                        .value_name("IP-PORT")
+                        .value_name("SECONDS")
                         .required(false),
-                ),
+                )
+                .arg(addr_arg())
+                .arg(encoding_arg())
+                .arg(timeout_arg())
+                .arg(servers_arg())
+                .args(&tls_args())
+                .args(&auth_args()),
         )
         .subcommand(
             SubCommand::with_name("get")
@@ -62,14 +388,12 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .required(true),
                 )
-                .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .help("address to connect to server")
-                        .takes_value(true)
-                        .value_name("IP-PORT")
-                        .required(false),
-                ),
+                .arg(addr_arg())
+                .arg(encoding_arg())
+                .arg(timeout_arg())
+                .arg(servers_arg())
+                .args(&tls_args())
+                .args(&auth_args()),
         )
         .subcommand(
             SubCommand::with_name("rm")
@@ -79,68 +403,95 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .required(true),
                 )
+                .arg(addr_arg())
+                .arg(encoding_arg())
+                .arg(timeout_arg())
+                .arg(servers_arg())
+                .args(&tls_args())
+                .args(&auth_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("pipe")
+                .about("Read commands from stdin, and send them over one connection")
+                .arg(addr_arg())
+                .arg(encoding_arg())
+                .arg(timeout_arg())
+                .args(&tls_args())
+                .args(&auth_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print changes of keys with the prefix")
                 .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .help("address to connect to server")
+                    Arg::with_name("prefix")
+                        .help("prefix of keys to watch")
                         .takes_value(true)
-                        .value_name("IP-PORT")
-                        .required(false),
-                ),
+                        .required(true),
+                )
+                .arg(addr_arg())
+                .arg(encoding_arg())
+                .arg(timeout_arg())
+                .args(&tls_args())
+                .args(&auth_args()),
         );
     let matches = app.get_matches();
     let default_addr: &str = "127.0.0.1:4000";
 
     match matches.subcommand() {
-        ("set", Some(sub_m)) => {
-            let mut client: Client =
-                Client::connect(sub_m.value_of("addr").unwrap_or(default_addr))?;
-            let instruction: Instruction = Instruction::Set {
-                key: String::from(sub_m.value_of("key").unwrap()),
-                value: String::from(sub_m.value_of("value").unwrap()),
-            };
-
-            client.send_instruction(&instruction)?;
-
-            let response: Response = client.read_response()?;
-            if !response.is_ok() {
-                eprintln!("{}", response.get_message());
-                process::exit(1);
+        ("set", Some(sub_m)) => match sharded_client(sub_m)? {
+            Some(client) => run_set(&client, sub_m)?,
+            None => run_set(&client_pool(sub_m, default_addr)?, sub_m)?,
+        },
+        ("get", Some(sub_m)) => match sharded_client(sub_m)? {
+            Some(client) => run_get(&client, sub_m)?,
+            None => run_get(&client_pool(sub_m, default_addr)?, sub_m)?,
+        },
+        ("rm", Some(sub_m)) => match sharded_client(sub_m)? {
+            Some(client) => run_rm(&client, sub_m)?,
+            None => run_rm(&client_pool(sub_m, default_addr)?, sub_m)?,
+        },
+        ("pipe", Some(sub_m)) => {
+            let mut client: Client = Client::connect_with(
+                sub_m.value_of("addr").unwrap_or(default_addr),
+                &client_config(sub_m)?,
+            )?;
+            let encoding: Encoding = Encoding::from_matches(sub_m)?;
+            let mut instructions: Vec<Instruction> = Vec::new();
+            for line in io::stdin().lock().lines() {
+                let line: String = line?;
+                if !line.trim().is_empty() {
+                    instructions.push(parse_command(&encoding, &line)?);
+                }
             }
-        }
-        ("get", Some(sub_m)) => {
-            let mut client: Client =
-                Client::connect(sub_m.value_of("addr").unwrap_or(default_addr))?;
-            let instruction: Instruction = Instruction::Get {
-                key: String::from(sub_m.value_of("key").unwrap()),
-            };
-
-            client.send_instruction(&instruction)?;
-
-            let response: Response = client.read_response()?;
-            if response.is_ok() {
-                println!("{}", response.get_body());
-            } else {
-                println!("{}", response.get_message());
+
+            let mut failed: bool = false;
+            for (instruction, response) in instructions.iter().zip(client.pipeline(&instructions)?)
+            {
+                match instruction {
+                    Instruction::Get { .. } if response.is_ok() => {
+                        println!("{}", encoding.encode(response.get_body()))
+                    }
+                    Instruction::Get { .. } => println!("{}", response.get_message()),
+                    _ if !response.is_ok() => {
+                        eprintln!("{}", response.get_message());
+                        failed = true;
+                    }
+                    _ => {}
+                }
             }
-        }
-        ("rm", Some(sub_m)) => {
-            let mut client: Client =
-                Client::connect(sub_m.value_of("addr").unwrap_or(default_addr))?;
-            let instruction: Instruction = Instruction::Rm {
-                key: String::from(sub_m.value_of("key").unwrap()),
-            };
-
-            client.send_instruction(&instruction)?;
-
-            let response: Response = client.read_response()?;
-            if !response.is_ok() {
-                eprintln!("{}", response.get_message());
+            if failed {
                 process::exit(1);
             }
         }
+        ("watch", Some(sub_m)) => {
+            let client: Client = Client::connect_with(
+                sub_m.value_of("addr").unwrap_or(default_addr),
+                &client_config(sub_m)?,
+            )?;
+            run_watch(client, sub_m)?;
+        }
         (&_, _) => {
-            eprintln!("You need to provide commands, for now the supported commands are `set`, `get`, `rm`");
+            eprintln!("You need to provide commands, for now the supported commands are `set`, `get`, `rm`, `pipe`, `watch`");
             process::exit(1);
         }
     }
@@ -1,23 +1,45 @@
 //! The kvs-server executable supports the following command line arguments:
-//!     kvs-server [--addr IP-PORT] [--engine ENGINE-NAME]
+//!     kvs-server [--addr IP-PORT] [--engine ENGINE-NAME] [--protocol PROTOCOL-NAME] [--http-addr IP-PORT] [--tls-cert PATH --tls-key PATH [--tls-client-ca PATH]] [--auth-config PATH] [--replica-of IP-PORT [--replica-token TOKEN | --replica-user USER --replica-password PASSWORD] | --cluster IP-PORT,IP-PORT,...]
 //!     Start the server and begin listening for incoming connections. --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT.
 //! If --addr is not specified then listen on 127.0.0.1:4000.
 //! If --engine is specified, then ENGINE-NAME must be either "kvs", in which case the built-in engine is used, or "sled", in which case sled is used.
 //! If this is the first run (there is no data previously persisted) then the default value is "kvs"; if there is previously persisted data then the default is the engine already in use.
 //! If data was previously persisted with a different engine than selected, print an error and exit with a non-zero exit code.
 //! Print an error and return a non-zero exit code on failure to bind a socket, if ENGINE-NAME is invalid, if IP-PORT does not parse as an address.
+//! If --protocol is specified, then PROTOCOL-NAME must be either "kvs", the default JSON instruction protocol, or "resp", in which
+//! case redis clients can send GET/SET/DEL/EXISTS/PING/SCAN/MGET/MSET commands.
+//! If --http-addr is specified, the HTTP/JSON gateway listens on it as well, see `GET/PUT/DELETE /keys/{key}` and `GET /keys?prefix=`.
+//! If --tls-cert and --tls-key are specified, clients are served over TLS with the certificate chain and the private key in
+//! the PEM files, on the HTTP gateway as well.  If --tls-client-ca is specified too, clients must present certificates
+//! signed by the CAs in the PEM file.
+//! If --auth-config is specified, clients must log in as users of the JSON file, and requests are checked by ACLs of key
+//! prefixes of the users.  It's only supported by the "kvs" protocol without --http-addr.
+//! If --replica-of is specified, the server follows the leader at the address as a read-only replica, it receives a
+//! snapshot of the leader, then writes of the leader as they are applied.  Writes of clients are rejected.
+//! The replication position is kept in memory, so the whole snapshot is received again after either server
+//! restarts, or after the replica is disconnected for more than 30 seconds or lags behind more than 16 MiB of writes.
+//! --replica-token, or --replica-user and --replica-password, are credentials to log in to a leader which requires
+//! authentication, the user must be allowed to read all keys.
+//! If --cluster is specified, the server is a node of the Raft cluster of the addresses, --addr must be one of them, and
+//! every node must list them in the same order.  The Raft log is kept in ./raft, it's compacted once entries are applied
+//! to the engine.  Only the elected leader serves clients, other nodes redirect them to it.
+//! On SIGINT or SIGTERM, the server stops accepting connections, finishes received requests, flushes the engine and exits.
 //!     kvs-server -V
 //!     Print the version.
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use env_logger::Target;
 use kvs::thread_pool::{self, NaiveThreadPool, ThreadPool};
-use kvs::{Engine, KvStore, Result, Server, SledKvsEngine};
+use kvs::{
+    AuthConfig, ClientConfig, Credentials, Engine, KvStore, KvsEngine, KvsError, Protocol,
+    RaftConfig, Result, Server, ServerTls, ShutdownHandle, SledKvsEngine,
+};
 use log::info;
 use log::LevelFilter;
 use std::path::Path;
 use std::process;
 use std::str::FromStr;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     env_logger::builder()
@@ -43,11 +65,110 @@ fn main() -> Result<()> {
                 .long("engine")
                 .value_name("ENGINE-NAME")
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .help("address to listen for the HTTP gateway")
+                .long("http-addr")
+                .value_name("IP-PORT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .help("protocol to speak with clients")
+                .long("protocol")
+                .value_name("PROTOCOL-NAME")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .help("PEM file of the certificate chain, to serve over TLS")
+                .long("tls-cert")
+                .value_name("PATH")
+                .takes_value(true)
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .help("PEM file of the private key, to serve over TLS")
+                .long("tls-key")
+                .value_name("PATH")
+                .takes_value(true)
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .help("PEM file of CAs which sign client certificates, to require them")
+                .long("tls-client-ca")
+                .value_name("PATH")
+                .takes_value(true)
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::with_name("auth-config")
+                .help("JSON file of users and ACLs, to require clients to log in")
+                .long("auth-config")
+                .value_name("PATH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replica-of")
+                .help(
+                    "address of the leader to follow, as a read-only replica; a full snapshot \
+                     is received again after restarts or disconnects longer than 30 seconds",
+                )
+                .long("replica-of")
+                .value_name("IP-PORT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replica-token")
+                .help("token to log in to the leader")
+                .long("replica-token")
+                .value_name("TOKEN")
+                .takes_value(true)
+                .requires("replica-of")
+                .conflicts_with("replica-user"),
+        )
+        .arg(
+            Arg::with_name("replica-user")
+                .help("user name to log in to the leader")
+                .long("replica-user")
+                .value_name("USER")
+                .takes_value(true)
+                .requires("replica-of")
+                .requires("replica-password"),
+        )
+        .arg(
+            Arg::with_name("replica-password")
+                .help("password to log in to the leader")
+                .long("replica-password")
+                .value_name("PASSWORD")
+                .takes_value(true)
+                .requires("replica-user"),
+        )
+        .arg(
+            Arg::with_name("cluster")
+                .help("addresses of all nodes of the Raft cluster, separated by commas")
+                .long("cluster")
+                .value_name("IP-PORT,IP-PORT,...")
+                .takes_value(true)
+                .conflicts_with("replica-of"),
         );
 
     let matches = app.get_matches();
     let addr: &str = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
     let engine: Engine = Engine::from_str(matches.value_of("engine").unwrap_or("kvs"))?;
+    let protocol: Protocol = Protocol::from_str(matches.value_of("protocol").unwrap_or("kvs"))?;
+    let http_addr: Option<&str> = matches.value_of("http-addr");
+    let tls: Option<ServerTls> = server_tls(&matches)?;
+    let auth: Option<AuthConfig> = match matches.value_of("auth-config") {
+        Some(path) => Some(AuthConfig::from_file(Path::new(path))?),
+        None => None,
+    };
+    let replica_of: Option<&str> = matches.value_of("replica-of");
+    let replica_credentials: Option<Credentials> = replica_credentials(&matches);
+    let cluster: Option<RaftConfig> = cluster_config(&matches, addr)?;
 
     let pool = thread_pool::NaiveThreadPool::new(10)?;
     // other engine already running?
@@ -68,18 +189,137 @@ fn main() -> Result<()> {
 
     info!("Listening on {}", addr);
     info!("Using engine {:?}", engine);
+    info!("Using protocol {:?}", protocol);
+    if tls.is_some() {
+        info!("Serving over TLS");
+    }
+    if let Some(leader) = replica_of {
+        info!("Following leader {}", leader);
+    }
+    if let Some(cluster) = &cluster {
+        info!("Joining cluster {:?} as node {}", cluster.nodes, cluster.id);
+    }
 
+    let opt: Opt = Opt {
+        protocol,
+        http_addr,
+        tls,
+        auth,
+        replica_of,
+        replica_credentials,
+        cluster,
+    };
     match engine {
-        Engine::Kvs => {
-            let mut server: Server<KvStore, NaiveThreadPool> =
-                Server::new(addr, KvStore::open(Path::new("."))?, pool)?;
-            server.serve_forever()?;
-        }
-        Engine::Sled => {
-            let mut server: Server<SledKvsEngine, NaiveThreadPool> =
-                Server::new(addr, SledKvsEngine::open(Path::new("."))?, pool)?;
-            server.serve_forever()?;
+        Engine::Kvs => run(
+            Server::new(addr, KvStore::open(Path::new("."))?, pool)?,
+            opt,
+        ),
+        Engine::Sled => run(
+            Server::new(addr, SledKvsEngine::open(Path::new("."))?, pool)?,
+            opt,
+        ),
+    }
+}
+
+// Options of the command line which are applied to the server of either engine.
+struct Opt<'a> {
+    protocol: Protocol,
+    http_addr: Option<&'a str>,
+    tls: Option<ServerTls>,
+    auth: Option<AuthConfig>,
+    replica_of: Option<&'a str>,
+    replica_credentials: Option<Credentials>,
+    cluster: Option<RaftConfig>,
+}
+
+// Set up the server by the options, then serve until a signal is received.
+fn run<E: KvsEngine>(mut server: Server<E, NaiveThreadPool>, opt: Opt) -> Result<()> {
+    server.set_protocol(opt.protocol);
+    if let Some(tls) = opt.tls {
+        server.set_tls(tls);
+    }
+    if let Some(auth) = opt.auth {
+        server.set_auth(auth);
+    }
+    if let Some(leader) = opt.replica_of {
+        server.set_replica_of(leader, replica_config(opt.replica_credentials));
+    }
+    if let Some(cluster) = opt.cluster {
+        server.set_cluster(cluster)?;
+    }
+    if let Some(http_addr) = opt.http_addr {
+        info!(
+            "HTTP gateway listening on {}",
+            server.listen_http(http_addr)?
+        );
+    }
+    stop_on_signal(server.shutdown_handle())?;
+    server.serve_forever()
+}
+
+// TLS settings of the command line, `--tls-cert` and `--tls-key` are given together.
+fn server_tls(matches: &ArgMatches) -> Result<Option<ServerTls>> {
+    let (cert, key) = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => (Path::new(cert), Path::new(key)),
+        _ => return Ok(None),
+    };
+    let tls: ServerTls = match matches.value_of("tls-client-ca") {
+        Some(client_ca) => ServerTls::with_client_auth(cert, key, Path::new(client_ca))?,
+        None => ServerTls::from_pem_files(cert, key)?,
+    };
+    Ok(Some(tls))
+}
+
+// Raft config of `--cluster`, the node id is the index of `--addr` in it.
+fn cluster_config(matches: &ArgMatches, addr: &str) -> Result<Option<RaftConfig>> {
+    let nodes: Vec<String> = match matches.value_of("cluster") {
+        Some(nodes) => nodes
+            .split(',')
+            .map(|node| node.trim().to_owned())
+            .collect(),
+        None => return Ok(None),
+    };
+    let id: usize = match nodes.iter().position(|node| node == addr) {
+        Some(id) => id,
+        None => {
+            return Err(KvsError::invalid_request(
+                "--addr must be one of the cluster nodes",
+            ))
         }
+    };
+    Ok(Some(RaftConfig::new(nodes, id, Path::new("raft"))))
+}
+
+// Credentials of `--replica-token`, or `--replica-user` and `--replica-password`.
+fn replica_credentials(matches: &ArgMatches) -> Option<Credentials> {
+    match (
+        matches.value_of("replica-token"),
+        matches.value_of("replica-user"),
+        matches.value_of("replica-password"),
+    ) {
+        (Some(token), _, _) => Some(Credentials::Token(token.to_owned())),
+        (None, Some(user), Some(password)) => Some(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        }),
+        _ => None,
     }
-    Ok(())
+}
+
+// The leader is connected with a timeout, so an unreachable one is retried soon.
+fn replica_config(credentials: Option<Credentials>) -> ClientConfig {
+    ClientConfig {
+        connect_timeout: Some(Duration::from_secs(5)),
+        credentials,
+        ..ClientConfig::default()
+    }
+}
+
+// Stop the server gracefully on SIGINT or SIGTERM.
+fn stop_on_signal(handle: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("Signal received, shutting down");
+        handle.shutdown();
+    })
+    .map_err(|e| KvsError::from_string(&e.to_string()))
 }
@@ -1,24 +1,281 @@
-///! Command definition for kvs.
+//! Command definition for kvs.
+use crate::{KvsError, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Keys and values are bytes.  bincode encodes them like strings, and JSON accepts both strings
+// and byte arrays, so logs and requests written with string keys are still readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Instruction {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// Milliseconds since unix epoch when the key expires, it never expires if `None`.
+        #[serde(default)]
+        expire_at: Option<u64>,
+    },
+    Rm {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Apply all operations of the batch atomically.
+    Batch { batch: WriteBatch },
+    /// Set the key to `new` if it's current value is `expected`, `None` means the key doesn't
+    /// exist.  It's never written to logs.
+    CompareAndSwap {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    /// Scan at most `limit` key/value pairs whose keys are in the range, in key order.  It's
+    /// never written to logs.
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: u32,
+    },
+    /// Log in to the server, following requests of the connection are checked by ACLs of the
+    /// user.  It's never written to logs.
+    Auth { credentials: Credentials },
+    /// Follow the server as a replica, the connection streams the log of the server from now on.
+    /// `log_id` and `offset` tell where the follower stopped, it receives a snapshot first if the
+    /// server can't resume from there.  It's never written to logs.
+    Replicate { log_id: Option<u64>, offset: u64 },
+    /// Message between nodes of a Raft cluster, the response body is the reply encoded by
+    /// bincode.  It's never written to logs.
+    Raft { message: RaftMessage },
+    /// Watch changes of keys with the prefix, the connection streams them as responses from now
+    /// on.  It's never written to logs.
+    Watch {
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+    },
+    /// Set the key which expires `ttl_ms` milliseconds after the server receives it, so clocks
+    /// of clients don't matter.  It's never written to logs, the server records it as `Set` with
+    /// the expire time.
+    SetWithTtl {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    /// Remaining time to live of the key, the response body is the milliseconds as `Option<u64>`
+    /// encoded by bincode, `None` if the key never expires or it doesn't exist.  It's never
+    /// written to logs.
+    Ttl {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
 }
 
-impl Instruction {
-    pub fn play(&self, store: &mut HashMap<String, u64>, position: u64) {
+/// Entry of the Raft log, `None` is the no-op entry which a new leader appends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub instruction: Option<Instruction>,
+}
+
+/// Requests of the Raft protocol, nodes are identified by their index in the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// Entries from `prev_log_index + 1`, it's a heartbeat if there is none.
+    AppendEntries {
+        term: u64,
+        leader: usize,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// A chunk of the snapshot of the leader, whose engine has entries up to `last_index`
+    /// applied.  `pairs` are `(key, value, expire_at)` from the `offset`th pair encoded by
+    /// bincode, the last chunk has no pair and it's `done`.
+    InstallSnapshot {
+        term: u64,
+        leader: usize,
+        last_index: u64,
+        last_term: u64,
+        offset: u64,
+        #[serde(with = "serde_bytes")]
+        pairs: Vec<u8>,
+        done: bool,
+    },
+}
+
+/// How a client logs in, users and their secrets are in the config file of the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    Token(String),
+    Password { user: String, password: String },
+}
+
+// secrets are not printed, requests are logged by the server.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Instruction::Set { key, value: _value } => {
-                store.insert(key.clone(), position);
+            Credentials::Token(_) => write!(f, "Token(***)"),
+            Credentials::Password { user, .. } => {
+                write!(f, "Password {{ user: {:?}, password: *** }}", user)
             }
-            Instruction::Rm { key } => {
-                store.remove(key);
+        }
+    }
+}
+
+/// Milliseconds since unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before unix epoch")
+        .as_millis() as u64
+}
+
+/// Expire time of a key which lives for `ttl` from now.
+pub fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Check if a key with the given expire time is already expired.
+pub fn is_expired(expire_at: Option<u64>) -> bool {
+    match expire_at {
+        Some(expire_at) => expire_at <= now_millis(),
+        None => false,
+    }
+}
+
+/// Remaining time to live of a key with the given expire time, `None` if it never expires or
+/// it's already expired.
+pub fn ttl_of(expire_at: Option<u64>) -> Option<Duration> {
+    match expire_at {
+        Some(expire_at) if !is_expired(Some(expire_at)) => Some(Duration::from_millis(
+            expire_at.saturating_sub(now_millis()),
+        )),
+        _ => None,
+    }
+}
+
+/// Operation of a `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Rm {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl BatchOp {
+    /// The key which is set or removed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Rm { key } => key,
+        }
+    }
+}
+
+/// A group of `set` and `remove` operations which are applied all-or-nothing by
+/// `KvsEngine::write_batch`, in the order they are added.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set the value of a key.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Remove a key, the whole batch fails if the key does not exist when it's applied.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Rm { key: key.into() });
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Check that every removed key exists, taking earlier operations of the batch into account.
+    /// `exists` tells if a key exists in the engine.
+    pub(crate) fn check_removes<F>(&self, mut exists: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<bool>,
+    {
+        let mut touched: HashMap<&[u8], bool> = HashMap::new();
+        for op in self.ops.iter() {
+            match op {
+                BatchOp::Set { key, .. } => {
+                    touched.insert(key.as_slice(), true);
+                }
+                BatchOp::Rm { key } => {
+                    let found: bool = match touched.get(key.as_slice()) {
+                        Some(&found) => found,
+                        None => exists(key)?,
+                    };
+                    if !found {
+                        return Err(KvsError::key_not_found());
+                    }
+                    touched.insert(key.as_slice(), false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value of `key` after the batch is applied, `None` if the batch doesn't set it.
+    pub(crate) fn into_value_of(self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut result: Option<Vec<u8>> = None;
+        for op in self.ops {
+            match op {
+                BatchOp::Set { key: op_key, value } if op_key == key => result = Some(value),
+                BatchOp::Rm { key: op_key } if op_key == key => result = None,
+                _ => {}
             }
-            _ => {} // for get, do nothing.
         }
+        result
     }
 }
@@ -0,0 +1,144 @@
+use super::KvsEngine;
+use crate::command::{Instruction, WriteBatch};
+use crate::error::{KvsError, Result};
+use crate::network::auth::Session;
+use crate::network::server::execute_instruction;
+use crate::network::Response;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task;
+
+/// Async adapter of a `KvsEngine`.
+///
+/// Engine calls may block on disk IO, so they run on the blocking pool of Tokio, and the async
+/// tasks are not blocked.  The returned futures don't borrow the adapter, so they can be
+/// spawned even if the engine is not `Sync`, like `KvStore`.
+#[derive(Clone)]
+pub struct AsyncKvsEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    pub fn new(engine: E) -> AsyncKvsEngine<E> {
+        AsyncKvsEngine { engine }
+    }
+
+    /// The wrapped engine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Run the function with a clone of the engine on the blocking pool.
+    fn run<F, T>(&self, f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine: E = self.engine.clone();
+        async move {
+            task::spawn_blocking(move || f(engine))
+                .await
+                .map_err(|e| KvsError::from_string(&format!("Blocking task failed: {}", e)))?
+        }
+    }
+
+    /// Same as `KvsEngine::set_bytes`.
+    pub fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.set_bytes(key, val))
+    }
+
+    /// Same as `KvsEngine::set_bytes_with_ttl`.
+    pub fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.set_bytes_with_ttl(key, val, ttl))
+    }
+
+    /// Same as `KvsEngine::get_bytes`.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send + 'static {
+        self.run(move |engine| engine.get_bytes(&key))
+    }
+
+    /// Same as `KvsEngine::remove_bytes`.
+    pub fn remove_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.remove_bytes(&key))
+    }
+
+    /// Same as `KvsEngine::compare_and_swap_bytes`.
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send + 'static {
+        self.run(move |engine| engine.compare_and_swap_bytes(key, expected, new))
+    }
+
+    /// Same as `KvsEngine::write_batch`.
+    pub fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.write_batch(batch))
+    }
+
+    /// Same as `KvsEngine::flush`.
+    pub fn flush(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.flush())
+    }
+
+    /// Same as `KvsEngine::scan_prefix_bytes`, but all pairs are collected.
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<(Vec<u8>, Vec<u8>)>>> + Send + 'static {
+        self.run(move |engine| engine.scan_prefix_bytes(&prefix)?.collect())
+    }
+
+    /// Same as `KvsEngine::set`.
+    pub fn set(
+        &self,
+        key: String,
+        val: String,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.set(key, val))
+    }
+
+    /// Same as `KvsEngine::get`.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> impl Future<Output = Result<Option<String>>> + Send + 'static {
+        self.run(move |engine| engine.get(key))
+    }
+
+    /// Same as `KvsEngine::remove`.
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    /// Execute an instruction of a client, errors are returned as responses.
+    pub(crate) fn execute(
+        &self,
+        instruction: Instruction,
+    ) -> impl Future<Output = Response> + Send + 'static {
+        let response = self.run(move |engine| {
+            Ok(execute_instruction(
+                instruction,
+                &engine,
+                &mut Session::default(),
+            ))
+        });
+        async move { response.await.unwrap_or_else(|e| Response::from_error(&e)) }
+    }
+}
@@ -1,198 +1,1136 @@
-use std::collections::HashMap;
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Take, Write};
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use super::KvsEngine;
-use crate::command::Instruction;
+use super::{BytesScanIter, KvsEngine};
+use crate::command::{expire_at, is_expired, ttl_of, BatchOp, Instruction, WriteBatch};
 use crate::error::{KvsError, Result};
 
 // when useless command in the file match this threshold, a compaction
 // action is required.
 static THRESHOLD: usize = 10240;
 
-// Note the reason for storing reader, writer fields in the InnerStore rather than a `File` struct.
+// Single log file used by old versions, it's taken as the first generation.
+static LEGACY_DB_FILE_NAME: &str = "kvs.db";
+
+// Log files start with the header, then each record is framed as
+// `payload length (u32, LE) | crc32 of payload (u32, LE) | payload`,
+// the payload is an `Instruction` encoded by bincode.
+static LOG_HEADER: &[u8] = b"KVSLOG03";
+// Same framing, but `set` commands in the payload have no expire time.
+static V2_LOG_HEADER: &[u8] = b"KVSLOG02";
+// Same framing, but the payload is JSON.
+static JSON_LOG_HEADER: &[u8] = b"KVSLOG01";
+const FRAME_HEADER_LEN: u64 = 8;
+
+// Hint files are written by compaction for the new generation, they start with the header and
+// length of the generation, then each `HintEntry` is framed like log records.
+static HINT_HEADER: &[u8] = b"KVSHINT2";
+
+/// Entry of a hint file, it locates a `set` command in the generation.
+#[derive(Debug, Serialize, Deserialize)]
+struct HintEntry {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    pos: u64,
+    len: u64,
+    expire_at: Option<u64>,
+}
+
+/// `Instruction` encoded in logs of the `FramedV2` format.
+#[derive(Debug, Deserialize)]
+enum InstructionV2 {
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Rm {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+}
+
+impl From<InstructionV2> for Instruction {
+    fn from(instruction: InstructionV2) -> Instruction {
+        match instruction {
+            InstructionV2::Get { key } => Instruction::Get { key },
+            InstructionV2::Set { key, value } => Instruction::Set {
+                key,
+                value,
+                expire_at: None,
+            },
+            InstructionV2::Rm { key } => Instruction::Rm { key },
+            InstructionV2::Batch { batch } => Instruction::Batch { batch },
+        }
+    }
+}
+
+/// Log formats, files written by old versions are upgraded to `Framed` on open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Newline delimited JSON, it has no header.
+    JsonLines,
+    /// Framed records with JSON payload.
+    FramedJson,
+    /// Framed records with bincode payload, `set` commands have no expire time.
+    FramedV2,
+    /// Framed records with bincode payload.
+    Framed,
+}
+
+/// Location of an instruction in the log files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    /// Generation of the log file which the instruction is written to.
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+// Compaction marks keys which are expired by this position, as it's not allowed to remove keys
+// from the index.  Generations start from 1, so it never locates an instruction.
+const EXPIRED: CommandPos = CommandPos {
+    gen: 0,
+    pos: 0,
+    len: 0,
+};
+
+// The position is updated in place when a key is overwritten.  Re-inserting the key into the
+// skip map would make it invisible to readers for a short time.
+type Index = SkipMap<Vec<u8>, AtomicCell<CommandPos>>;
+
+/// Point `key` to `cmd_pos`, returns true if the key is already in the index.
+fn update_index(index: &Index, key: Vec<u8>, cmd_pos: CommandPos) -> bool {
+    match index.get(&key) {
+        Some(entry) => {
+            entry.value().store(cmd_pos);
+            true
+        }
+        None => {
+            index.insert(key, AtomicCell::new(cmd_pos));
+            false
+        }
+    }
+}
+
+// Note the reason for storing reader, writer separately rather than a `File` struct.
 // The logical separation of readers and writers into their own concurrent types is a common in Rust. Readers have their
 // own data set to work with, and writers their own, and that provides a good opportunity for encapsulation, with all
 // read operations beloning to one type and all write operations another.
 //
 // Making this distinction will further make it very obvious which resources are accessed by both, since the reader and
 // writer will both carry shared handles to those resources.
-struct InnerStore {
-    reader: BufReaderSeekable<File>,
+//
+// Here the shared resources are the index and the log files, readers never take the writer's lock.
+pub struct KvStore {
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+struct KvStoreReader {
+    folder_path: Arc<PathBuf>,
+    // Generations lower than the safe point are deleted by compaction, so their file handles
+    // can be closed.
+    safe_point: Arc<AtomicU64>,
+    // Each clone of the store owns it's file readers, so readers in different threads never
+    // share a file cursor.
+    readers: RefCell<BTreeMap<u64, BufReaderSeekable<File>>>,
+}
+
+impl KvStoreReader {
+    /// Read the instruction located by `cmd_pos`, and handle it by `f`.
+    ///
+    /// Returns `None` if the generation of `cmd_pos` is already deleted by compaction, then the
+    /// caller should look up the index again.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(Take<&mut BufReaderSeekable<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader: &mut BufReaderSeekable<File> = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file: File = match File::open(log_path(&self.folder_path, cmd_pos.gen)) {
+                    Ok(file) => file,
+                    // compaction updates the index before deleting the generation.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(KvsError::from(e)),
+                };
+                entry.insert(BufReaderSeekable::new(file))
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        f(reader.take(cmd_pos.len)).map(Some)
+    }
+
+    fn read_command(&self, index: &Index, key: &[u8]) -> Result<Option<Instruction>> {
+        loop {
+            let cmd_pos: CommandPos = match index.get(key) {
+                Some(entry) => entry.value().load(),
+                None => return Ok(None),
+            };
+            if cmd_pos == EXPIRED {
+                return Ok(None);
+            }
+            let instruction: Option<Instruction> = self.read_and(cmd_pos, |cmd_reader| {
+                Ok(bincode::deserialize_from(cmd_reader)?)
+            })?;
+            if instruction.is_some() {
+                return Ok(instruction);
+            }
+        }
+    }
+
+    fn close_stale_handles(&self) {
+        let safe_point: u64 = self.safe_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        while let Some(&gen) = readers.keys().next() {
+            if gen >= safe_point {
+                break;
+            }
+            readers.remove(&gen);
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader {
+            folder_path: self.folder_path.clone(),
+            safe_point: self.safe_point.clone(),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
     writer: BufWriterSeekable<File>,
-    folder_path: PathBuf,
-    index: HashMap<String, u64>,
+    current_gen: u64,
+    folder_path: Arc<PathBuf>,
+    index: Arc<Index>,
     useless_cmd: usize,
+    compaction: Option<JoinHandle<()>>,
 }
 
-pub struct KvStore {
-    inner: Arc<Mutex<InnerStore>>,
-}
+impl KvStoreWriter {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        // create a relative fiinstruction object.
+        let instruction: Instruction = Instruction::Set {
+            key: key.clone(),
+            value: val,
+            expire_at,
+        };
+        let cmd_pos: CommandPos = self.append(&instruction)?;
+        // write the current offset to inner index.
+        if update_index(&self.index, key, cmd_pos) {
+            self.useless_cmd += 1;
+        }
+        self.do_compaction()
+    }
 
-impl InnerStore {
-    pub fn do_compaction(self: &mut InnerStore) -> Result<()> {
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        // check key exists.
+        if !self.is_live(key)? {
+            return Err(KvsError::key_not_found());
+        }
+        let instruction: Instruction = Instruction::Rm { key: key.to_vec() };
+        self.append(&instruction)?;
+        // Remember to remove key from inner index.
+        self.index.remove(key);
+        // both the removed `set` command and the `rm` command itself are useless.
+        self.useless_cmd += 2;
+        self.do_compaction()
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        // other writers are blocked by the lock, so the value can't change after the check.
+        let instruction: Option<Instruction> = self.reader.read_command(&self.index, &key)?;
+        // the swapped value keeps the expire time of the current one, keys set by batches
+        // never expire.
+        let expire_at: Option<u64> = match &instruction {
+            Some(Instruction::Set { expire_at, .. }) if !is_expired(*expire_at) => *expire_at,
+            _ => None,
+        };
+        let current: Option<Vec<u8>> =
+            instruction.and_then(|instruction| value_of(instruction, &key));
+        if current != expected {
+            return Ok(false);
+        }
+        match (current, new) {
+            (_, Some(new)) => self.set(key, new, expire_at)?,
+            (Some(_), None) => self.remove(&key)?,
+            (None, None) => {}
+        }
+        Ok(true)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        batch.check_removes(|key| self.is_live(key))?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // the whole batch is a single record, so it's replayed entirely or dropped as a torn tail.
+        let instruction: Instruction = Instruction::Batch { batch };
+        let cmd_pos: CommandPos = self.append(&instruction)?;
+        self.useless_cmd += replay(&self.index, instruction, cmd_pos);
+        self.do_compaction()
+    }
+
+    /// Check if the key exists and it's not expired.
+    fn is_live(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .reader
+            .read_command(&self.index, key)?
+            .and_then(|instruction| value_of(instruction, key))
+            .is_some())
+    }
+
+    /// Append the instruction to the current generation, returns where it's located.
+    fn append(&mut self, instruction: &Instruction) -> Result<CommandPos> {
+        let payload: Vec<u8> = bincode::serialize(instruction)?;
+        let pos: u64 = write_record(&mut self.writer, &payload)?;
+        self.writer.flush()?;
+        Ok(CommandPos {
+            gen: self.current_gen,
+            pos,
+            len: payload.len() as u64,
+        })
+    }
+
+    /// Start a background compaction if there are too many useless commands.
+    ///
+    /// Live entries of the existed generations are copied into a new generation, while new
+    /// commands are written to the generation after it.
+    fn do_compaction(&mut self) -> Result<()> {
         if self.useless_cmd < THRESHOLD {
             return Ok(());
         }
-        // for each index, construct relative `set` command.
-        // ??? maybe we should lock the file or index while doing compaction.
-        let mut insts_str: String = String::new();
-        {
-            for (_, offset) in self.index.iter() {
-                // read the relative command.
-                self.reader.seek(SeekFrom::Start(*offset))?;
-                self.reader.read_line(&mut insts_str)?;
+        // only one compaction runs at a time.
+        if let Some(handle) = &self.compaction {
+            if !handle.is_finished() {
+                return Ok(());
             }
+            self.finish_compaction();
         }
 
-        // directly write instructions into `kvs.db`.
-        let truncated_file: File = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(self.folder_path.join("kvs.db"))?;
-        self.writer = BufWriterSeekable {
-            inner: BufWriter::new(truncated_file),
-            pos: 0,
-        };
-        self.writer.write_all(insts_str.as_bytes())?;
+        let compaction_gen: u64 = self.current_gen + 1;
+        self.writer = open_writer(&log_path(&self.folder_path, compaction_gen + 1))?;
+        self.current_gen = compaction_gen + 1;
+        self.useless_cmd = 0;
+
+        let reader: KvStoreReader = self.reader.clone();
+        let index: Arc<Index> = self.index.clone();
+        self.compaction = Some(thread::spawn(move || {
+            if let Err(e) = compact(&reader, &index, compaction_gen) {
+                error!(
+                    "Compaction into generation {} failed, reason: {:?}",
+                    compaction_gen, e
+                );
+            }
+        }));
+        Ok(())
+    }
+
+    /// Sync the current generation to disk, and wait for the running compaction.
+    fn flush(&mut self) -> Result<()> {
         self.writer.flush()?;
+        self.writer.inner.get_ref().sync_all()?;
+        self.finish_compaction();
+        Ok(())
+    }
 
-        let new_file: File = File::open(self.folder_path.join("kvs.db"))?;
-        self.reader = BufReaderSeekable {
-            inner: BufReader::new(new_file),
-            pos: 0,
+    /// Wait for the running compaction, then remove keys which are dropped by it.
+    fn finish_compaction(&mut self) {
+        if let Some(handle) = self.compaction.take() {
+            join_compaction(handle);
+            // only the writer removes keys from the index.
+            for entry in self.index.iter() {
+                if entry.value().load() == EXPIRED {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+// Wait for the running compaction, so it doesn't delete files under a newly opened store.
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if let Some(handle) = self.compaction.take() {
+            join_compaction(handle);
+        }
+    }
+}
+
+fn join_compaction(handle: JoinHandle<()>) {
+    if handle.join().is_err() {
+        error!("Compaction thread panicked");
+    }
+}
+
+/// Copy live entries lower than `compaction_gen` into `compaction_gen`, then delete the
+/// generations lower than it.
+fn compact(reader: &KvStoreReader, index: &Index, compaction_gen: u64) -> Result<()> {
+    let folder_path: &Path = &reader.folder_path;
+    let compaction_path: PathBuf = folder_path.join(format!("{}.compact", compaction_gen));
+    let mut compaction_writer: BufWriterSeekable<File> = open_writer(&compaction_path)?;
+
+    // for each index, copy relative `set` command into the compaction file.
+    let mut new_positions: Vec<(Vec<u8>, CommandPos, CommandPos, Option<u64>)> = Vec::new();
+    let mut expired_positions: Vec<(Vec<u8>, CommandPos)> = Vec::new();
+    for entry in index.iter() {
+        let cmd_pos: CommandPos = entry.value().load();
+        if cmd_pos.gen >= compaction_gen || cmd_pos == EXPIRED {
+            continue;
+        }
+        let mut payload: Vec<u8> = reader
+            .read_and(cmd_pos, |mut cmd_reader| {
+                let mut payload: Vec<u8> = Vec::with_capacity(cmd_pos.len as usize);
+                cmd_reader.read_to_end(&mut payload)?;
+                Ok(payload)
+            })?
+            .expect("Only compaction can delete log files");
+        let mut expire_at: Option<u64> = None;
+        match bincode::deserialize(&payload)? {
+            // expired entries are dropped.
+            Instruction::Set {
+                expire_at: time, ..
+            } if is_expired(time) => {
+                expired_positions.push((entry.key().clone(), cmd_pos));
+                continue;
+            }
+            Instruction::Set {
+                expire_at: time, ..
+            } => expire_at = time,
+            // values written by a batch are copied as single `set` commands.
+            Instruction::Batch { batch } => {
+                let key: Vec<u8> = entry.key().clone();
+                let value: Vec<u8> = batch
+                    .into_value_of(&key)
+                    .expect("Index only points to batches which set the key");
+                payload = bincode::serialize(&Instruction::Set {
+                    key,
+                    value,
+                    expire_at: None,
+                })?;
+            }
+            _ => {}
+        }
+        let pos: u64 = write_record(&mut compaction_writer, &payload)?;
+        new_positions.push((
+            entry.key().clone(),
+            cmd_pos,
+            CommandPos {
+                gen: compaction_gen,
+                pos,
+                len: payload.len() as u64,
+            },
+            expire_at,
+        ));
+    }
+
+    // the new generation must be durable before old generations are deleted.
+    compaction_writer.flush()?;
+    compaction_writer.inner.get_ref().sync_all()?;
+    fs::rename(&compaction_path, log_path(folder_path, compaction_gen))?;
+    sync_dir(folder_path)?;
+    // without the hint file, the generation is replayed on open.
+    if let Err(e) = write_hint(
+        folder_path,
+        compaction_gen,
+        compaction_writer.pos,
+        new_positions
+            .iter()
+            .map(|(key, _, new_pos, expire_at)| (key, new_pos, *expire_at)),
+    ) {
+        warn!(
+            "Write hint file of generation {} failed, reason: {:?}",
+            compaction_gen, e
+        );
+    }
+
+    // keys which are overwritten or removed during compaction are left untouched.
+    let expired_positions = expired_positions
+        .into_iter()
+        .map(|(key, old_pos)| (key, old_pos, EXPIRED));
+    let new_positions = new_positions
+        .into_iter()
+        .map(|(key, old_pos, new_pos, _)| (key, old_pos, new_pos));
+    for (key, old_pos, new_pos) in new_positions.chain(expired_positions) {
+        if let Some(entry) = index.get(&key) {
+            let _ = entry.value().compare_exchange(old_pos, new_pos);
+        }
+    }
+
+    reader.safe_point.store(compaction_gen, Ordering::SeqCst);
+    for gen in sorted_gen_list(folder_path)? {
+        if gen < compaction_gen {
+            fs::remove_file(log_path(folder_path, gen))?;
+            let gen_hint_path: PathBuf = hint_path(folder_path, gen);
+            if gen_hint_path.exists() {
+                fs::remove_file(gen_hint_path)?;
+            }
+        }
+    }
+    info!("Compaction into generation {} finished", compaction_gen);
+    Ok(())
+}
+
+fn log_path(folder_path: &Path, gen: u64) -> PathBuf {
+    folder_path.join(format!("{}.log", gen))
+}
+
+fn hint_path(folder_path: &Path, gen: u64) -> PathBuf {
+    folder_path.join(format!("{}.hint", gen))
+}
+
+/// Write the hint file for a generation whose length is `log_len`.
+fn write_hint<'a, I>(folder_path: &Path, gen: u64, log_len: u64, entries: I) -> Result<()>
+where
+    I: Iterator<Item = (&'a Vec<u8>, &'a CommandPos, Option<u64>)>,
+{
+    // unfinished hint files are removed on open, like compaction files.
+    let tmp_path: PathBuf = folder_path.join(format!("{}.hint.compact", gen));
+    let mut writer: BufWriterSeekable<File> = BufWriterSeekable::new(File::create(&tmp_path)?);
+    writer.write_all(HINT_HEADER)?;
+    writer.write_all(&log_len.to_le_bytes())?;
+    for (key, cmd_pos, expire_at) in entries {
+        let entry: HintEntry = HintEntry {
+            key: key.clone(),
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expire_at,
         };
+        write_record(&mut writer, &bincode::serialize(&entry)?)?;
+    }
+    writer.flush()?;
+    writer.inner.get_ref().sync_all()?;
+    fs::rename(&tmp_path, hint_path(folder_path, gen))?;
+    sync_dir(folder_path)
+}
 
-        // don't forget to re-build index.
-        self.index = build_indx(&mut self.reader)?;
-        Ok(())
+/// Read the hint file of a generation.
+///
+/// Returns `None` if the hint file is missing, corrupted, or doesn't match the generation.
+fn read_hint(folder_path: &Path, gen: u64) -> Result<Option<Vec<HintEntry>>> {
+    let file: File = match File::open(hint_path(folder_path, gen)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(KvsError::from(e)),
+    };
+    let hint_len: u64 = file.metadata()?.len();
+    let log_len: u64 = fs::metadata(log_path(folder_path, gen))?.len();
+    let mut reader: BufReaderSeekable<File> = BufReaderSeekable::new(file);
+
+    let mut header: [u8; 16] = [0; 16];
+    if hint_len < header.len() as u64 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header)?;
+    let mut hinted_len: [u8; 8] = [0; 8];
+    hinted_len.copy_from_slice(&header[HINT_HEADER.len()..]);
+    if &header[..HINT_HEADER.len()] != HINT_HEADER || u64::from_le_bytes(hinted_len) != log_len {
+        return Ok(None);
+    }
+
+    let mut entries: Vec<HintEntry> = Vec::new();
+    while reader.pos < hint_len {
+        let entry: Option<HintEntry> = read_record(&mut reader, hint_len)?
+            .and_then(|payload| bincode::deserialize(&payload).ok());
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(entries))
+}
+
+/// Returns generations of log files in the given folder, in ascending order.
+fn sorted_gen_list(folder_path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = Vec::new();
+    for entry in fs::read_dir(folder_path)? {
+        let path: PathBuf = entry?.path();
+        if !path.is_file() || path.extension() != Some(OsStr::new("log")) {
+            continue;
+        }
+        if let Some(gen) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            gen_list.push(gen);
+        }
+    }
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+// Make renaming and deleting of log files durable.
+#[cfg(unix)]
+fn sync_dir(folder_path: &Path) -> Result<()> {
+    File::open(folder_path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_folder_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Open log file for appending, the writer is positioned at the end of file.
+///
+/// The header is written if the file is empty.
+fn open_writer(path: &Path) -> Result<BufWriterSeekable<File>> {
+    let mut writer: BufWriterSeekable<File> = BufWriterSeekable::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?,
+    );
+    if writer.seek(SeekFrom::End(0))? == 0 {
+        writer.write_all(LOG_HEADER)?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// Write a framed record, returns the position of it's payload.
+fn write_record(writer: &mut BufWriterSeekable<File>, payload: &[u8]) -> Result<u64> {
+    if payload.len() > u32::MAX as usize {
+        return Err(KvsError::invalid_request("Command is too large"));
+    }
+    let mut frame_header: [u8; FRAME_HEADER_LEN as usize] = [0; FRAME_HEADER_LEN as usize];
+    frame_header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame_header[4..].copy_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    writer.write_all(&frame_header)?;
+    let pos: u64 = writer.pos;
+    writer.write_all(payload)?;
+    Ok(pos)
+}
+
+/// Read a framed record at the current position.
+///
+/// Returns `None` if the record is torn or it's checksum doesn't match.
+fn read_record(reader: &mut BufReaderSeekable<File>, file_len: u64) -> Result<Option<Vec<u8>>> {
+    if file_len - reader.pos < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut frame_header: [u8; FRAME_HEADER_LEN as usize] = [0; FRAME_HEADER_LEN as usize];
+    reader.read_exact(&mut frame_header)?;
+    let mut len: [u8; 4] = [0; 4];
+    let mut crc: [u8; 4] = [0; 4];
+    len.copy_from_slice(&frame_header[..4]);
+    crc.copy_from_slice(&frame_header[4..]);
+    let len: u64 = u64::from(u32::from_le_bytes(len));
+    if file_len - reader.pos < len {
+        return Ok(None);
+    }
+    let mut payload: Vec<u8> = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if crc32fast::hash(&payload) != u32::from_le_bytes(crc) {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+/// Result of scanning one generation.
+struct ScanResult {
+    /// Length of the valid part of the file, anything after it is torn or corrupted.
+    valid_len: u64,
+    /// How many records are found after the valid part.
+    dropped: usize,
+}
+
+/// Apply the instruction to memory-index, returns how many commands become useless.
+fn replay(index: &Index, instruction: Instruction, cmd_pos: CommandPos) -> usize {
+    match instruction {
+        // the expired `set` command and the overwritten one are useless.
+        Instruction::Set { key, expire_at, .. } if is_expired(expire_at) => {
+            if index.remove(&key).is_some() {
+                2
+            } else {
+                1
+            }
+        }
+        Instruction::Set { key, .. } => {
+            if update_index(index, key, cmd_pos) {
+                1
+            } else {
+                0
+            }
+        }
+        Instruction::Rm { key } => {
+            index.remove(&key);
+            2
+        }
+        // for get, do nothing, compare-and-swap is written as set or rm.
+        Instruction::Get { .. }
+        | Instruction::CompareAndSwap { .. }
+        | Instruction::Scan { .. }
+        | Instruction::Auth { .. }
+        | Instruction::Replicate { .. }
+        | Instruction::Raft { .. }
+        | Instruction::Watch { .. }
+        | Instruction::SetWithTtl { .. }
+        | Instruction::Ttl { .. } => 0,
+        Instruction::Batch { batch } => batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => replay(
+                    index,
+                    Instruction::Set {
+                        key,
+                        value,
+                        expire_at: None,
+                    },
+                    cmd_pos,
+                ),
+                BatchOp::Rm { key } => replay(index, Instruction::Rm { key }, cmd_pos),
+            })
+            .sum(),
+    }
+}
+
+/// Value of `key` in the instruction which the index points to, `None` if it's expired.
+fn value_of(instruction: Instruction, key: &[u8]) -> Option<Vec<u8>> {
+    match instruction {
+        Instruction::Set { expire_at, .. } if is_expired(expire_at) => None,
+        Instruction::Set { value, .. } => Some(value),
+        Instruction::Batch { batch } => batch.into_value_of(key),
+        _ => None,
+    }
+}
+
+/// Detect format of the log file from it's header, the reader is positioned after the header.
+///
+/// An empty file or a torn header is taken as the current format.
+fn detect_format(reader: &mut BufReaderSeekable<File>) -> Result<LogFormat> {
+    let mut header: Vec<u8> = Vec::with_capacity(LOG_HEADER.len());
+    (&mut *reader)
+        .take(LOG_HEADER.len() as u64)
+        .read_to_end(&mut header)?;
+
+    if header == JSON_LOG_HEADER {
+        Ok(LogFormat::FramedJson)
+    } else if header == V2_LOG_HEADER {
+        Ok(LogFormat::FramedV2)
+    } else if LOG_HEADER.starts_with(&header) {
+        Ok(LogFormat::Framed)
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(LogFormat::JsonLines)
+    }
+}
+
+/// Read instructions of one generation in order, and handle them by `f` with their positions.
+///
+/// Scanning stops at the first torn or corrupted record.
+fn scan_log<F>(
+    gen: u64,
+    format: LogFormat,
+    reader: &mut BufReaderSeekable<File>,
+    f: F,
+) -> Result<ScanResult>
+where
+    F: FnMut(Instruction, CommandPos) -> Result<()>,
+{
+    let file_len: u64 = reader.inner.get_ref().metadata()?.len();
+    match format {
+        LogFormat::JsonLines => scan_lines(gen, reader, f),
+        LogFormat::FramedJson | LogFormat::FramedV2 | LogFormat::Framed => {
+            // the header itself is torn.
+            if reader.pos < LOG_HEADER.len() as u64 {
+                return Ok(ScanResult {
+                    valid_len: 0,
+                    dropped: 0,
+                });
+            }
+            scan_records(gen, format, reader, file_len, f)
+        }
+    }
+}
+
+fn scan_records<F>(
+    gen: u64,
+    format: LogFormat,
+    reader: &mut BufReaderSeekable<File>,
+    file_len: u64,
+    mut f: F,
+) -> Result<ScanResult>
+where
+    F: FnMut(Instruction, CommandPos) -> Result<()>,
+{
+    loop {
+        let record_start: u64 = reader.pos;
+        // Instruction is end.
+        if record_start == file_len {
+            return Ok(ScanResult {
+                valid_len: file_len,
+                dropped: 0,
+            });
+        }
+        let instruction: Option<Instruction> =
+            read_record(reader, file_len)?.and_then(|payload| match format {
+                LogFormat::FramedJson => serde_json::from_slice(&payload).ok(),
+                LogFormat::FramedV2 => bincode::deserialize::<InstructionV2>(&payload)
+                    .ok()
+                    .map(Instruction::from),
+                _ => bincode::deserialize(&payload).ok(),
+            });
+        let instruction: Instruction = match instruction {
+            Some(instruction) => instruction,
+            None => {
+                return Ok(ScanResult {
+                    valid_len: record_start,
+                    dropped: count_frames(reader, record_start, file_len)?,
+                })
+            }
+        };
+        let cmd_pos: CommandPos = CommandPos {
+            gen,
+            pos: record_start + FRAME_HEADER_LEN,
+            len: reader.pos - record_start - FRAME_HEADER_LEN,
+        };
+        f(instruction, cmd_pos)?;
+    }
+}
+
+/// Count records from `from` to the end of file by following their lengths, a torn record is
+/// also counted.
+fn count_frames(reader: &mut BufReaderSeekable<File>, from: u64, file_len: u64) -> Result<usize> {
+    let mut count: usize = 0;
+    let mut pos: u64 = from;
+    while pos < file_len {
+        count += 1;
+        if file_len - pos < FRAME_HEADER_LEN {
+            break;
+        }
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut len: [u8; 4] = [0; 4];
+        reader.read_exact(&mut len)?;
+        pos += FRAME_HEADER_LEN + u64::from(u32::from_le_bytes(len));
     }
+    Ok(count)
 }
 
-fn build_indx(reader: &mut BufReaderSeekable<File>) -> Result<HashMap<String, u64>> {
-    let mut map: HashMap<String, u64> = HashMap::new();
+fn scan_lines<F>(gen: u64, reader: &mut BufReaderSeekable<File>, mut f: F) -> Result<ScanResult>
+where
+    F: FnMut(Instruction, CommandPos) -> Result<()>,
+{
     loop {
-        let position_before: u64 = reader.seek(SeekFrom::Current(0))?;
-        let mut line_content: String = String::new();
-        reader.read_line(&mut line_content)?;
+        let position_before: u64 = reader.pos;
+        let mut line_content: Vec<u8> = Vec::new();
+        reader.read_until(b'\n', &mut line_content)?;
         // Instruction is end.
         if line_content.is_empty() {
-            return Ok(map);
+            return Ok(ScanResult {
+                valid_len: position_before,
+                dropped: 0,
+            });
         }
-        let instruction: Instruction = serde_json::from_str(&line_content)?;
-        instruction.play(&mut map, position_before);
+        // a line without the ending newline is torn.
+        let instruction: Option<Instruction> = if line_content.ends_with(b"\n") {
+            serde_json::from_slice(&line_content).ok()
+        } else {
+            None
+        };
+        let instruction: Instruction = match instruction {
+            Some(instruction) => instruction,
+            None => {
+                let mut dropped: usize = 1;
+                loop {
+                    line_content.clear();
+                    if reader.read_until(b'\n', &mut line_content)? == 0 {
+                        break;
+                    }
+                    dropped += 1;
+                }
+                return Ok(ScanResult {
+                    valid_len: position_before,
+                    dropped,
+                });
+            }
+        };
+        let cmd_pos: CommandPos = CommandPos {
+            gen,
+            pos: position_before,
+            len: line_content.len() as u64 - 1,
+        };
+        f(instruction, cmd_pos)?;
     }
 }
 
+/// Rewrite a generation written by old versions into the current format.
+fn upgrade_log(folder_path: &Path, gen: u64) -> Result<()> {
+    let gen_path: PathBuf = log_path(folder_path, gen);
+    let mut reader: BufReaderSeekable<File> = BufReaderSeekable::new(File::open(&gen_path)?);
+    let format: LogFormat = detect_format(&mut reader)?;
+    if format == LogFormat::Framed {
+        return Ok(());
+    }
+
+    info!("Upgrade generation {} from {:?} format", gen, format);
+    let upgrade_path: PathBuf = folder_path.join(format!("{}.upgrade", gen));
+    let mut writer: BufWriterSeekable<File> = open_writer(&upgrade_path)?;
+    let result: ScanResult = scan_log(gen, format, &mut reader, |instruction, _| {
+        write_record(&mut writer, &bincode::serialize(&instruction)?)?;
+        Ok(())
+    })?;
+    if result.dropped > 0 {
+        warn!(
+            "Generation {} is torn or corrupted, {} records are dropped",
+            gen, result.dropped
+        );
+    }
+
+    writer.flush()?;
+    writer.inner.get_ref().sync_all()?;
+    fs::rename(&upgrade_path, &gen_path)?;
+    sync_dir(folder_path)
+}
+
+/// Build memory-index from one generation, returns how many commands are useless.
+///
+/// The torn or corrupted tail is truncated, so new commands are appended after valid ones.
+fn build_indx(
+    folder_path: &Path,
+    gen: u64,
+    reader: &mut BufReaderSeekable<File>,
+    index: &Index,
+) -> Result<usize> {
+    let mut useless_cmd: usize = 0;
+    let format: LogFormat = detect_format(reader)?;
+    let result: ScanResult = scan_log(gen, format, reader, |instruction, cmd_pos| {
+        useless_cmd += replay(index, instruction, cmd_pos);
+        Ok(())
+    })?;
+
+    if result.valid_len < reader.inner.get_ref().metadata()?.len() {
+        warn!(
+            "Generation {} is torn or corrupted, {} records are dropped",
+            gen, result.dropped
+        );
+        let file: File = OpenOptions::new()
+            .write(true)
+            .open(log_path(folder_path, gen))?;
+        file.set_len(result.valid_len)?;
+        file.sync_all()?;
+    }
+    Ok(useless_cmd)
+}
+
 impl KvStore {
-    /// Open the local kvs store from given file.
+    /// Open the local kvs store from given folder.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path: PathBuf = path.into();
-        // the inner file name is kvs.db
-        fs::create_dir_all(&path)?;
+        let path: Arc<PathBuf> = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
 
-        // locate inner kvs.db file
-        let f_path: PathBuf = path.join("kvs.db");
+        // files left by unfinished compactions or upgrades, the generations are still complete.
+        for entry in fs::read_dir(&*path)? {
+            let entry_path: PathBuf = entry?.path();
+            if entry_path.extension() == Some(OsStr::new("compact"))
+                || entry_path.extension() == Some(OsStr::new("upgrade"))
+            {
+                fs::remove_file(&entry_path)?;
+            }
+        }
+
+        let mut gen_list: Vec<u64> = sorted_gen_list(&path)?;
+        let legacy_path: PathBuf = path.join(LEGACY_DB_FILE_NAME);
+        if gen_list.is_empty() && legacy_path.exists() {
+            fs::rename(&legacy_path, log_path(&path, 1))?;
+            gen_list.push(1);
+        }
 
-        let db_writer: BufWriterSeekable<File> =
-            BufWriterSeekable::new(OpenOptions::new().write(true).create(true).open(&f_path)?);
-        let mut db_reader: BufReaderSeekable<File> = BufReaderSeekable::new(File::open(&f_path)?);
         // Build memory-index.
-        let indx: HashMap<String, u64> = build_indx(&mut db_reader)?;
+        let index: Arc<Index> = Arc::new(SkipMap::new());
+        let mut readers: BTreeMap<u64, BufReaderSeekable<File>> = BTreeMap::new();
+        let mut useless_cmd: usize = 0;
+        for &gen in &gen_list {
+            upgrade_log(&path, gen)?;
+            let mut reader: BufReaderSeekable<File> =
+                BufReaderSeekable::new(File::open(log_path(&path, gen))?);
+            // the hint file builds memory-index without reading values.
+            match read_hint(&path, gen)? {
+                Some(entries) => {
+                    for entry in entries {
+                        let cmd_pos: CommandPos = CommandPos {
+                            gen,
+                            pos: entry.pos,
+                            len: entry.len,
+                        };
+                        if is_expired(entry.expire_at) {
+                            index.remove(&entry.key);
+                            useless_cmd += 1;
+                        } else if update_index(&index, entry.key, cmd_pos) {
+                            useless_cmd += 1;
+                        }
+                    }
+                }
+                None => {
+                    if hint_path(&path, gen).exists() {
+                        warn!("Hint file of generation {} is stale, replay the log", gen);
+                    }
+                    useless_cmd += build_indx(&path, gen, &mut reader, &index)?;
+                }
+            }
+            readers.insert(gen, reader);
+        }
+
+        // new commands are appended to the latest generation.
+        let current_gen: u64 = gen_list.last().cloned().unwrap_or(1);
+        let db_writer: BufWriterSeekable<File> = open_writer(&log_path(&path, current_gen))?;
+
+        let reader: KvStoreReader = KvStoreReader {
+            folder_path: path.clone(),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
+        let writer: KvStoreWriter = KvStoreWriter {
+            reader: reader.clone(),
+            writer: db_writer,
+            current_gen,
+            folder_path: path,
+            index: index.clone(),
+            useless_cmd,
+            compaction: None,
+        };
         Ok(KvStore {
-            inner: Arc::new(Mutex::new(InnerStore {
-                reader: db_reader,
-                writer: db_writer,
-                folder_path: path,
-                index: indx,
-                useless_cmd: 0,
-            })),
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
         })
     }
 
     /// Check if the db file exists in for the given folder.
     pub fn db_exists(path: &Path) -> bool {
-        let file_name: &str = "kvs.db";
-        let full_path: PathBuf = path.join(file_name);
-
-        full_path.exists()
+        if path.join(LEGACY_DB_FILE_NAME).exists() {
+            return true;
+        }
+        match sorted_gen_list(path) {
+            Ok(gen_list) => !gen_list.is_empty(),
+            Err(_) => false,
+        }
     }
 }
 
 impl KvsEngine for KvStore {
-    fn set(self: &KvStore, key: String, val: String) -> Result<()> {
-        let mut inner: MutexGuard<InnerStore> = self.inner.lock().expect("Lock KvsEngine failed.");
+    fn set_bytes(self: &KvStore, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.set(key, val, None)
+    }
 
-        // create a relative fiinstruction object.
-        let instruction: Instruction = Instruction::Set {
-            key: key.clone(),
-            value: val,
-        };
-        let inst_str: String = serde_json::ser::to_string(&instruction)?;
-        // just write serialized data into file
-        let offset: u64 = inner.writer.seek(SeekFrom::End(0))?;
-        // write the current offset to inner index.
-        if let Some(_) = inner.index.insert(key, offset) {
-            inner.useless_cmd += 1;
-        }
-        inner
-            .writer
-            .write_all(format!("{}\n", inst_str).as_bytes())?;
-        inner.writer.flush()?;
-        // NOTE: do_compaction here is not efficient.
-        inner.do_compaction()?;
-        Ok(())
+    fn set_bytes_with_ttl(self: &KvStore, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.set(key, val, Some(expire_at(ttl)))
     }
 
-    fn get(self: &KvStore, key: String) -> Result<Option<String>> {
-        let mut inner: MutexGuard<InnerStore> = self.inner.lock().expect("Lock KvsEngine failed.");
-        if !inner.index.contains_key(&key) {
-            return Ok(None);
-        }
+    fn get_bytes(self: &KvStore, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // load command from file and run it, it doesn't need the writer's lock.
+        Ok(self
+            .reader
+            .read_command(&self.index, key)?
+            .and_then(|instruction| value_of(instruction, key)))
+    }
 
-        // load command from file and run it.
-        let pointer = inner.index.get(&key).unwrap().to_owned();
-        inner.reader.seek(SeekFrom::Start(pointer))?;
-        let mut buf: String = String::new();
-        inner.reader.read_line(&mut buf)?;
-        let instruction: Instruction = serde_json::from_str(&buf)?;
-        match instruction {
-            Instruction::Set { key: _key, value } => Ok(Some(value.clone())),
+    fn ttl_bytes(self: &KvStore, key: &[u8]) -> Result<Option<Duration>> {
+        // keys set by batches never expire.
+        match self.reader.read_command(&self.index, key)? {
+            Some(Instruction::Set { expire_at, .. }) => Ok(ttl_of(expire_at)),
             _ => Ok(None),
         }
     }
 
-    fn remove(self: &KvStore, key: String) -> Result<()> {
-        let mut inner: MutexGuard<InnerStore> = self.inner.lock().expect("Lock KvsEngine failed.");
+    fn remove_bytes(self: &KvStore, key: &[u8]) -> Result<()> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.remove(key)
+    }
 
-        // check key exists.
-        if !inner.index.contains_key(&key) {
-            return Err(KvsError::from_string("Key not found"));
-        }
-        // Remember to remove key from inner index.
-        if let Some(_) = inner.index.remove(&key) {
-            inner.useless_cmd += 1;
-        }
-        inner.writer.seek(SeekFrom::End(0))?;
-        let instruction: Instruction = Instruction::Rm { key };
-        let inst_str = serde_json::to_string(&instruction)?;
-        inner
-            .writer
-            .write_all(format!("{}\n", inst_str).as_bytes())?;
-        inner.writer.flush()?;
-        inner.do_compaction()?;
-        Ok(())
+    fn compare_and_swap_bytes(
+        self: &KvStore,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.compare_and_swap(key, expected, new)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.write_batch(batch)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut writer: MutexGuard<KvStoreWriter> =
+            self.writer.lock().expect("Lock KvsEngine failed.");
+        writer.flush()
+    }
+
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a,
+    {
+        // keys removed during scanning are skipped.
+        Ok(Box::new(self.index.range(range).filter_map(move |entry| {
+            let key: &Vec<u8> = entry.key();
+            match self.reader.read_command(&self.index, key) {
+                Ok(instruction) => instruction
+                    .and_then(|instruction| value_of(instruction, key))
+                    .map(|value| Ok((key.clone(), value))),
+                Err(e) => Some(Err(e)),
+            }
+        })))
     }
 }
 
 impl Clone for KvStore {
     fn clone(&self) -> Self {
         KvStore {
-            inner: self.inner.clone(),
+            index: self.index.clone(),
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
         }
     }
 }
@@ -216,7 +1154,8 @@ impl<T: Seek + Read> BufRead for BufReaderSeekable<T> {
         self.inner.fill_buf()
     }
     fn consume(&mut self, amt: usize) {
-        self.inner.consume(amt)
+        self.inner.consume(amt);
+        self.pos += amt as u64;
     }
 }
 
@@ -1,27 +1,215 @@
+use crate::command::WriteBatch;
 use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
+/// Iterator over key/value pairs in key order.
+pub type ScanIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// Iterator over binary key/value pairs in key order.
+pub type BytesScanIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Storage engine, keys and values are bytes.  The string methods are wrappers of the byte
+/// methods, they fail if the stored bytes are not valid UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a key.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()>;
+
+    /// Set the value of a key, the key expires after `ttl`.
+    ///
+    /// An expired key is taken as removed, it's not returned by `get` or scans.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not written successfully.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Get the value of a key.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not read successfully.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Remaining time to live of a key, `None` if the key never expires or it does not exist.
+    ///
+    /// Engines which don't know expire times always return `None`.
+    ///
+    /// # Errors
+    /// This method should return an error if the expire time is not read successfully.
+    fn ttl_bytes(&self, _key: &[u8]) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    /// An error should occured when the key does not exist or it's not remove successfully.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Set the value of a key to `new` only if it's current value is `expected`, a `None` value
+    /// means the key does not exist, so `new` as `None` removes the key.  The swapped value keeps
+    /// the expire time of the key.
+    ///
+    /// Returns false if the current value doesn't match, nothing is changed in that case.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not read or written successfully.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Set the value of a key only if the key does not exist, returns false if it exists.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not read or written successfully.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(val))
+    }
+
+    /// Apply all operations of the batch atomically, either all of them take effect or none.
+    ///
+    /// # Errors
+    /// An error should occured when a removed key does not exist or the batch is not written
+    /// successfully, nothing is changed in that case.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Persist written data to disk, and wait for background work like compaction to finish.
+    ///
+    /// # Errors
+    /// This method should return an error if the data is not flushed successfully.
+    fn flush(&self) -> Result<()>;
+
+    /// Scan key/value pairs whose keys are in the given range, in key order.
+    ///
+    /// # Errors
+    /// The iterator yields an error if a value is not read successfully.
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a;
+
+    /// Scan key/value pairs whose keys start with the given prefix, in key order.
+    ///
+    /// # Errors
+    /// The iterator yields an error if a value is not read successfully.
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter<'_>> {
+        let prefix: Vec<u8> = prefix.to_vec();
+        let iter: BytesScanIter = self.scan_bytes(prefix.clone()..)?;
+        Ok(Box::new(iter.take_while(move |item| match item {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
     /// Set the value of a string key to a string.
     ///
     /// # Errors
     /// This method should return an error if the value is not written successfully.
-    fn set(&self, key: String, val: String) -> Result<()>;
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    /// Set the value of a string key to a string, the key expires after `ttl`.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not written successfully.
+    fn set_with_ttl(&self, key: String, val: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), val.into_bytes(), ttl)
+    }
 
     /// Get the string value of a string key.
     ///
     /// # Errors
     /// This method should return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// Remove a given key.
     ///
     /// # Errors
     /// An error should occured when the key does not exist or it's not remove successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Set the string value of a string key to `new` only if it's current value is `expected`.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not read or written successfully.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the value of a string key only if the key does not exist.
+    ///
+    /// # Errors
+    /// This method should return an error if the value is not read or written successfully.
+    fn set_if_absent(&self, key: String, val: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    /// Scan string key/value pairs whose keys are in the given range, in key order.
+    ///
+    /// # Errors
+    /// The iterator yields an error if a value is not read successfully.
+    fn scan<'a, R>(&'a self, range: R) -> Result<ScanIter<'a>>
+    where
+        R: RangeBounds<String> + 'a,
+    {
+        let range: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (
+            to_bytes_bound(range.start_bound()),
+            to_bytes_bound(range.end_bound()),
+        );
+        Ok(Box::new(self.scan_bytes(range)?.map(decode_pair)))
+    }
+
+    /// Scan string key/value pairs whose keys start with the given prefix, in key order.
+    ///
+    /// # Errors
+    /// The iterator yields an error if a value is not read successfully.
+    fn scan_prefix(&self, prefix: &str) -> Result<ScanIter<'_>> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.as_bytes())?.map(decode_pair),
+        ))
+    }
+}
+
+fn to_bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_bytes().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn decode_pair(pair: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
 }
 
+#[cfg(feature = "async")]
+mod async_engine;
 mod kvs;
 mod sled;
 
+#[cfg(feature = "async")]
+pub use self::async_engine::AsyncKvsEngine;
 pub use self::kvs::KvStore;
 pub use self::sled::SledKvsEngine;
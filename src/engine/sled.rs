@@ -1,50 +1,172 @@
 //! Sled kvs engine.
-use super::KvsEngine;
+use super::{BytesScanIter, KvsEngine};
+use crate::command::{expire_at, is_expired, ttl_of, BatchOp, WriteBatch};
 use crate::{KvsError, Result};
-use sled::Db;
+use sled::transaction::{
+    ConflictableTransactionResult, TransactionError, TransactionResult, TransactionalTree,
+};
+use sled::{Batch, Db, IVec, Transactional, Tree};
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
-use std::str;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// Expire times of keys are kept in this tree, as big endian milliseconds since unix epoch.
+static TTL_TREE_NAME: &str = "ttl";
 
 struct InnerSledEngine {
     inner: Db,
+    ttl: Tree,
 }
 
 impl InnerSledEngine {
-    pub fn insert(&mut self, key: String, val: String) -> Result<()> {
-        self.inner.insert(key.as_bytes(), val.as_bytes())?;
-        self.inner.flush()?;
-        Ok(())
+    pub fn insert(&mut self, key: Vec<u8>, val: Vec<u8>, expire_at: Option<u64>) -> Result<()> {
+        self.transact(|values, ttl| {
+            match expire_at {
+                Some(expire_at) => ttl.insert(key.as_slice(), &expire_at.to_be_bytes())?,
+                None => ttl.remove(key.as_slice())?,
+            };
+            values.insert(key.as_slice(), val.as_slice())?;
+            Ok(())
+        })
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let result = self.inner.remove(key.as_bytes())?;
-        // This maybe not efficient.
-        self.inner.flush()?;
-        if result.is_some() {
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        let live: bool = self.is_live(key)?;
+        // expired keys are removed as well.
+        self.purge(key)?;
+        if live {
             Ok(())
         } else {
-            Err(KvsError::from_string("Key not found"))
+            Err(KvsError::key_not_found())
         }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let result = self.inner.get(key.as_bytes())?;
-        if let Some(value) = result {
-            // NOTE: sled::IVec implement Deref<target=[u8]>, so sled::IVec can invoke to_vec method.
-            Ok(Some(String::from_utf8(value.to_vec())?))
-        } else {
-            Err(KvsError::from_string("Key not found"))
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        // the value and its expire time are compared and swapped together, expired keys are
+        // taken as absent.
+        self.transact(|values, ttl| {
+            let expired: bool = is_expired(
+                ttl.get(key.as_slice())?
+                    .map(|bytes| decode_expire_at(&bytes)),
+            );
+            let current: Option<IVec> = if expired {
+                None
+            } else {
+                values.get(key.as_slice())?
+            };
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            // the new value keeps the expire time of the current one.
+            if expired || new.is_none() {
+                ttl.remove(key.as_slice())?;
+            }
+            match &new {
+                Some(new) => values.insert(key.as_slice(), new.as_slice())?,
+                None => values.remove(key.as_slice())?,
+            };
+            Ok(true)
+        })
+    }
+
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        batch.check_removes(|key| self.is_live(key))?;
+        let mut sled_batch: Batch = Batch::default();
+        let mut ttl_batch: Batch = Batch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    ttl_batch.remove(key.as_slice());
+                    sled_batch.insert(key, value);
+                }
+                BatchOp::Rm { key } => {
+                    ttl_batch.remove(key.as_slice());
+                    sled_batch.remove(key);
+                }
+            }
         }
+        self.transact(|values, ttl| {
+            ttl.apply_batch(&ttl_batch)?;
+            values.apply_batch(&sled_batch)?;
+            Ok(())
+        })
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.inner.get(key)?;
+        match result {
+            Some(value) if !is_expired(expire_at_of(&self.ttl, key)?) => {
+                // NOTE: sled::IVec implement Deref<target=[u8]>, so sled::IVec can invoke to_vec method.
+                Ok(Some(value.to_vec()))
+            }
+            Some(_) => {
+                // remove the expired key lazily.
+                self.purge(key)?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the key and its expire time, whether it's live or not.
+    fn purge(&self, key: &[u8]) -> Result<()> {
+        self.transact(|values, ttl| {
+            ttl.remove(key)?;
+            values.remove(key)?;
+            Ok(())
+        })
+    }
+
+    /// Check if the key exists and it's not expired.
+    fn is_live(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.inner.contains_key(key)? && !is_expired(expire_at_of(&self.ttl, key)?))
+    }
+
+    /// Update values and their expire times in one transaction, then flush.
+    fn transact<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T>,
+    {
+        let result: TransactionResult<T> =
+            (&*self.inner, &self.ttl).transaction(|(values, ttl)| f(values, ttl));
+        let result: T = match result {
+            Ok(result) => result,
+            Err(TransactionError::Storage(e)) => return Err(KvsError::from(e)),
+            Err(TransactionError::Abort(())) => {
+                return Err(KvsError::storage_failure("Transaction aborted"))
+            }
+        };
+        // This maybe not efficient.
+        self.inner.flush()?;
+        Ok(result)
     }
 
     pub fn new(path: &Path) -> Result<InnerSledEngine> {
+        let inner: Db = sled::open(path)?;
         Ok(InnerSledEngine {
-            inner: Db::open(path)?,
+            ttl: inner.open_tree(TTL_TREE_NAME)?,
+            inner,
         })
     }
 }
 
+/// Expire time of a key, `None` if it never expires.
+fn expire_at_of(ttl: &Tree, key: &[u8]) -> Result<Option<u64>> {
+    Ok(ttl.get(key)?.map(|bytes| decode_expire_at(&bytes)))
+}
+
+fn decode_expire_at(bytes: &[u8]) -> u64 {
+    let mut expire_at: [u8; 8] = [0; 8];
+    expire_at.copy_from_slice(bytes);
+    u64::from_be_bytes(expire_at)
+}
+
 pub struct SledKvsEngine {
     inner: Arc<Mutex<InnerSledEngine>>,
 }
@@ -61,23 +183,90 @@ impl SledKvsEngine {
         let full_path: PathBuf = path.join(file_name);
         full_path.exists()
     }
+
+    fn trees(&self) -> (Db, Tree) {
+        let inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        (inner.inner.clone(), inner.ttl.clone())
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, val: String) -> Result<()> {
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        inner.insert(key, val, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
         let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
-        inner.insert(key, val)
+        inner.insert(key, val, Some(expire_at(ttl)))
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
         inner.get(key)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        if !inner.inner.contains_key(key)? {
+            return Ok(None);
+        }
+        Ok(ttl_of(expire_at_of(&inner.ttl, key)?))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
         let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
         inner.remove(key)
     }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        inner.compare_and_swap(key, expected, new)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        inner.apply_batch(batch)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let inner: MutexGuard<InnerSledEngine> = self.inner.lock().expect("Can't get lock");
+        inner.inner.flush()?;
+        Ok(())
+    }
+
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a,
+    {
+        let (db, ttl): (Db, Tree) = self.trees();
+        Ok(Box::new(
+            db.range(range)
+                .filter_map(move |pair| live_pair(&ttl, pair).transpose()),
+        ))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter<'_>> {
+        let (db, ttl): (Db, Tree) = self.trees();
+        Ok(Box::new(
+            db.scan_prefix(prefix)
+                .filter_map(move |pair| live_pair(&ttl, pair).transpose()),
+        ))
+    }
+}
+
+/// Decode a scanned pair, expired pairs are skipped.
+fn live_pair(ttl: &Tree, pair: sled::Result<(IVec, IVec)>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let (key, value) = pair?;
+    if is_expired(expire_at_of(ttl, &key)?) {
+        return Ok(None);
+    }
+    Ok(Some((key.to_vec(), value.to_vec())))
 }
 
 impl Clone for SledKvsEngine {
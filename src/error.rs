@@ -1,4 +1,6 @@
 use bincode::Error as BincodeError;
+use rustls::Error as TlsError;
+use serde::{Deserialize, Serialize};
 use serde_json::Error as JsonError;
 use sled::Error as SledError;
 use std::error::Error;
@@ -19,6 +21,33 @@ pub enum Repr {
     FromUtf8Error(FromUtf8Error),
     CommandError(String),
     StorageEngineError(String),
+    KeyNotFound,
+    InvalidRequest(String),
+    StorageFailure(String),
+    Conflict(String),
+    Unauthorized(String),
+    TlsError(TlsError),
+    /// Address of the leader, if it's known.
+    NotLeader(Option<String>),
+}
+
+/// Kind of an error, it's sent to clients so they don't need to match error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The key does not exist.
+    NotFound,
+    /// The request is malformed or it's not valid for the current data.
+    InvalidRequest,
+    /// The engine fails to read or write data.
+    StorageFailure,
+    /// The current value doesn't match the expected one of a compare-and-swap.
+    Conflict,
+    /// The client is not allowed to do the request.
+    Unauthorized,
+    /// The server is not the leader of the cluster, the request should be sent to the leader.
+    NotLeader,
+    /// Other errors.
+    Internal,
 }
 
 #[derive(Debug)]
@@ -36,6 +65,13 @@ impl Error for KvsError {
             Repr::FromUtf8Error(e) => e.source(),
             Repr::CommandError(_) => None,
             Repr::StorageEngineError(_) => None,
+            Repr::KeyNotFound => None,
+            Repr::InvalidRequest(_) => None,
+            Repr::StorageFailure(_) => None,
+            Repr::Conflict(_) => None,
+            Repr::Unauthorized(_) => None,
+            Repr::TlsError(e) => e.source(),
+            Repr::NotLeader(_) => None,
         }
     }
 }
@@ -80,9 +116,33 @@ impl From<JsonError> for KvsError {
     }
 }
 
+impl From<TlsError> for KvsError {
+    fn from(error: TlsError) -> Self {
+        KvsError {
+            repr: Repr::TlsError(error),
+        }
+    }
+}
+
 impl Display for KvsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.repr)
+        match &self.repr {
+            Repr::IOError(e) => write!(f, "IO error: {}", e),
+            Repr::BinCodeError(e) => write!(f, "Bincode error: {}", e),
+            Repr::SledError(e) => write!(f, "Sled error: {}", e),
+            Repr::JsonError(e) => write!(f, "JSON error: {}", e),
+            Repr::FromUtf8Error(e) => write!(f, "Invalid UTF-8: {}", e),
+            Repr::TlsError(e) => write!(f, "TLS error: {}", e),
+            Repr::KeyNotFound => write!(f, "Key not found"),
+            Repr::NotLeader(Some(leader)) => write!(f, "Not the leader, the leader is {}", leader),
+            Repr::NotLeader(None) => write!(f, "Not the leader, the leader is unknown"),
+            Repr::CommandError(msg)
+            | Repr::StorageEngineError(msg)
+            | Repr::InvalidRequest(msg)
+            | Repr::StorageFailure(msg)
+            | Repr::Conflict(msg)
+            | Repr::Unauthorized(msg) => write!(f, "{}", msg),
+        }
     }
 }
 
@@ -99,6 +159,78 @@ impl KvsError {
         }
     }
 
+    pub fn key_not_found() -> KvsError {
+        KvsError {
+            repr: Repr::KeyNotFound,
+        }
+    }
+
+    pub fn invalid_request(msg: &str) -> KvsError {
+        KvsError::from_code(ErrorCode::InvalidRequest, msg)
+    }
+
+    pub fn storage_failure(msg: &str) -> KvsError {
+        KvsError::from_code(ErrorCode::StorageFailure, msg)
+    }
+
+    pub fn unauthorized(msg: &str) -> KvsError {
+        KvsError::from_code(ErrorCode::Unauthorized, msg)
+    }
+
+    /// The server is not the leader of the cluster, `leader` is the address of the leader.
+    pub fn not_leader(leader: Option<String>) -> KvsError {
+        KvsError {
+            repr: Repr::NotLeader(leader),
+        }
+    }
+
+    /// Build the error of a code, it's used to restore errors sent by the server.
+    pub fn from_code(code: ErrorCode, msg: &str) -> KvsError {
+        let msg: String = msg.to_owned();
+        let repr: Repr = match code {
+            ErrorCode::NotFound => Repr::KeyNotFound,
+            ErrorCode::InvalidRequest => Repr::InvalidRequest(msg),
+            ErrorCode::StorageFailure => Repr::StorageFailure(msg),
+            ErrorCode::Conflict => Repr::Conflict(msg),
+            ErrorCode::Unauthorized => Repr::Unauthorized(msg),
+            ErrorCode::NotLeader => Repr::NotLeader(None),
+            ErrorCode::Internal => Repr::CommandError(msg),
+        };
+        KvsError { repr }
+    }
+
+    /// Code of the error, which is sent to clients.
+    pub fn code(&self) -> ErrorCode {
+        match &self.repr {
+            Repr::KeyNotFound => ErrorCode::NotFound,
+            Repr::InvalidRequest(_) | Repr::JsonError(_) | Repr::FromUtf8Error(_) => {
+                ErrorCode::InvalidRequest
+            }
+            Repr::IOError(_)
+            | Repr::BinCodeError(_)
+            | Repr::SledError(_)
+            | Repr::StorageEngineError(_)
+            | Repr::StorageFailure(_) => ErrorCode::StorageFailure,
+            Repr::Conflict(_) => ErrorCode::Conflict,
+            Repr::Unauthorized(_) => ErrorCode::Unauthorized,
+            Repr::NotLeader(_) => ErrorCode::NotLeader,
+            Repr::CommandError(_) | Repr::TlsError(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// Check if it's the error of a missing key.
+    pub fn is_key_not_found(&self) -> bool {
+        matches!(&self.repr, Repr::KeyNotFound)
+    }
+
+    /// Address of the leader if it's the error of a server which is not the leader.
+    pub fn leader(&self) -> Option<&str> {
+        match &self.repr {
+            Repr::NotLeader(leader) => leader.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn repr(&self) -> &Repr {
         &self.repr
     }
@@ -28,14 +28,45 @@ impl FromStr for Engine {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Our own protocol, framed JSON instructions.
+    Kvs,
+    /// Redis serialization protocol.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::from_string(&format!(
+                "Unsupported protocol {}",
+                s
+            ))),
+        }
+    }
+}
+
 pub mod command;
 mod engine;
 mod error;
 mod network;
 pub mod thread_pool;
 
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Repr, Result};
-pub use network::client::Client;
+pub use command::{Credentials, WriteBatch};
+#[cfg(feature = "async")]
+pub use engine::AsyncKvsEngine;
+pub use engine::{BytesScanIter, KvStore, KvsEngine, ScanIter, SledKvsEngine};
+pub use error::{ErrorCode, KvsError, Repr, Result};
 pub use network::server::Server;
-pub use network::Response;
+#[cfg(feature = "async")]
+pub use network::{AsyncClient, AsyncServer};
+pub use network::{AuthConfig, ClusterHandle, RaftConfig, ReplicaStatus, Response, ShutdownHandle};
+pub use network::{
+    Client, ClientConfig, ClientPool, ClientTls, ClusterClient, ServerTls, ShardedClient,
+};
+pub use network::{WatchEvent, Watcher};
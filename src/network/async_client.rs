@@ -0,0 +1,135 @@
+use super::frame::{read_frame_async, write_frame_async};
+use super::Response;
+use crate::command::{Credentials, Instruction, WriteBatch};
+use crate::error::{ErrorCode, KvsError, Result};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Tokio based client, it works with both `Server` and `AsyncServer`.
+pub struct AsyncClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+}
+
+impl AsyncClient {
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<AsyncClient> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(AsyncClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        })
+    }
+
+    pub async fn send_instruction(&mut self, inst: &Instruction) -> Result<()> {
+        write_frame_async(&mut self.writer, inst).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read_response(&mut self) -> Result<Response> {
+        match read_frame_async(&mut self.reader).await? {
+            Some(response) => Ok(response),
+            None => Err(KvsError::from(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed by server",
+            ))),
+        }
+    }
+
+    /// Send the instruction and wait for it's response, returns the response body if it
+    /// succeeds, otherwise the error sent by the server.
+    pub async fn execute(&mut self, inst: &Instruction) -> Result<Vec<u8>> {
+        self.send_instruction(inst).await?;
+        self.read_response().await?.into_result()
+    }
+
+    /// Same as `Client::login`.
+    pub async fn login(&mut self, credentials: Credentials) -> Result<()> {
+        self.execute(&Instruction::Auth { credentials }).await?;
+        Ok(())
+    }
+
+    /// Same as `KvsEngine::set_bytes`.
+    pub async fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let inst: Instruction = Instruction::Set {
+            key,
+            value: val,
+            expire_at: None,
+        };
+        self.execute(&inst).await?;
+        Ok(())
+    }
+
+    /// Same as `KvsEngine::set_bytes_with_ttl`.
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let inst: Instruction = Instruction::SetWithTtl {
+            key,
+            value: val,
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        self.execute(&inst).await?;
+        Ok(())
+    }
+
+    /// Same as `KvsEngine::get_bytes`.
+    pub async fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.execute(&Instruction::Get { key: key.to_vec() }).await {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Same as `KvsEngine::remove_bytes`.
+    pub async fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.execute(&Instruction::Rm { key: key.to_vec() }).await?;
+        Ok(())
+    }
+
+    /// Same as `KvsEngine::compare_and_swap_bytes`.
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let inst: Instruction = Instruction::CompareAndSwap { key, expected, new };
+        match self.execute(&inst).await {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.code() == ErrorCode::Conflict => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Same as `KvsEngine::write_batch`.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.execute(&Instruction::Batch { batch }).await?;
+        Ok(())
+    }
+
+    /// Same as `KvsEngine::set`.
+    pub async fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes()).await
+    }
+
+    /// Same as `KvsEngine::get`.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes()).await? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Same as `KvsEngine::remove`.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes()).await
+    }
+}
@@ -0,0 +1,83 @@
+use super::frame::{read_frame_async, write_frame_async};
+use super::Response;
+use crate::command::Instruction;
+use crate::engine::{AsyncKvsEngine, KvsEngine};
+use crate::error::Result;
+use log::{debug, error};
+use std::net::SocketAddr;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Tokio based server, it speaks the same protocol as `Server` with `Protocol::Kvs`.
+///
+/// Each connection is served by an async task, and engine calls run on the blocking pool, so
+/// idle connections don't hold threads.
+pub struct AsyncServer<E: KvsEngine> {
+    listener: TcpListener,
+    engine: AsyncKvsEngine<E>,
+}
+
+impl<E: KvsEngine> AsyncServer<E> {
+    pub async fn bind<T>(addr: T, engine: E) -> Result<AsyncServer<E>>
+    where
+        T: ToSocketAddrs,
+    {
+        Ok(AsyncServer {
+            listener: TcpListener::bind(addr).await?,
+            engine: AsyncKvsEngine::new(engine),
+        })
+    }
+
+    /// Address which the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn serve_forever(self) -> Result<()> {
+        debug!("Waiting for connections...");
+        loop {
+            match self.listener.accept().await {
+                Ok((client_stream, peer_addr)) => {
+                    debug!("New connection established from {}", peer_addr);
+                    let engine: AsyncKvsEngine<E> = self.engine.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(client_stream, engine).await {
+                            error!("Handle client failed, reason: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Connection failed, reason: {:?}", e),
+            }
+        }
+    }
+}
+
+async fn handle_client<E: KvsEngine>(
+    client_stream: TcpStream,
+    engine: AsyncKvsEngine<E>,
+) -> Result<()> {
+    let peer_addr: SocketAddr = client_stream.peer_addr()?;
+    let (reader, writer) = client_stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let instruction: Instruction = match read_frame_async(&mut reader).await? {
+            Some(instruction) => instruction,
+            None => {
+                debug!(
+                    "Connection closed by peer {}, so this connection is closed.",
+                    peer_addr
+                );
+                break;
+            }
+        };
+        debug!("Peer: {}, Instruction: {:?}", peer_addr, instruction);
+        let response: Response = engine.execute(instruction).await;
+        write_frame_async(&mut writer, &response).await?;
+        // pipelined requests are answered together, once all received ones are handled.
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
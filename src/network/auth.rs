@@ -0,0 +1,203 @@
+//! Authentication of clients, and ACLs of key prefixes.
+//!
+//! Users are loaded from a JSON config file of the server, like:
+//!
+//! ```json
+//! {
+//!     "users": [
+//!         {
+//!             "name": "billing",
+//!             "password": "secret",
+//!             "rules": [{"prefix": "billing/", "access": "read_write"}]
+//!         },
+//!         {
+//!             "name": "reports",
+//!             "token": "0123456789abcdef",
+//!             "rules": [{"prefix": "billing/", "access": "read"}]
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! A user logs in by the token, or the name and the password.  A request is allowed if a rule
+//! of the user grants the access to a prefix of the key, keys out of all prefixes are denied.
+//! `access` is `read`, `write` or `read_write`.
+use crate::command::{Credentials, Instruction};
+use crate::error::{KvsError, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Users which are allowed to log in, and their ACLs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Rule {
+    #[serde(default)]
+    prefix: String,
+    access: Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+impl AuthConfig {
+    /// Load the config file.
+    pub fn from_file(path: &Path) -> Result<AuthConfig> {
+        let config: AuthConfig = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        for user in &config.users {
+            if user.password.is_none() && user.token.is_none() {
+                return Err(KvsError::invalid_request(&format!(
+                    "User {} has neither password nor token",
+                    user.name
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    fn find_user(&self, credentials: &Credentials) -> Option<usize> {
+        self.users.iter().position(|user| match credentials {
+            Credentials::Token(token) => user
+                .token
+                .as_ref()
+                .is_some_and(|expected| secret_eq(expected, token)),
+            Credentials::Password {
+                user: name,
+                password,
+            } => {
+                user.name == *name
+                    && user
+                        .password
+                        .as_ref()
+                        .is_some_and(|expected| secret_eq(expected, password))
+            }
+        })
+    }
+}
+
+// Compare secrets in constant time, so they can't be guessed by timing.
+fn secret_eq(expected: &str, actual: &str) -> bool {
+    let (expected, actual) = (expected.as_bytes(), actual.as_bytes());
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Who the client of a connection is, it's checked by every request.
+///
+/// Everything is allowed if the server has no `AuthConfig`.
+#[derive(Default)]
+pub(crate) struct Session {
+    auth: Option<Arc<AuthConfig>>,
+    // index of the logged in user.
+    user: Option<usize>,
+}
+
+impl Session {
+    pub(crate) fn new(auth: Option<Arc<AuthConfig>>) -> Session {
+        Session { auth, user: None }
+    }
+
+    /// Log in as the user of the credentials, the former user is logged out even if it fails.
+    pub(crate) fn login(&mut self, credentials: &Credentials) -> Result<()> {
+        let auth: &AuthConfig = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        self.user = auth.find_user(credentials);
+        match self.user {
+            Some(_) => Ok(()),
+            None => Err(KvsError::unauthorized("Invalid credentials")),
+        }
+    }
+
+    /// Check if the instruction is allowed, keys of scans are checked by `can_read`.
+    pub(crate) fn authorize(&self, instruction: &Instruction) -> Result<()> {
+        match instruction {
+            Instruction::Auth { .. } => Ok(()),
+            Instruction::Get { key } | Instruction::Ttl { key } => self.check(key, Access::Read),
+            Instruction::Set { key, .. }
+            | Instruction::SetWithTtl { key, .. }
+            | Instruction::Rm { key } => self.check(key, Access::Write),
+            // the result tells if the current value is the expected one.
+            Instruction::CompareAndSwap { key, .. } => {
+                self.check(key, Access::Read)?;
+                self.check(key, Access::Write)
+            }
+            Instruction::Batch { batch } => batch
+                .ops()
+                .iter()
+                .try_for_each(|op| self.check(op.key(), Access::Write)),
+            Instruction::Scan { .. } => self.rules().map(|_| ()),
+            // followers receive all keys, only a rule of the empty prefix allows it.
+            Instruction::Replicate { .. } => self.check(&[], Access::Read),
+            // nodes of a cluster replicate writes of all keys to each other.
+            Instruction::Raft { .. } => {
+                self.check(&[], Access::Read)?;
+                self.check(&[], Access::Write)
+            }
+            // changes are filtered by `can_read` like keys of scans.
+            Instruction::Watch { .. } => self.rules().map(|_| ()),
+        }
+    }
+
+    /// Check if the key can be read, it's false if the client is not logged in.
+    pub(crate) fn can_read(&self, key: &[u8]) -> bool {
+        self.check(key, Access::Read).is_ok()
+    }
+
+    fn check(&self, key: &[u8], access: Access) -> Result<()> {
+        let rules: &[Rule] = match self.rules()? {
+            Some(rules) => rules,
+            None => return Ok(()),
+        };
+        let allowed: bool = rules
+            .iter()
+            .any(|rule| key.starts_with(rule.prefix.as_bytes()) && rule.access.allows(access));
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvsError::unauthorized("Permission denied"))
+        }
+    }
+
+    // Rules of the logged in user, `None` if auth is disabled.
+    fn rules(&self) -> Result<Option<&[Rule]>> {
+        match (&self.auth, self.user) {
+            (None, _) => Ok(None),
+            (Some(auth), Some(user)) => Ok(Some(&auth.users[user].rules)),
+            (Some(_), None) => Err(KvsError::unauthorized("Authentication required")),
+        }
+    }
+}
@@ -1,30 +1,372 @@
+use super::frame::{read_frame, write_frame};
+use super::stream::Stream;
+use super::tls::ClientTls;
+use super::watch::Watcher;
 use super::Response;
-use crate::command::Instruction;
-use crate::error::Result;
-use serde_json;
+use crate::command::{Credentials, Instruction, WriteBatch};
+use crate::engine::{BytesScanIter, KvsEngine};
+use crate::error::{ErrorCode, KvsError, Result};
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
+// how many responses can be pending in a pipeline.
+const PIPELINE_WINDOW: usize = 128;
+// how many pairs are fetched by a scan request.
+const SCAN_PAGE_SIZE: u32 = 128;
+
+/// Timeouts of client connections, and how requests are retried by `ClientPool`.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Timeout to establish a connection, `None` means blocking until the OS gives up.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout to read a response, `None` means blocking forever.
+    pub read_timeout: Option<Duration>,
+    /// Timeout to write a request, `None` means blocking forever.
+    pub write_timeout: Option<Duration>,
+    /// How many times idempotent requests are retried on connection errors.
+    pub retries: u32,
+    /// Delay before the first retry, it's doubled for each of the following ones.
+    pub backoff: Duration,
+    /// How many idle connections are kept by `ClientPool`.
+    pub max_idle: usize,
+    /// Connect over TLS if it's set, otherwise plain TCP.
+    pub tls: Option<ClientTls>,
+    /// Log in with the credentials once connected, for servers which require authentication.
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_idle: 8,
+            tls: None,
+            credentials: None,
+        }
+    }
+}
+
+/// Client of a kvs server.
+///
+/// It implements `KvsEngine`, so a remote store can be used like a local one.  Clones share the
+/// same connection, requests of them are sent one by one.
+#[derive(Clone)]
 pub struct Client {
-    inner: TcpStream,
+    connection: Arc<Mutex<ClientConnection>>,
+}
+
+struct ClientConnection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
 }
 
 impl Client {
     pub fn connect(addr: &str) -> Result<Client> {
-        let stream: TcpStream = TcpStream::connect(addr)?;
-        Ok(Client { inner: stream })
+        Client::connect_with(addr, &ClientConfig::default())
     }
 
-    pub fn send_instruction(&mut self, inst: &Instruction) -> Result<()> {
-        let out: String = serde_json::to_string(inst)?;
-        self.inner.write_all(out.as_bytes())?;
+    /// Connect to the server with timeouts and TLS settings of the config, then log in if it
+    /// has credentials.
+    pub fn connect_with(addr: &str, config: &ClientConfig) -> Result<Client> {
+        let stream: TcpStream = match config.connect_timeout {
+            Some(timeout) => connect_timeout(addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_read_timeout(config.read_timeout)?;
+        stream.set_write_timeout(config.write_timeout)?;
+        let stream: Stream = match &config.tls {
+            Some(tls) => tls.connect(addr, stream)?,
+            None => Stream::Tcp(stream),
+        };
+        let client: Client = Client {
+            connection: Arc::new(Mutex::new(ClientConnection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+            })),
+        };
+        if let Some(credentials) = &config.credentials {
+            client.login(credentials)?;
+        }
+        Ok(client)
+    }
+
+    /// Log in to the server, following requests are checked by ACLs of the user.
+    pub fn login(&self, credentials: &Credentials) -> Result<()> {
+        self.request(&Instruction::Auth {
+            credentials: credentials.clone(),
+        })?;
         Ok(())
     }
 
+    pub fn send_instruction(&mut self, inst: &Instruction) -> Result<()> {
+        self.lock().send_instruction(inst)
+    }
+
+    /// Send the instruction and wait for it's response, returns the response body if it
+    /// succeeds, otherwise the error sent by the server, like `KvsError::is_key_not_found`.
+    pub fn execute(&mut self, inst: &Instruction) -> Result<Vec<u8>> {
+        self.request(inst)
+    }
+
+    /// Send instructions back-to-back without waiting for responses, then returns their
+    /// responses in the same order.
+    ///
+    /// At most `PIPELINE_WINDOW` responses are pending, so the server never blocks on writing
+    /// responses while the client is still writing requests.
+    pub fn pipeline(&mut self, instructions: &[Instruction]) -> Result<Vec<Response>> {
+        let mut connection: MutexGuard<ClientConnection> = self.lock();
+        let mut responses: Vec<Response> = Vec::with_capacity(instructions.len());
+        for (sent, inst) in instructions.iter().enumerate() {
+            if sent - responses.len() == PIPELINE_WINDOW {
+                connection.writer.flush()?;
+                responses.push(connection.read_response()?);
+            }
+            write_frame(&mut connection.writer, inst)?;
+        }
+        connection.writer.flush()?;
+        while responses.len() < instructions.len() {
+            responses.push(connection.read_response()?);
+        }
+        Ok(responses)
+    }
+
+    /// Watch changes of keys with the prefix, the connection streams them from now on, so it's
+    /// taken by the returned `Watcher`.
+    ///
+    /// # Errors
+    /// It fails if the server doesn't allow the client to read keys.
+    pub fn watch(self, prefix: &[u8]) -> Result<Watcher> {
+        self.request(&Instruction::Watch {
+            prefix: prefix.to_vec(),
+        })?;
+        Ok(Watcher::new(self))
+    }
+
     pub fn read_response(&mut self) -> Result<Response> {
-        let mut buffer: [u8; 1024] = [0; 1024];
-        let bytes = self.inner.read(&mut buffer)?;
-        let response: Response = serde_json::from_slice(&buffer[..bytes])?;
-        Ok(response)
+        self.lock().read_response()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ClientConnection> {
+        self.connection
+            .lock()
+            .expect("Lock client connection failed.")
+    }
+
+    /// Check if the connection is not closed by the server, without blocking.
+    pub(crate) fn is_alive(&self) -> bool {
+        let connection: MutexGuard<ClientConnection> = self.lock();
+        if !connection.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream: &TcpStream = connection.reader.get_ref().tcp();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // nothing should be sent by the server between requests.
+        let alive: bool = match stream.peek(&mut [0; 1]) {
+            Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        stream.set_nonblocking(false).is_ok() && alive
+    }
+
+    /// Clone of the underlying TCP stream, to change it's timeouts or shut it down.
+    pub(crate) fn tcp_stream(&self) -> io::Result<TcpStream> {
+        self.lock().reader.get_ref().tcp().try_clone()
+    }
+
+    /// Read a message streamed by the server, returns `None` if the connection is closed.
+    pub(crate) fn read_message<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        read_frame(&mut self.lock().reader)
+    }
+}
+
+// Try each resolved address in order, like `TcpStream::connect`.
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error: io::Error =
+        io::Error::new(io::ErrorKind::InvalidInput, "Address is not resolved");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+impl ClientConnection {
+    fn send_instruction(&mut self, inst: &Instruction) -> Result<()> {
+        write_frame(&mut self.writer, inst)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn read_response(&mut self) -> Result<Response> {
+        match read_frame(&mut self.reader)? {
+            Some(response) => Ok(response),
+            None => Err(KvsError::from(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed by server",
+            ))),
+        }
+    }
+}
+
+impl Requester for Client {
+    // the lock is held in between, so responses of clones are not mixed up.
+    fn request(&self, inst: &Instruction) -> Result<Vec<u8>> {
+        let mut connection: MutexGuard<ClientConnection> = self.lock();
+        connection.send_instruction(inst)?;
+        connection.read_response()?.into_result()
+    }
+}
+
+/// Remote store which sends instructions to kvs servers, `Client`, `ClientPool` and
+/// `ClusterClient` implement `KvsEngine` by it.
+pub(crate) trait Requester {
+    /// Send the instruction and map the response to a result, returns the response body if it
+    /// succeeds, otherwise the error sent by the server.
+    fn request(&self, inst: &Instruction) -> Result<Vec<u8>>;
+}
+
+impl<T: Requester + Clone + Send + 'static> KvsEngine for T {
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.request(&Instruction::Set {
+            key,
+            value: val,
+            expire_at: None,
+        })?;
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.request(&Instruction::SetWithTtl {
+            key,
+            value: val,
+            ttl_ms: ttl.as_millis() as u64,
+        })?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&Instruction::Get { key: key.to_vec() }) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        decode_ttl(&self.request(&Instruction::Ttl { key: key.to_vec() })?)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.request(&Instruction::Rm { key: key.to_vec() })?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self.request(&Instruction::CompareAndSwap { key, expected, new }) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.code() == ErrorCode::Conflict => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.request(&Instruction::Batch { batch })?;
+        Ok(())
+    }
+
+    // writes are flushed, or committed by the cluster, before they are answered.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a,
+    {
+        Ok(Box::new(ScanPages::new(
+            move |inst: &Instruction| self.request(inst),
+            range,
+        )))
+    }
+}
+
+/// Decode the response body of `Instruction::Ttl`.
+fn decode_ttl(body: &[u8]) -> Result<Option<Duration>> {
+    let millis: Option<u64> = bincode::deserialize(body)?;
+    Ok(millis.map(Duration::from_millis))
+}
+
+// Send a request and returns the response body.
+type RequestFn<'a> = Box<dyn Fn(&Instruction) -> Result<Vec<u8>> + 'a>;
+
+/// Iterator of a remote scan, pairs are fetched page by page.
+struct ScanPages<'a> {
+    request: RequestFn<'a>,
+    // start of the next page, `None` if all pages are fetched.
+    start: Option<Bound<Vec<u8>>>,
+    end: Bound<Vec<u8>>,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> ScanPages<'a> {
+    /// Scan the range, each page is fetched by `request`.
+    fn new<F, R>(request: F, range: R) -> ScanPages<'a>
+    where
+        F: Fn(&Instruction) -> Result<Vec<u8>> + 'a,
+        R: RangeBounds<Vec<u8>>,
+    {
+        ScanPages {
+            request: Box::new(request),
+            start: Some(range.start_bound().cloned()),
+            end: range.end_bound().cloned(),
+            page: VecDeque::new(),
+        }
+    }
+
+    fn fetch(&mut self, start: Bound<Vec<u8>>) -> Result<()> {
+        let body: Vec<u8> = (self.request)(&Instruction::Scan {
+            start,
+            end: self.end.clone(),
+            limit: SCAN_PAGE_SIZE,
+        })?;
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize(&body)?;
+        if pairs.len() == SCAN_PAGE_SIZE as usize {
+            self.start = pairs.last().map(|(key, _)| Bound::Excluded(key.clone()));
+        }
+        self.page.extend(pairs);
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ScanPages<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() {
+            let start: Bound<Vec<u8>> = self.start.take()?;
+            if let Err(e) = self.fetch(start) {
+                return Some(Err(e));
+            }
+        }
+        self.page.pop_front().map(Ok)
     }
 }
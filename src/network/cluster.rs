@@ -0,0 +1,137 @@
+use super::client::{ClientConfig, Requester};
+use super::pool::{is_broken, ClientPool};
+use crate::command::Instruction;
+use crate::error::{ErrorCode, KvsError, Repr, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Client of a Raft cluster, it implements `KvsEngine` like `Client`.
+///
+/// Requests are sent to the leader, by a `ClientPool` of it.  Nodes which are not the leader
+/// redirect the client to it, and if the leader is unknown or gone, other nodes are tried in
+/// turn, with backoff once all of them are tried.  The config's `retries` is how many times all
+/// nodes are tried.  Writes are only retried if they are redirected or the connection is
+/// refused, because other errors may happen after they are applied.
+#[derive(Clone)]
+pub struct ClusterClient {
+    inner: Arc<ClusterInner>,
+}
+
+struct ClusterInner {
+    nodes: Vec<String>,
+    config: ClientConfig,
+    leader: Mutex<Option<String>>,
+    pools: Mutex<HashMap<String, ClientPool>>,
+}
+
+impl ClusterClient {
+    /// Create a client of the cluster of the nodes, connections are opened on demand.
+    pub fn new(nodes: Vec<String>, config: ClientConfig) -> ClusterClient {
+        ClusterClient {
+            inner: Arc::new(ClusterInner {
+                nodes,
+                config,
+                leader: Mutex::new(None),
+                pools: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Address of the leader which requests are sent to, it's `None` until a request succeeds.
+    pub fn leader(&self) -> Option<String> {
+        self.inner
+            .leader
+            .lock()
+            .expect("Lock cluster leader failed.")
+            .clone()
+    }
+
+    fn set_leader(&self, leader: Option<String>) {
+        *self
+            .inner
+            .leader
+            .lock()
+            .expect("Lock cluster leader failed.") = leader;
+    }
+
+    fn pool(&self, addr: &str) -> ClientPool {
+        let mut pools = self.inner.pools.lock().expect("Lock cluster pools failed.");
+        pools
+            .entry(addr.to_owned())
+            .or_insert_with(|| {
+                // requests are retried on other nodes instead.
+                let config: ClientConfig = ClientConfig {
+                    retries: 0,
+                    ..self.inner.config.clone()
+                };
+                ClientPool::new(addr, config)
+            })
+            .clone()
+    }
+}
+
+impl Requester for ClusterClient {
+    fn request(&self, inst: &Instruction) -> Result<Vec<u8>> {
+        let idempotent: bool = matches!(inst, Instruction::Get { .. } | Instruction::Scan { .. });
+        let nodes: &[String] = &self.inner.nodes;
+        if nodes.is_empty() {
+            return Err(KvsError::invalid_request("The cluster has no nodes"));
+        }
+        let max_attempts: usize = (self.inner.config.retries as usize + 1) * nodes.len();
+        let mut backoff: Duration = self.inner.config.backoff;
+        let mut attempts: usize = 0;
+        let mut tried: usize = 0;
+        loop {
+            let addr: String = match self.leader() {
+                Some(addr) => addr,
+                None => {
+                    // back off once every node is tried, the cluster may be electing a leader.
+                    if tried > 0 && tried.is_multiple_of(nodes.len()) {
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                    let addr: String = nodes[tried % nodes.len()].clone();
+                    tried += 1;
+                    addr
+                }
+            };
+            attempts += 1;
+            let result: Result<Vec<u8>> = self.pool(&addr).request(inst);
+            match result {
+                Err(ref e) if e.code() == ErrorCode::NotLeader => {
+                    debug!(
+                        "Node {} is not the leader, redirected to {:?}",
+                        addr,
+                        e.leader()
+                    );
+                    self.set_leader(e.leader().map(str::to_owned));
+                }
+                Err(ref e) if is_broken(e) => {
+                    debug!("Request to node {} failed, reason: {:?}", addr, e);
+                    self.set_leader(None);
+                    if !idempotent && !is_refused(e) {
+                        return result;
+                    }
+                }
+                result => {
+                    self.set_leader(Some(addr));
+                    return result;
+                }
+            }
+            if attempts >= max_attempts {
+                return result;
+            }
+        }
+    }
+}
+
+// The request is never sent if the connection is refused.
+fn is_refused(e: &KvsError) -> bool {
+    matches!(e.repr(), Repr::IOError(e) if e.kind() == io::ErrorKind::ConnectionRefused)
+}
@@ -0,0 +1,128 @@
+//! Framing of messages between client and server.
+//!
+//! Each message is framed as `payload length (u32, LE) | payload`, the payload is JSON.  So a
+//! message can be up to `MAX_FRAME_LEN` bytes, and a single read may contain several messages.
+use crate::error::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+// the length is sent by the peer before it's authenticated, larger frames are rejected before
+// their payload is read.
+const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+// Check the length of a frame, returns it if it's allowed.
+fn frame_len(len: [u8; 4]) -> Result<u64> {
+    let len: u32 = u32::from_le_bytes(len);
+    if len as usize > MAX_FRAME_LEN {
+        return Err(KvsError::invalid_request("Frame is too large"));
+    }
+    Ok(u64::from(len))
+}
+
+// The payload is read to the end of the frame, it fails if the frame is truncated.
+fn check_payload(payload: &[u8], len: u64) -> Result<()> {
+    if (payload.len() as u64) < len {
+        return Err(KvsError::from(io::Error::from(
+            io::ErrorKind::UnexpectedEof,
+        )));
+    }
+    Ok(())
+}
+
+/// Write a message as a frame, the writer is not flushed.
+pub fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: Write,
+    T: Serialize,
+{
+    let payload: Vec<u8> = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(KvsError::invalid_request("Message is too large"));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read a message from the next frame.
+///
+/// Returns `None` if the peer closes the connection between frames.
+pub fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    let mut len: [u8; 4] = [0; 4];
+    let mut filled: usize = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(KvsError::from(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )))
+            }
+            Ok(bytes) => filled += bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(KvsError::from(e)),
+        }
+    }
+
+    // the payload is allocated as it arrives, not by the length which the peer claims.
+    let len: u64 = frame_len(len)?;
+    let mut payload: Vec<u8> = Vec::new();
+    (&mut *reader).take(len).read_to_end(&mut payload)?;
+    check_payload(&payload, len)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+/// Same as `write_frame`, for async writers.
+#[cfg(feature = "async")]
+pub async fn write_frame_async<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+    T: Serialize,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let payload: Vec<u8> = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(KvsError::invalid_request("Message is too large"));
+    }
+    writer
+        .write_all(&(payload.len() as u32).to_le_bytes())
+        .await?;
+    writer.write_all(&payload).await?;
+    Ok(())
+}
+
+/// Same as `read_frame`, for async readers.
+#[cfg(feature = "async")]
+pub async fn read_frame_async<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut len: [u8; 4] = [0; 4];
+    let mut filled: usize = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => {
+                return Err(KvsError::from(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )))
+            }
+            bytes => filled += bytes,
+        }
+    }
+
+    let len: u64 = frame_len(len)?;
+    let mut payload: Vec<u8> = Vec::new();
+    (&mut *reader).take(len).read_to_end(&mut payload).await?;
+    check_payload(&payload, len)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
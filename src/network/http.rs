@@ -0,0 +1,434 @@
+//! HTTP/JSON gateway, for services which can't use the rust `Client`.
+//!
+//! Routes:
+//!   - `GET /keys/{key}` returns `{"key": KEY, "value": VALUE}`, 404 if the key doesn't exist.
+//!   - `PUT /keys/{key}` sets the key by body `{"value": VALUE, "ttl": SECONDS}`, `ttl` is
+//!     optional.  If `expected` is given, like `{"value": VALUE, "expected": OLD}`, it's a
+//!     compare-and-swap, a `null` expected value means the key must not exist, and 409 is
+//!     returned if the current value doesn't match.
+//!   - `DELETE /keys/{key}` removes the key, 404 if the key doesn't exist.  It's a
+//!     compare-and-swap as well if the body is `{"expected": OLD}`.
+//!   - `GET /keys?prefix=PREFIX&after=KEY&limit=N` returns key/value pairs whose keys start with
+//!     the prefix, in key order.  All parameters are optional, at most `limit` pairs (1000 by
+//!     default and at most) whose keys are greater than `after` are returned, so the next page
+//!     is requested with the last key as `after` until less than `limit` pairs are returned.
+//!
+//! Keys in the path and query are percent-encoded, a `+` in the query is a space as well.  Keys
+//! and values are JSON strings, bytes which are not valid UTF-8 are replaced when they are
+//! returned.
+use super::stream::Stream;
+use crate::engine::KvsEngine;
+use crate::error::{ErrorCode, Result};
+use log::debug;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::ops::Bound;
+use std::time::Duration;
+
+const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_LIST_LIMIT: usize = 1000;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct HttpResponse {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: serde_json::to_vec(body).expect("Serialize response failed"),
+        }
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            body: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(
+            status,
+            &ErrorBody {
+                error: message.to_owned(),
+            },
+        )
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.body.len()
+        )?;
+        if !self.body.is_empty() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        if !keep_alive {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+    #[serde(default, deserialize_with = "present")]
+    expected: Option<Option<String>>,
+    #[serde(default)]
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct DeleteBody {
+    expected: String,
+}
+
+// Tell a `null` field from a missing one, a missing field is `None`.
+fn present<'de, D>(deserializer: D) -> std::result::Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer).map(Some)
+}
+
+/// Handle requests of a HTTP client in order, until the connection is closed.
+pub fn handle_client(client_stream: Stream, engine: &impl KvsEngine) -> Result<()> {
+    let peer_addr = client_stream.tcp().peer_addr()?;
+    debug!("Waiting HTTP requests from {}", peer_addr);
+
+    let mut reader: BufReader<Stream> = BufReader::new(client_stream.try_clone()?);
+    let mut writer: BufWriter<Stream> = BufWriter::new(client_stream);
+    loop {
+        let request: Request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                debug!(
+                    "Connection closed by peer {}, so this connection is closed.",
+                    peer_addr
+                );
+                return Ok(());
+            }
+            // the stream can't be parsed any more, report it and close the connection.
+            Err((status, message)) => {
+                HttpResponse::error(status, &message).write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+        debug!("Peer: {}, {} {}", peer_addr, request.method, request.path);
+        let response: HttpResponse = route(&request, engine);
+        response.write_to(&mut writer, request.keep_alive)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+type RequestError = (u16, String);
+
+/// Read a request, returns `None` if the client closes the connection between requests.
+fn read_request<R: BufRead>(reader: &mut R) -> std::result::Result<Option<Request>, RequestError> {
+    let bad_request = |message: &str| (400, message.to_owned());
+    let request_line: String = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let (method, target, version) = match parts.as_slice() {
+        [method, target, version] if version.starts_with("HTTP/1.") => (*method, *target, *version),
+        _ => return Err(bad_request("Invalid request line")),
+    };
+
+    let mut keep_alive: bool = version != "HTTP/1.0";
+    let mut content_length: usize = 0;
+    let mut header_count: usize = 0;
+    loop {
+        let line: String = read_line(reader)?.ok_or_else(|| bad_request("Unexpected end"))?;
+        if line.is_empty() {
+            break;
+        }
+        header_count += 1;
+        if header_count > MAX_HEADERS {
+            return Err(bad_request("Too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("Invalid header"))?;
+        let value: &str = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse::<usize>()
+                    .map_err(|_| bad_request("Invalid content length"))?;
+            }
+            "transfer-encoding" => {
+                return Err((501, String::from("Transfer encoding is not supported")));
+            }
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return Err((413, String::from("Body is too large")));
+    }
+    // the body is allocated as it arrives, not by the length which the client claims.
+    let mut body: Vec<u8> = Vec::new();
+    reader
+        .take(content_length as u64)
+        .read_to_end(&mut body)
+        .map_err(|_| bad_request("Unexpected end"))?;
+    if body.len() < content_length {
+        return Err(bad_request("Unexpected end"));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    let query: Vec<(Vec<u8>, Vec<u8>)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((query_decode(name)?, query_decode(value)?))
+        })
+        .collect::<std::result::Result<_, RequestError>>()?;
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        body,
+        keep_alive,
+    }))
+}
+
+/// Read a line ending with "\r\n" or "\n", the ending is not included.  Lines are at most
+/// `MAX_LINE_LEN` bytes.
+fn read_line<R: BufRead>(reader: &mut R) -> std::result::Result<Option<String>, RequestError> {
+    let mut line: Vec<u8> = Vec::new();
+    let limit: u64 = MAX_LINE_LEN as u64 + 2;
+    match reader.take(limit).read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(e) => return Err((400, e.to_string())),
+    }
+    if line.len() as u64 == limit && !line.ends_with(b"\n") {
+        return Err((431, String::from("Request line or header is too long")));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err((400, String::from("Unexpected end")));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| (400, String::from("Invalid UTF-8 in request head")))
+}
+
+/// Decode a query parameter, `+` is a space only in queries.
+fn query_decode(input: &str) -> std::result::Result<Vec<u8>, RequestError> {
+    percent_decode(&input.replace('+', " "))
+}
+
+fn percent_decode(input: &str) -> std::result::Result<Vec<u8>, RequestError> {
+    let bytes: &[u8] = input.as_bytes();
+    let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte: u8 = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| (400, format!("Invalid percent-encoding {}", input)))?;
+                output.push(byte);
+                i += 3;
+            }
+            byte => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn route(request: &Request, engine: &impl KvsEngine) -> HttpResponse {
+    let result: Result<HttpResponse> = if request.path == "/keys" {
+        match request.method.as_str() {
+            "GET" => list(request, engine),
+            _ => Ok(HttpResponse::error(405, "Method not allowed")),
+        }
+    } else if let Some(key) = request.path.strip_prefix("/keys/") {
+        match percent_decode(key) {
+            Ok(key) => match request.method.as_str() {
+                "GET" => get(key, engine),
+                "PUT" => put(key, &request.body, engine),
+                "DELETE" => delete(key, &request.body, engine),
+                _ => Ok(HttpResponse::error(405, "Method not allowed")),
+            },
+            Err((status, message)) => Ok(HttpResponse::error(status, &message)),
+        }
+    } else {
+        Ok(HttpResponse::error(404, "Not found"))
+    };
+    result.unwrap_or_else(|e| HttpResponse::error(status_of(e.code()), &e.to_string()))
+}
+
+fn status_of(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::NotFound => 404,
+        ErrorCode::InvalidRequest => 400,
+        ErrorCode::Conflict => 409,
+        ErrorCode::Unauthorized => 401,
+        ErrorCode::NotLeader => 503,
+        ErrorCode::StorageFailure | ErrorCode::Internal => 500,
+    }
+}
+
+fn key_value(key: &[u8], value: &[u8]) -> KeyValue {
+    KeyValue {
+        key: String::from_utf8_lossy(key).into_owned(),
+        value: String::from_utf8_lossy(value).into_owned(),
+    }
+}
+
+fn get(key: Vec<u8>, engine: &impl KvsEngine) -> Result<HttpResponse> {
+    match engine.get_bytes(&key) {
+        Ok(Some(value)) => Ok(HttpResponse::json(200, &key_value(&key, &value))),
+        Ok(None) => Ok(HttpResponse::error(404, "Key not found")),
+        Err(ref e) if e.is_key_not_found() => Ok(HttpResponse::error(404, "Key not found")),
+        Err(e) => Err(e),
+    }
+}
+
+fn put(key: Vec<u8>, body: &[u8], engine: &impl KvsEngine) -> Result<HttpResponse> {
+    let body: PutBody = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => return Ok(HttpResponse::error(400, &e.to_string())),
+    };
+    let value: Vec<u8> = body.value.into_bytes();
+    match (body.expected, body.ttl) {
+        (None, None) => engine.set_bytes(key, value)?,
+        (None, Some(ttl)) => engine.set_bytes_with_ttl(key, value, Duration::from_secs(ttl))?,
+        (Some(_), Some(_)) => {
+            return Ok(HttpResponse::error(
+                400,
+                "ttl is not supported with expected value",
+            ))
+        }
+        (Some(expected), None) => {
+            let expected: Option<Vec<u8>> = expected.map(String::into_bytes);
+            if !engine.compare_and_swap_bytes(key, expected, Some(value))? {
+                return Ok(HttpResponse::error(409, "Value is changed"));
+            }
+        }
+    }
+    Ok(HttpResponse::no_content())
+}
+
+fn delete(key: Vec<u8>, body: &[u8], engine: &impl KvsEngine) -> Result<HttpResponse> {
+    if body.is_empty() {
+        return match engine.remove_bytes(&key) {
+            Ok(()) => Ok(HttpResponse::no_content()),
+            Err(ref e) if e.is_key_not_found() => Ok(HttpResponse::error(404, "Key not found")),
+            Err(e) => Err(e),
+        };
+    }
+    let body: DeleteBody = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => return Ok(HttpResponse::error(400, &e.to_string())),
+    };
+    if engine.compare_and_swap_bytes(key, Some(body.expected.into_bytes()), None)? {
+        Ok(HttpResponse::no_content())
+    } else {
+        Ok(HttpResponse::error(409, "Value is changed"))
+    }
+}
+
+fn list(request: &Request, engine: &impl KvsEngine) -> Result<HttpResponse> {
+    let param = |param: &[u8]| {
+        request
+            .query
+            .iter()
+            .find(|(name, _)| name == param)
+            .map(|(_, value)| value.as_slice())
+    };
+    let prefix: &[u8] = param(b"prefix").unwrap_or(&[]);
+    let limit: usize = match param(b"limit") {
+        Some(limit) => match std::str::from_utf8(limit).ok().and_then(|l| l.parse().ok()) {
+            Some(limit) if limit <= MAX_LIST_LIMIT => limit,
+            _ => return Ok(HttpResponse::error(400, "Invalid limit")),
+        },
+        None => MAX_LIST_LIMIT,
+    };
+    let start: Bound<Vec<u8>> = match param(b"after") {
+        Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+        _ => Bound::Included(prefix.to_vec()),
+    };
+    let mut pairs: Vec<KeyValue> = Vec::new();
+    let iter = engine.scan_bytes((start, Bound::Unbounded))?;
+    for pair in iter.take(limit) {
+        let (key, value) = pair?;
+        if !key.starts_with(prefix) {
+            break;
+        }
+        pairs.push(key_value(&key, &value));
+    }
+    Ok(HttpResponse::json(200, &pairs))
+}
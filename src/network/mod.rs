@@ -1,5 +1,36 @@
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+pub(crate) mod auth;
 pub mod client;
-pub mod server;
+mod cluster;
+mod frame;
+mod http;
+mod pool;
+mod raft;
+mod replication;
+mod resp;
 pub mod response;
+pub mod server;
+mod sharded;
+mod shutdown;
+mod stream;
+mod tls;
+mod watch;
 
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncServer;
+pub use auth::AuthConfig;
+pub use client::{Client, ClientConfig};
+pub use cluster::ClusterClient;
+pub use pool::ClientPool;
+pub use raft::{ClusterHandle, RaftConfig};
+pub use replication::ReplicaStatus;
 pub use response::Response;
+pub use sharded::ShardedClient;
+pub use shutdown::ShutdownHandle;
+pub use tls::{ClientTls, ServerTls};
+pub use watch::{WatchEvent, Watcher};
@@ -0,0 +1,103 @@
+use super::client::{Client, ClientConfig, Requester};
+use crate::command::Instruction;
+use crate::error::{KvsError, Repr, Result};
+use log::debug;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Pool of reusable connections to a kvs server, it implements `KvsEngine` like `Client`.
+///
+/// Each request takes an idle connection, or opens a new one, and gives it back once the
+/// response is read.  Connections closed by the server are dropped before they are reused, and
+/// connections which fail in a request are never reused.  Idempotent requests, `get` and scans,
+/// are retried with backoff on connection errors, other requests are not, because they may
+/// have been applied by the server.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: String,
+    config: ClientConfig,
+    idle: Mutex<Vec<Client>>,
+}
+
+impl ClientPool {
+    /// Create a pool of the server, connections are opened on demand.
+    pub fn new(addr: &str, config: ClientConfig) -> ClientPool {
+        ClientPool {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_owned(),
+                config,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// How many idle connections are kept now.
+    pub fn idle_connections(&self) -> usize {
+        self.lock_idle().len()
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<Client>> {
+        self.inner
+            .idle
+            .lock()
+            .expect("Lock idle connections failed.")
+    }
+
+    fn request_once(&self, inst: &Instruction) -> Result<Vec<u8>> {
+        let client: Client = self.checkout()?;
+        let result: Result<Vec<u8>> = client.request(inst);
+        match result {
+            Err(ref e) if is_broken(e) => {}
+            _ => self.checkin(client),
+        }
+        result
+    }
+
+    /// Take an idle connection which is still alive, or open a new one.
+    fn checkout(&self) -> Result<Client> {
+        loop {
+            let client: Option<Client> = self.lock_idle().pop();
+            match client {
+                Some(client) if client.is_alive() => return Ok(client),
+                Some(_) => debug!("Idle connection is closed by server, drop it"),
+                None => return Client::connect_with(&self.inner.addr, &self.inner.config),
+            }
+        }
+    }
+
+    fn checkin(&self, client: Client) {
+        let mut idle: MutexGuard<Vec<Client>> = self.lock_idle();
+        if idle.len() < self.inner.config.max_idle {
+            idle.push(client);
+        }
+    }
+}
+
+// The connection is in an unknown state after IO errors, like timeouts, or broken frames.
+pub(crate) fn is_broken(e: &KvsError) -> bool {
+    matches!(e.repr(), Repr::IOError(_) | Repr::JsonError(_))
+}
+
+impl Requester for ClientPool {
+    fn request(&self, inst: &Instruction) -> Result<Vec<u8>> {
+        let idempotent: bool = matches!(inst, Instruction::Get { .. } | Instruction::Scan { .. });
+        let mut backoff: Duration = self.inner.config.backoff;
+        let mut retries: u32 = 0;
+        loop {
+            match self.request_once(inst) {
+                Err(ref e) if idempotent && is_broken(e) && retries < self.inner.config.retries => {
+                    debug!("Request failed, retry after {:?}, reason: {:?}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
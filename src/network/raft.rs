@@ -0,0 +1,1599 @@
+//! Raft based replicated cluster.
+//!
+//! Every node of a cluster has a Raft log of write instructions, the leader is elected by the
+//! nodes, it appends writes of clients to the log and replicates them to others.  Once an entry
+//! is on a majority of nodes, it's committed and applied to the engine of every node, then the
+//! leader answers the client.  Other nodes redirect clients to the leader by
+//! `KvsError::not_leader`, for reads as well.
+//!
+//! The log and the current term are kept in files of `RaftConfig::dir`.  Once
+//! `RaftConfig::snapshot_entries` entries are applied after the last snapshot, the engine is
+//! flushed and it becomes the new snapshot: the index of the last applied entry is persisted, and
+//! entries up to it are dropped from the log.  A node keeps its engine when it starts, entries
+//! after the snapshot are applied again, like records after a snapshot of a replica.  Files are
+//! written with the state unlocked, replies to peers wait until what they say is synced.
+//!
+//! A follower which lags behind the snapshot of the leader receives a snapshot instead of
+//! entries.  Pairs are copied from the engine before they are sent, entries are not applied
+//! meanwhile, so they are exactly the engine at the index of the snapshot, and entries after it
+//! are applied to them as they are on the leader.  Keys which are not in the snapshot are removed
+//! at last.  Until it's received, the node doesn't apply entries or start elections, even
+//! after a restart.  Nodes talk to each other by `Instruction::Raft` over the kvs protocol, so
+//! peers log in and use TLS like other clients.
+//!
+//! Reads are served by the leader from its engine, a leader which is partitioned from the
+//! majority steps down once it hears nothing from them for an election timeout, it may serve
+//! stale reads before that.
+use super::client::{Client, ClientConfig, Requester};
+use super::frame::{read_frame, write_frame};
+use super::replication::{apply_and_publish, apply_write, ignore_not_found, SnapshotPair};
+use super::watch::WatchHub;
+use crate::command::{expire_at, Instruction, LogEntry, RaftMessage};
+use crate::engine::{BytesScanIter, KvsEngine};
+use crate::error::{KvsError, Result};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+// how many entries are sent by an append message, and how many pairs by a snapshot chunk.
+const MAX_APPEND_ENTRIES: usize = 256;
+const SNAPSHOT_CHUNK_SIZE: usize = 256;
+// how many bytes of entries or pairs are sent by a message, bytes are a few times larger in the
+// JSON frame, which is still far below `MAX_FRAME_LEN`.
+const MAX_APPEND_BYTES: u64 = 1024 * 1024;
+// how often the election timer is checked.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+static STATE_FILE_NAME: &str = "state.json";
+static LOG_FILE_NAME: &str = "log";
+
+/// Settings of a node of a Raft cluster.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Addresses of all nodes, in the same order on every node.  Peers are connected by them,
+    /// and clients are redirected to them.
+    pub nodes: Vec<String>,
+    /// Index of this node in `nodes`.
+    pub id: usize,
+    /// Directory of the Raft log and the current term.
+    pub dir: PathBuf,
+    /// A follower starts an election if it hears nothing from the leader for a random time
+    /// between it and twice of it.
+    pub election_timeout: Duration,
+    /// How often the leader sends heartbeats, it should be much less than `election_timeout`.
+    pub heartbeat_interval: Duration,
+    /// The log is compacted once this many entries are applied after the last snapshot.
+    pub snapshot_entries: u64,
+    /// How peers are connected, with timeouts, TLS settings and credentials.
+    pub client: ClientConfig,
+}
+
+impl RaftConfig {
+    /// Config of the node `id` in `nodes`, the log is kept in `dir`.
+    pub fn new(nodes: Vec<String>, id: usize, dir: &Path) -> RaftConfig {
+        let election_timeout: Duration = Duration::from_millis(300);
+        RaftConfig {
+            nodes,
+            id,
+            dir: dir.to_path_buf(),
+            election_timeout,
+            heartbeat_interval: Duration::from_millis(50),
+            snapshot_entries: 10_000,
+            client: ClientConfig {
+                connect_timeout: Some(election_timeout),
+                read_timeout: Some(election_timeout),
+                write_timeout: Some(election_timeout),
+                ..ClientConfig::default()
+            },
+        }
+    }
+}
+
+/// Reply of a `RaftMessage`.
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftReply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// `last_index` is the last entry which matches the leader if it succeeds, otherwise where
+    /// the leader should retry from.  A follower which needs a snapshot rejects entries from the
+    /// first one.
+    Append {
+        term: u64,
+        success: bool,
+        last_index: u64,
+    },
+    /// `done` if the snapshot is installed, or the follower already has its entries.
+    Snapshot {
+        term: u64,
+        success: bool,
+        done: bool,
+    },
+}
+
+/// Current term and vote, and the snapshot which the log starts after.  They are persisted
+/// before any reply depends on them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<usize>,
+    // entries up to the index are applied to the engine and dropped from the log.
+    #[serde(default)]
+    snapshot_index: u64,
+    #[serde(default)]
+    snapshot_term: u64,
+    // the engine has part of the snapshot, it's received again.
+    #[serde(default)]
+    installing: bool,
+}
+
+/// Files of the Raft log and the hard state.
+struct RaftStorage {
+    dir: PathBuf,
+    // index of the first entry in the log file.
+    first_index: u64,
+    log_writer: BufWriter<File>,
+}
+
+impl RaftStorage {
+    /// Open the storage in the directory, returns the persisted state and log.  A torn tail of
+    /// the log is dropped.
+    fn open(dir: &Path) -> Result<(RaftStorage, HardState, Vec<LogEntry>)> {
+        fs::create_dir_all(dir)?;
+        let hard_state: HardState = match File::open(dir.join(STATE_FILE_NAME)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(KvsError::from(e)),
+        };
+        let first_index: u64 = hard_state.snapshot_index + 1;
+        remove_stale_logs(dir, first_index)?;
+        let mut entries: Vec<LogEntry> = Vec::new();
+        let mut torn: bool = false;
+        if let Ok(file) = File::open(log_path(dir, first_index)) {
+            let mut reader: BufReader<File> = BufReader::new(file);
+            loop {
+                match read_frame(&mut reader) {
+                    Ok(Some(entry)) => entries.push(entry),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Raft log is torn, reason: {:?}", e);
+                        torn = true;
+                        break;
+                    }
+                }
+            }
+        }
+        let mut storage: RaftStorage = RaftStorage {
+            dir: dir.to_path_buf(),
+            first_index,
+            log_writer: open_log(&log_path(dir, first_index))?,
+        };
+        if torn {
+            storage.rewrite(first_index, &entries)?;
+        }
+        Ok((storage, hard_state, entries))
+    }
+
+    /// Write the changes, the state file is replaced after the log file, so it never refers to
+    /// a log file which is not written.
+    fn write(&mut self, changes: &Changes) -> Result<()> {
+        let old_path: PathBuf = log_path(&self.dir, self.first_index);
+        match changes.rewrite_from {
+            Some(first_index) => self.rewrite(first_index, &changes.entries)?,
+            None => self.append(&changes.entries)?,
+        }
+        if let Some(hard_state) = &changes.hard_state {
+            self.save_state(hard_state)?;
+        }
+        if old_path != log_path(&self.dir, self.first_index) {
+            match fs::remove_file(&old_path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the hard state file atomically.
+    fn save_state(&self, hard_state: &HardState) -> Result<()> {
+        let tmp_path: PathBuf = self.dir.join(format!("{}.tmp", STATE_FILE_NAME));
+        let mut file: File = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, hard_state)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(STATE_FILE_NAME))?;
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in entries {
+            write_frame(&mut self.log_writer, entry)?;
+        }
+        self.log_writer.flush()?;
+        self.log_writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the log with the entries from the index, it's done when conflicting entries are
+    /// dropped or the log is compacted.
+    fn rewrite(&mut self, first_index: u64, entries: &[LogEntry]) -> Result<()> {
+        let path: PathBuf = log_path(&self.dir, first_index);
+        let tmp_path: PathBuf = path.with_extension("tmp");
+        let mut writer: BufWriter<File> = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            write_frame(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        self.log_writer = open_log(&path)?;
+        self.first_index = first_index;
+        Ok(())
+    }
+}
+
+// The log file which starts from the index, the log of an uncompacted node keeps its old name.
+fn log_path(dir: &Path, first_index: u64) -> PathBuf {
+    match first_index {
+        1 => dir.join(LOG_FILE_NAME),
+        _ => dir.join(format!("{}-{}", LOG_FILE_NAME, first_index)),
+    }
+}
+
+// Remove log files which are left by a crash while the log is replaced.
+fn remove_stale_logs(dir: &Path, first_index: u64) -> Result<()> {
+    let current: PathBuf = log_path(dir, first_index);
+    for entry in fs::read_dir(dir)? {
+        let path: PathBuf = entry?.path();
+        let is_log: bool = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(LOG_FILE_NAME));
+        if is_log && path != current {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn open_log(path: &Path) -> Result<BufWriter<File>> {
+    let file: File = OpenOptions::new().append(true).create(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+/// Changes of the hard state and the log which are not synced to files yet.
+struct Changes {
+    hard_state: Option<HardState>,
+    version: u64,
+    // the log file is rewritten from the index, otherwise entries are appended to it.
+    rewrite_from: Option<u64>,
+    entries: Vec<LogEntry>,
+    last_index: u64,
+    truncations: u64,
+}
+
+/// The engine which committed entries are applied to.
+trait StateMachine: Send {
+    /// Apply an instruction, returns false if it's a compare-and-swap which doesn't match.
+    fn apply(&self, instruction: Instruction) -> Result<bool>;
+
+    /// Pairs after the key in key order, with their expire times.
+    fn snapshot_chunk(&self, after: Option<&[u8]>) -> Result<Vec<SnapshotPair>>;
+
+    /// Set pairs of a snapshot.
+    fn restore(&self, pairs: &[SnapshotPair]) -> Result<()>;
+
+    /// Remove keys which are not in the snapshot.
+    fn retain(&self, keys: &HashSet<Vec<u8>>) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+}
+
+struct EngineMachine<E: KvsEngine> {
+    engine: E,
+    hub: WatchHub,
+}
+
+impl<E: KvsEngine> StateMachine for EngineMachine<E> {
+    fn apply(&self, instruction: Instruction) -> Result<bool> {
+        apply_and_publish(&self.engine, &self.hub, instruction).map(|(swapped, _)| swapped)
+    }
+
+    fn snapshot_chunk(&self, after: Option<&[u8]>) -> Result<Vec<SnapshotPair>> {
+        let start: Bound<Vec<u8>> = match after {
+            Some(key) => Bound::Excluded(key.to_vec()),
+            None => Bound::Unbounded,
+        };
+        let pairs: BytesScanIter = self.engine.scan_bytes((start, Bound::Unbounded))?;
+        let mut chunk: Vec<SnapshotPair> = Vec::new();
+        let mut bytes: u64 = 0;
+        for pair in pairs {
+            let (key, value) = pair?;
+            bytes += (key.len() + value.len()) as u64;
+            let expire: Option<u64> = self.engine.ttl_bytes(&key)?.map(expire_at);
+            chunk.push((key, value, expire));
+            if chunk.len() >= SNAPSHOT_CHUNK_SIZE || bytes >= MAX_APPEND_BYTES {
+                break;
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn restore(&self, pairs: &[SnapshotPair]) -> Result<()> {
+        for (key, value, expire_at) in pairs {
+            // pairs which are not changed are sent again after a restart.
+            let changed: bool = self.engine.get_bytes(key)?.as_ref() != Some(value);
+            let set: Instruction = Instruction::Set {
+                key: key.clone(),
+                value: value.clone(),
+                expire_at: *expire_at,
+            };
+            apply_write(&self.engine, set.clone())?;
+            if changed {
+                self.hub.publish(&set);
+            }
+        }
+        Ok(())
+    }
+
+    fn retain(&self, keys: &HashSet<Vec<u8>>) -> Result<()> {
+        let stale: Vec<Vec<u8>> = self
+            .engine
+            .scan_bytes(..)?
+            .filter(|pair| match pair {
+                Ok((key, _)) => !keys.contains(key),
+                Err(_) => true,
+            })
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        for key in stale {
+            ignore_not_found(self.engine.remove_bytes(&key))?;
+            self.hub.publish(&Instruction::Rm { key });
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+}
+
+/// The engine, with the last entry which is applied to it, or the snapshot which is restored to
+/// it.
+struct AppliedEngine {
+    machine: Box<dyn StateMachine>,
+    index: u64,
+    term: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A snapshot which is being received.
+struct Receiving {
+    last_index: u64,
+    // how many pairs are received.
+    offset: u64,
+    keys: HashSet<Vec<u8>>,
+}
+
+struct RaftState {
+    role: RaftRole,
+    hard_state: HardState,
+    // entries after the snapshot, entry of index `i` is at `log[i - snapshot_index - 1]`,
+    // indexes start from 1.
+    log: Vec<LogEntry>,
+    commit_index: u64,
+    last_applied: u64,
+    leader: Option<usize>,
+    election_deadline: Instant,
+    votes: usize,
+    // term which the vote of each peer is requested for, it's only used by a candidate.
+    vote_requested: Vec<u64>,
+    // states of peers, they are only used by the leader.
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+    last_ack: Vec<Instant>,
+    // index of the no-op entry of the leader, reads wait until it's applied.
+    term_start: u64,
+    // results of entries proposed by this node, they are filled once the entries are applied.
+    pending: HashMap<u64, Option<Result<bool>>>,
+    receiving: Option<Receiving>,
+    // the hard state is changed `version` times, the first `synced_version` ones are synced.
+    version: u64,
+    synced_version: u64,
+    // entries up to the index are synced to the log file.
+    synced_index: u64,
+    // index of the first entry in the log file.
+    file_index: u64,
+    // the log file has entries which are dropped from the log, so it's rewritten.
+    stale_file: bool,
+    // how many times entries are dropped from the end of the log.
+    truncations: u64,
+    stopped: bool,
+}
+
+impl RaftState {
+    fn term(&self) -> u64 {
+        self.hard_state.term
+    }
+
+    fn snapshot_index(&self) -> u64 {
+        self.hard_state.snapshot_index
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index() + self.log.len() as u64
+    }
+
+    /// Term of the entry, entries up to the snapshot are taken as the last one of it.
+    fn term_at(&self, index: u64) -> u64 {
+        if index <= self.snapshot_index() {
+            return self.hard_state.snapshot_term;
+        }
+        self.log
+            .get((index - self.snapshot_index()) as usize - 1)
+            .map_or(0, |entry| entry.term)
+    }
+
+    fn entries_from(&self, index: u64) -> &[LogEntry] {
+        &self.log[(index - self.snapshot_index()) as usize - 1..]
+    }
+
+    fn set_hard_state(&mut self, term: u64, voted_for: Option<usize>) {
+        self.hard_state.term = term;
+        self.hard_state.voted_for = voted_for;
+        self.version += 1;
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) {
+        self.log.extend_from_slice(entries);
+    }
+
+    /// Drop entries from the index, they conflict with the leader.
+    fn truncate(&mut self, index: u64) {
+        self.log
+            .truncate((index - self.snapshot_index()) as usize - 1);
+        if index <= self.synced_index {
+            self.synced_index = index - 1;
+            self.stale_file = true;
+        }
+        self.truncations += 1;
+        for (_, result) in self.pending.iter_mut().filter(|(&i, _)| i >= index) {
+            result.get_or_insert_with(|| Err(lost_leadership()));
+        }
+    }
+
+    /// Drop entries up to the index, they are applied to the engine which is flushed.
+    fn compact(&mut self, index: u64) {
+        if index <= self.snapshot_index() || index > self.last_applied {
+            return;
+        }
+        self.hard_state.snapshot_term = self.term_at(index);
+        self.log.drain(..(index - self.snapshot_index()) as usize);
+        self.hard_state.snapshot_index = index;
+        self.version += 1;
+    }
+
+    /// Replace the log by the snapshot of the leader, its pairs are received next.
+    fn start_install(&mut self, last_index: u64, last_term: u64) {
+        self.log.clear();
+        self.synced_index = self.synced_index.min(last_index);
+        self.stale_file = true;
+        self.truncations += 1;
+        self.fail_pending();
+        self.hard_state.snapshot_index = last_index;
+        self.hard_state.snapshot_term = last_term;
+        self.hard_state.installing = true;
+        self.version += 1;
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        self.receiving = Some(Receiving {
+            last_index,
+            offset: 0,
+            keys: HashSet::new(),
+        });
+    }
+
+    /// Take results of entries after the last applied one, results of proposed ones are kept.
+    fn applied(&mut self, results: Vec<Result<bool>>) {
+        for result in results {
+            self.last_applied += 1;
+            let index: u64 = self.last_applied;
+            match self.pending.get_mut(&index) {
+                Some(pending) => *pending = Some(result),
+                None => {
+                    if let Err(e) = result {
+                        debug!("Apply entry {} failed, reason: {:?}", index, e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fail proposals which are not applied yet, they may still be committed by a new leader.
+    fn fail_pending(&mut self) {
+        for result in self.pending.values_mut() {
+            result.get_or_insert_with(|| Err(lost_leadership()));
+        }
+    }
+
+    fn is_synced(&self) -> bool {
+        self.synced_version == self.version
+            && self.synced_index >= self.last_index()
+            && !self.stale_file
+            && self.file_index == self.snapshot_index() + 1
+    }
+
+    fn changes(&self) -> Option<Changes> {
+        if self.is_synced() {
+            return None;
+        }
+        let first_index: u64 = self.snapshot_index() + 1;
+        let rewrite: bool = self.stale_file || self.file_index != first_index;
+        let synced: u64 = match rewrite {
+            true => 0,
+            false => self.synced_index.saturating_sub(self.snapshot_index()),
+        };
+        Some(Changes {
+            hard_state: Some(self.hard_state.clone())
+                .filter(|_| rewrite || self.synced_version < self.version),
+            version: self.version,
+            rewrite_from: Some(first_index).filter(|_| rewrite),
+            entries: self.log[synced as usize..].to_vec(),
+            last_index: self.last_index(),
+            truncations: self.truncations,
+        })
+    }
+
+    fn synced(&mut self, changes: &Changes) {
+        self.synced_version = self.synced_version.max(changes.version);
+        if let Some(first_index) = changes.rewrite_from {
+            self.file_index = first_index;
+        }
+        if changes.truncations == self.truncations {
+            self.synced_index = changes.last_index;
+            if changes.rewrite_from.is_some() {
+                self.stale_file = false;
+            }
+        } else {
+            // entries may be dropped while they are written.
+            self.stale_file = true;
+        }
+    }
+}
+
+fn lost_leadership() -> KvsError {
+    KvsError::from_string("Leadership is lost, the write may or may not be applied")
+}
+
+/// A node of a Raft cluster.
+pub(crate) struct RaftNode {
+    id: usize,
+    nodes: Vec<String>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    snapshot_entries: u64,
+    client: ClientConfig,
+    state: Mutex<RaftState>,
+    // notified when anything of the state is changed.
+    changed: Condvar,
+    // files are written by one thread at a time, with the state unlocked.
+    storage: Mutex<RaftStorage>,
+    // the engine, committed entries are applied to it, and it's scanned and flushed, with the
+    // state unlocked.
+    engine: Mutex<AppliedEngine>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl RaftNode {
+    /// Open the node, entries after the snapshot of the engine are applied again once they are
+    /// committed.  Applied writes are published to the hub.
+    pub(crate) fn open<E: KvsEngine>(
+        config: RaftConfig,
+        engine: E,
+        hub: WatchHub,
+    ) -> Result<Arc<RaftNode>> {
+        if config.id >= config.nodes.len() {
+            return Err(KvsError::invalid_request("Node id is out of the cluster"));
+        }
+        let (storage, hard_state, log) = RaftStorage::open(&config.dir)?;
+        info!(
+            "Raft node {} is opened at term {} with {} entries after index {}",
+            config.id,
+            hard_state.term,
+            log.len(),
+            hard_state.snapshot_index
+        );
+        if hard_state.installing {
+            warn!(
+                "Raft node {} has part of a snapshot, it's received again",
+                config.id
+            );
+        }
+
+        let size: usize = config.nodes.len();
+        let snapshot_index: u64 = hard_state.snapshot_index;
+        let snapshot_term: u64 = hard_state.snapshot_term;
+        let synced_index: u64 = snapshot_index + log.len() as u64;
+        let state: RaftState = RaftState {
+            role: RaftRole::Follower,
+            hard_state,
+            log,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            leader: None,
+            election_deadline: Instant::now() + random_timeout(config.election_timeout),
+            votes: 0,
+            vote_requested: vec![0; size],
+            next_index: vec![1; size],
+            match_index: vec![0; size],
+            last_ack: vec![Instant::now(); size],
+            term_start: 0,
+            pending: HashMap::new(),
+            receiving: None,
+            version: 0,
+            synced_version: 0,
+            synced_index,
+            file_index: storage.first_index,
+            stale_file: false,
+            truncations: 0,
+            stopped: false,
+        };
+        Ok(Arc::new(RaftNode {
+            id: config.id,
+            nodes: config.nodes,
+            election_timeout: config.election_timeout,
+            heartbeat_interval: config.heartbeat_interval,
+            snapshot_entries: config.snapshot_entries.max(1),
+            client: config.client,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            storage: Mutex::new(storage),
+            engine: Mutex::new(AppliedEngine {
+                machine: Box::new(EngineMachine { engine, hub }),
+                index: snapshot_index,
+                term: snapshot_term,
+            }),
+            threads: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RaftState> {
+        self.state.lock().expect("Lock Raft state failed.")
+    }
+
+    fn lock_engine(&self) -> MutexGuard<'_, AppliedEngine> {
+        self.engine.lock().expect("Lock Raft engine failed.")
+    }
+
+    fn wait_timeout<'a>(
+        &self,
+        state: MutexGuard<'a, RaftState>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, RaftState> {
+        self.changed
+            .wait_timeout(state, timeout)
+            .expect("Lock Raft state failed.")
+            .0
+    }
+
+    fn peers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |&peer| peer != self.id)
+    }
+
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    /// Start the election timer, the applier of committed entries, the compactor of the log,
+    /// and a thread for each peer which requests its vote when this node is a candidate, and
+    /// replicates the log to it when this node is the leader.
+    pub(crate) fn start(self: &Arc<Self>) {
+        let mut threads: MutexGuard<Vec<JoinHandle<()>>> =
+            self.threads.lock().expect("Lock Raft threads failed.");
+        let node: Arc<RaftNode> = self.clone();
+        threads.push(thread::spawn(move || node.run_timer()));
+        let node: Arc<RaftNode> = self.clone();
+        threads.push(thread::spawn(move || node.run_applier()));
+        let node: Arc<RaftNode> = self.clone();
+        threads.push(thread::spawn(move || node.run_compactor()));
+        for peer in self.peers() {
+            let node: Arc<RaftNode> = self.clone();
+            threads.push(thread::spawn(move || node.run_peer(peer)));
+        }
+    }
+
+    /// Stop the node, proposals which are not applied fail.  Applied entries are dropped from
+    /// the log, so they are not applied again when the node starts.
+    pub(crate) fn stop(&self) {
+        {
+            let mut state: MutexGuard<RaftState> = self.lock();
+            state.stopped = true;
+            state.fail_pending();
+        }
+        self.changed.notify_all();
+        let threads: Vec<JoinHandle<()>> = self
+            .threads
+            .lock()
+            .expect("Lock Raft threads failed.")
+            .drain(..)
+            .collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("Raft thread panicked");
+            }
+        }
+        if let Err(e) = self.compact() {
+            error!("Compact Raft log failed, reason: {:?}", e);
+        }
+    }
+
+    /// Sync changes of the hard state and the log to files.  The state is unlocked while they
+    /// are written, so it's returned locked again.
+    fn sync<'a>(&'a self, state: MutexGuard<'a, RaftState>) -> Result<MutexGuard<'a, RaftState>> {
+        if state.is_synced() {
+            return Ok(state);
+        }
+        drop(state);
+        let mut storage: MutexGuard<RaftStorage> =
+            self.storage.lock().expect("Lock Raft storage failed.");
+        let state: MutexGuard<RaftState> = self.lock();
+        // changes may be synced by another thread in the meantime.
+        let changes: Changes = match state.changes() {
+            Some(changes) => changes,
+            None => return Ok(state),
+        };
+        drop(state);
+        let result: Result<()> = storage.write(&changes);
+        let mut state: MutexGuard<RaftState> = self.lock();
+        result?;
+        state.synced(&changes);
+        if state.role == RaftRole::Leader {
+            self.advance_commit(&mut state);
+        }
+        self.changed.notify_all();
+        Ok(state)
+    }
+
+    /// Flush the engine, then drop applied entries from the log.
+    fn compact(&self) -> Result<()> {
+        let index: u64 = self.lock().last_applied;
+        self.lock_engine().machine.flush()?;
+        let mut state: MutexGuard<RaftState> = self.lock();
+        if state.hard_state.installing || index <= state.snapshot_index() {
+            return Ok(());
+        }
+        state.compact(index);
+        let state: MutexGuard<RaftState> = self.sync(state)?;
+        debug!(
+            "Raft log is compacted to index {}, {} entries are left",
+            index,
+            state.log.len()
+        );
+        Ok(())
+    }
+
+    fn not_leader(&self, state: &RaftState) -> KvsError {
+        KvsError::not_leader(
+            state
+                .leader
+                .filter(|&leader| leader != self.id)
+                .map(|leader| self.nodes[leader].clone()),
+        )
+    }
+
+    /// Append the write to the log if this node is the leader, and wait until it's applied.
+    /// Returns the result of applying it.
+    pub(crate) fn propose(&self, instruction: Instruction) -> Result<bool> {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        if state.role != RaftRole::Leader || state.stopped {
+            return Err(self.not_leader(&state));
+        }
+        let entry: LogEntry = LogEntry {
+            term: state.term(),
+            instruction: Some(instruction),
+        };
+        state.append(&[entry]);
+        let index: u64 = state.last_index();
+        state.pending.insert(index, None);
+        // peers receive the entry while it's synced.
+        self.changed.notify_all();
+        state = match self.sync(state) {
+            Ok(state) => state,
+            Err(e) => {
+                self.lock().pending.remove(&index);
+                return Err(e);
+            }
+        };
+        loop {
+            if let Some(Some(_)) = state.pending.get(&index) {
+                return state
+                    .pending
+                    .remove(&index)
+                    .and_then(|result| result)
+                    .unwrap_or_else(|| Err(lost_leadership()));
+            }
+            state = self.wait_timeout(state, self.heartbeat_interval);
+        }
+    }
+
+    /// Wait until entries of former terms are applied, reads are only served by the leader.
+    pub(crate) fn read_barrier(&self) -> Result<()> {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        loop {
+            if state.role != RaftRole::Leader || state.stopped {
+                return Err(self.not_leader(&state));
+            }
+            if state.last_applied >= state.term_start {
+                return Ok(());
+            }
+            state = self.wait_timeout(state, self.heartbeat_interval);
+        }
+    }
+
+    /// Handle a message from a peer, returns the reply encoded by bincode.
+    pub(crate) fn handle(&self, message: &RaftMessage) -> Result<Vec<u8>> {
+        let reply: RaftReply = match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self.handle_vote(*term, *candidate, *last_log_index, *last_log_term)?,
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append(
+                *term,
+                *leader,
+                *prev_log_index,
+                *prev_log_term,
+                entries,
+                *leader_commit,
+            )?,
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                offset,
+                pairs,
+                done,
+            } => self.handle_snapshot(
+                *term,
+                *leader,
+                (*last_index, *last_term),
+                *offset,
+                pairs,
+                *done,
+            )?,
+        };
+        Ok(bincode::serialize(&reply)?)
+    }
+
+    fn handle_vote(
+        &self,
+        term: u64,
+        candidate: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<RaftReply> {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        if term > state.term() {
+            self.become_follower(&mut state, term, None);
+        }
+        let up_to_date: bool = (last_log_term, last_log_index)
+            >= (state.term_at(state.last_index()), state.last_index());
+        let granted: bool = term == state.term()
+            && state
+                .hard_state
+                .voted_for
+                .is_none_or(|voted_for| voted_for == candidate)
+            && up_to_date;
+        if granted {
+            let term: u64 = state.term();
+            state.set_hard_state(term, Some(candidate));
+            state.election_deadline = Instant::now() + random_timeout(self.election_timeout);
+        }
+        let reply_term: u64 = state.term();
+        let _state: MutexGuard<RaftState> = self.sync(state)?;
+        debug!(
+            "Vote for node {} at term {}: {}",
+            candidate, reply_term, granted
+        );
+        Ok(RaftReply::Vote {
+            term: reply_term,
+            granted,
+        })
+    }
+
+    fn handle_append(
+        &self,
+        term: u64,
+        leader: usize,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: &[LogEntry],
+        leader_commit: u64,
+    ) -> Result<RaftReply> {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        if term < state.term() {
+            return Ok(RaftReply::Append {
+                term: state.term(),
+                success: false,
+                last_index: state.last_index(),
+            });
+        }
+        if term > state.term() || state.role != RaftRole::Follower {
+            self.become_follower(&mut state, term, Some(leader));
+        }
+        state.leader = Some(leader);
+        state.election_deadline = Instant::now() + random_timeout(self.election_timeout);
+        if state.hard_state.installing {
+            let _state: MutexGuard<RaftState> = self.sync(state)?;
+            return Ok(RaftReply::Append {
+                term,
+                success: false,
+                last_index: 0,
+            });
+        }
+
+        if prev_log_index < state.snapshot_index() {
+            // entries up to the snapshot are committed, so they match the leader.
+            let skip: usize =
+                ((state.snapshot_index() - prev_log_index) as usize).min(entries.len());
+            prev_log_index += skip as u64;
+            prev_log_term = state.term_at(prev_log_index);
+            entries = &entries[skip..];
+        }
+        if prev_log_index > state.last_index() || state.term_at(prev_log_index) != prev_log_term {
+            let last_index: u64 = state.last_index().min(prev_log_index.saturating_sub(1));
+            let _state: MutexGuard<RaftState> = self.sync(state)?;
+            return Ok(RaftReply::Append {
+                term,
+                success: false,
+                last_index,
+            });
+        }
+        // entries which are already in the log are skipped, conflicting ones are dropped.
+        for (i, entry) in entries.iter().enumerate() {
+            let index: u64 = prev_log_index + 1 + i as u64;
+            if index <= state.last_index() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                state.truncate(index);
+            }
+            state.append(&entries[i..]);
+            break;
+        }
+        let last_new: u64 = prev_log_index + entries.len() as u64;
+        let last_term: u64 = state.term_at(last_new);
+        state = self.sync(state)?;
+        // the log may be changed by another leader while it's synced.
+        if state.term() != term
+            || state.synced_index.max(state.snapshot_index()) < last_new
+            || state.term_at(last_new) != last_term
+        {
+            return Ok(RaftReply::Append {
+                term: state.term(),
+                success: false,
+                last_index: prev_log_index,
+            });
+        }
+        if leader_commit > state.commit_index {
+            state.commit_index = state.commit_index.max(leader_commit.min(last_new));
+            self.changed.notify_all();
+        }
+        Ok(RaftReply::Append {
+            term,
+            success: true,
+            last_index: last_new,
+        })
+    }
+
+    /// Receive a chunk of the snapshot of the leader, the engine is flushed before the snapshot
+    /// is taken as installed.
+    fn handle_snapshot(
+        &self,
+        term: u64,
+        leader: usize,
+        (last_index, last_term): (u64, u64),
+        offset: u64,
+        pairs: &[u8],
+        done: bool,
+    ) -> Result<RaftReply> {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        let rejected = |term: u64| RaftReply::Snapshot {
+            term,
+            success: false,
+            done: false,
+        };
+        if term < state.term() {
+            return Ok(rejected(state.term()));
+        }
+        if term > state.term() || state.role != RaftRole::Follower {
+            self.become_follower(&mut state, term, Some(leader));
+        }
+        state.leader = Some(leader);
+        state.election_deadline = Instant::now() + random_timeout(self.election_timeout);
+        if offset == 0 {
+            // committed entries are applied anyway.
+            if !state.hard_state.installing && last_index <= state.commit_index {
+                let _state: MutexGuard<RaftState> = self.sync(state)?;
+                return Ok(RaftReply::Snapshot {
+                    term,
+                    success: true,
+                    done: true,
+                });
+            }
+            info!(
+                "Node {} receives the snapshot of index {}",
+                self.id, last_index
+            );
+            state.start_install(last_index, last_term);
+            // the engine is changed once it's known to be installing.
+            state = self.sync(state)?;
+        }
+        if state.term() != term {
+            return Ok(rejected(state.term()));
+        }
+        let pairs: Vec<SnapshotPair> = bincode::deserialize(pairs)?;
+        match &state.receiving {
+            Some(receiving) if receiving.last_index == last_index && receiving.offset == offset => {
+            }
+            _ => return Ok(rejected(term)),
+        }
+        {
+            // entries which are not applied yet are replaced by the snapshot.
+            let mut engine: MutexGuard<AppliedEngine> = self.lock_engine();
+            engine.machine.restore(&pairs)?;
+            engine.index = last_index;
+            engine.term = last_term;
+        }
+        let receiving: &mut Receiving = state.receiving.as_mut().expect("checked above");
+        receiving.offset += pairs.len() as u64;
+        receiving
+            .keys
+            .extend(pairs.into_iter().map(|(key, _, _)| key));
+        if !done {
+            return Ok(RaftReply::Snapshot {
+                term,
+                success: true,
+                done: false,
+            });
+        }
+
+        let receiving: Receiving = state.receiving.take().expect("checked above");
+        self.lock_engine().machine.retain(&receiving.keys)?;
+        drop(state);
+        self.lock_engine().machine.flush()?;
+        let mut state: MutexGuard<RaftState> = self.lock();
+        if !state.hard_state.installing || state.snapshot_index() != last_index {
+            return Ok(rejected(state.term()));
+        }
+        state.hard_state.installing = false;
+        state.version += 1;
+        let state: MutexGuard<RaftState> = self.sync(state)?;
+        info!(
+            "Node {} installs the snapshot of index {} with {} keys",
+            self.id,
+            last_index,
+            receiving.keys.len()
+        );
+        Ok(RaftReply::Snapshot {
+            term: state.term(),
+            success: true,
+            done: true,
+        })
+    }
+
+    /// Step down to a follower, the new term is synced before anything replies with it.
+    fn become_follower(&self, state: &mut RaftState, term: u64, leader: Option<usize>) {
+        if term > state.term() {
+            state.set_hard_state(term, None);
+        }
+        if state.role != RaftRole::Follower {
+            info!("Node {} becomes a follower at term {}", self.id, term);
+        }
+        if state.role == RaftRole::Leader {
+            state.fail_pending();
+        }
+        state.role = RaftRole::Follower;
+        state.leader = leader;
+        state.election_deadline = Instant::now() + random_timeout(self.election_timeout);
+        self.changed.notify_all();
+    }
+
+    fn become_leader(&self, state: &mut RaftState) {
+        info!(
+            "Node {} becomes the leader at term {}",
+            self.id,
+            state.term()
+        );
+        state.role = RaftRole::Leader;
+        state.leader = Some(self.id);
+        // entries of former terms are committed along with the no-op entry.
+        let entry: LogEntry = LogEntry {
+            term: state.term(),
+            instruction: None,
+        };
+        state.append(&[entry]);
+        state.term_start = state.last_index();
+        let next_index: u64 = state.last_index();
+        state
+            .next_index
+            .iter_mut()
+            .for_each(|index| *index = next_index);
+        state.match_index.iter_mut().for_each(|index| *index = 0);
+        let now: Instant = Instant::now();
+        state.last_ack.iter_mut().for_each(|ack| *ack = now);
+        self.changed.notify_all();
+    }
+
+    /// Commit the latest entry of the current term which is on a majority of nodes, entries of
+    /// this node count once they are synced.
+    fn advance_commit(&self, state: &mut RaftState) {
+        let mut match_index: Vec<u64> = state.match_index.clone();
+        match_index[self.id] = state.synced_index.min(state.last_index());
+        match_index.sort_unstable_by(|a, b| b.cmp(a));
+        let committed: u64 = match_index[self.majority() - 1];
+        if committed > state.commit_index && state.term_at(committed) == state.term() {
+            state.commit_index = committed;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Start elections when the leader is gone, and step down if this node is a leader which
+    /// is partitioned from the majority.  Changes which nothing waits for, like the no-op entry
+    /// of a new leader, are synced here.
+    fn run_timer(self: Arc<Self>) {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        while !state.stopped {
+            let now: Instant = Instant::now();
+            match state.role {
+                RaftRole::Leader => {
+                    let acked: usize = self
+                        .peers()
+                        .filter(|&peer| now - state.last_ack[peer] < self.election_timeout)
+                        .count();
+                    if acked + 1 < self.majority() {
+                        warn!("Node {} loses the majority, step down", self.id);
+                        let term: u64 = state.term();
+                        self.become_follower(&mut state, term, None);
+                    }
+                }
+                _ if now >= state.election_deadline && !state.hard_state.installing => {
+                    self.start_election(&mut state)
+                }
+                _ => {}
+            }
+            state = match self.sync(state) {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("Sync Raft state failed, reason: {:?}", e);
+                    self.lock()
+                }
+            };
+            state = self.wait_timeout(state, TICK_INTERVAL);
+        }
+    }
+
+    /// Votes are requested by threads of peers once the new term is synced.
+    fn start_election(&self, state: &mut RaftState) {
+        let term: u64 = state.term() + 1;
+        state.set_hard_state(term, Some(self.id));
+        state.role = RaftRole::Candidate;
+        state.leader = None;
+        state.votes = 1;
+        state.election_deadline = Instant::now() + random_timeout(self.election_timeout);
+        info!("Node {} starts an election at term {}", self.id, term);
+        if state.votes >= self.majority() {
+            self.become_leader(state);
+        }
+    }
+
+    /// Apply committed entries to the engine, the rest of them are applied once the node is
+    /// stopped.  The state is unlocked while they are applied, the engine may be flushed for a
+    /// while meanwhile.
+    fn run_applier(self: Arc<Self>) {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        loop {
+            if state.last_applied >= state.commit_index {
+                if state.stopped {
+                    return;
+                }
+                state = self.wait_timeout(state, self.heartbeat_interval);
+                continue;
+            }
+            let first: u64 = state.last_applied + 1;
+            let count: usize = (state.commit_index - state.last_applied) as usize;
+            let entries: Vec<LogEntry> = state.entries_from(first)[..count].to_vec();
+            drop(state);
+            let mut engine: MutexGuard<AppliedEngine> = self.lock_engine();
+            // a snapshot which is restored in the meantime replaces the entries.
+            if engine.index + 1 != first {
+                drop(engine);
+                state = self.lock();
+                continue;
+            }
+            let mut results: Vec<Result<bool>> = Vec::with_capacity(entries.len());
+            for (index, entry) in (first..).zip(entries) {
+                results.push(match entry.instruction {
+                    Some(instruction) => engine.machine.apply(instruction),
+                    None => Ok(true),
+                });
+                engine.index = index;
+                engine.term = entry.term;
+            }
+            drop(engine);
+            state = self.lock();
+            if state.last_applied + 1 == first {
+                state.applied(results);
+                self.changed.notify_all();
+            }
+        }
+    }
+
+    /// Compact the log once enough entries are applied after the last snapshot.
+    fn run_compactor(self: Arc<Self>) {
+        let mut state: MutexGuard<RaftState> = self.lock();
+        while !state.stopped {
+            if !state.hard_state.installing
+                && state.last_applied >= state.snapshot_index() + self.snapshot_entries
+            {
+                drop(state);
+                if let Err(e) = self.compact() {
+                    error!("Compact Raft log failed, reason: {:?}", e);
+                }
+                state = self.lock();
+            }
+            state = self.wait_timeout(state, self.heartbeat_interval);
+        }
+    }
+
+    /// Talk to the peer: request its vote when this node is a candidate, and replicate the log
+    /// to it when this node is the leader, heartbeats are sent if there is nothing to replicate.
+    fn run_peer(self: Arc<Self>, peer: usize) {
+        let mut client: Option<Client> = None;
+        let mut state: MutexGuard<RaftState> = self.lock();
+        loop {
+            if state.stopped {
+                return;
+            }
+            state = match state.role {
+                RaftRole::Candidate
+                    if state.vote_requested[peer] < state.term()
+                        && state.synced_version == state.version =>
+                {
+                    self.request_vote(state, &mut client, peer)
+                }
+                RaftRole::Leader if state.next_index[peer] <= state.snapshot_index() => {
+                    self.send_snapshot(state, &mut client, peer)
+                }
+                RaftRole::Leader => self.replicate(state, &mut client, peer),
+                _ => self.wait_timeout(state, self.heartbeat_interval),
+            };
+        }
+    }
+
+    fn request_vote<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, RaftState>,
+        client: &mut Option<Client>,
+        peer: usize,
+    ) -> MutexGuard<'a, RaftState> {
+        let term: u64 = state.term();
+        state.vote_requested[peer] = term;
+        let message: RaftMessage = RaftMessage::RequestVote {
+            term,
+            candidate: self.id,
+            last_log_index: state.last_index(),
+            last_log_term: state.term_at(state.last_index()),
+        };
+        drop(state);
+        let reply: Result<RaftReply> = self.send(client, peer, message);
+        let mut state: MutexGuard<RaftState> = self.lock();
+        match reply {
+            Ok(RaftReply::Vote {
+                term: reply_term, ..
+            }) if reply_term > state.term() => self.become_follower(&mut state, reply_term, None),
+            Ok(RaftReply::Vote { granted: true, .. })
+                if state.role == RaftRole::Candidate && state.term() == term =>
+            {
+                state.votes += 1;
+                if state.votes >= self.majority() {
+                    self.become_leader(&mut state);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Request vote of node {} failed, reason: {:?}", peer, e);
+                *client = None;
+            }
+        }
+        state
+    }
+
+    /// Send entries from the next index of the peer, then wait for new entries or commits, or
+    /// the next heartbeat.
+    fn replicate<'a>(
+        &'a self,
+        state: MutexGuard<'a, RaftState>,
+        client: &mut Option<Client>,
+        peer: usize,
+    ) -> MutexGuard<'a, RaftState> {
+        let term: u64 = state.term();
+        let prev_log_index: u64 = state.next_index[peer] - 1;
+        let mut bytes: u64 = 0;
+        let entries: Vec<LogEntry> = state
+            .entries_from(prev_log_index + 1)
+            .iter()
+            .take(MAX_APPEND_ENTRIES)
+            .take_while(|entry| {
+                let first: bool = bytes == 0;
+                bytes += bincode::serialized_size(entry).unwrap_or(0);
+                first || bytes <= MAX_APPEND_BYTES
+            })
+            .cloned()
+            .collect();
+        let sent: u64 = entries.len() as u64;
+        let leader_commit: u64 = state.commit_index;
+        let message: RaftMessage = RaftMessage::AppendEntries {
+            term,
+            leader: self.id,
+            prev_log_index,
+            prev_log_term: state.term_at(prev_log_index),
+            entries,
+            leader_commit,
+        };
+        drop(state);
+        let sent_at: Instant = Instant::now();
+        let reply: Result<RaftReply> = self.send(client, peer, message);
+        let mut state: MutexGuard<RaftState> = self.lock();
+
+        let mut failed: bool = false;
+        match reply {
+            Ok(RaftReply::Append {
+                term: reply_term, ..
+            }) if reply_term > state.term() => {
+                self.become_follower(&mut state, reply_term, None);
+                return state;
+            }
+            Ok(RaftReply::Append {
+                success,
+                last_index,
+                ..
+            }) if state.role == RaftRole::Leader && state.term() == term => {
+                state.last_ack[peer] = Instant::now();
+                if success {
+                    let match_index: u64 = state.match_index[peer].max(prev_log_index + sent);
+                    state.match_index[peer] = match_index;
+                    state.next_index[peer] = match_index + 1;
+                    self.advance_commit(&mut state);
+                } else {
+                    // retry from where the follower's log may match, a follower which rejects
+                    // entries from the first one receives a snapshot.
+                    state.next_index[peer] = (last_index + 1).min(prev_log_index);
+                    return state;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Append entries to node {} failed, reason: {:?}", peer, e);
+                *client = None;
+                failed = true;
+            }
+        }
+
+        let deadline: Instant = sent_at + self.heartbeat_interval;
+        loop {
+            let now: Instant = Instant::now();
+            let ready: bool = state.stopped
+                || state.role != RaftRole::Leader
+                || state.term() != term
+                || (!failed
+                    && (state.next_index[peer] <= state.last_index()
+                        || state.commit_index > leader_commit));
+            if ready || now >= deadline {
+                return state;
+            }
+            state = self.wait_timeout(state, deadline - now);
+        }
+    }
+
+    /// Send the engine to the peer, whose log is behind the snapshot of this node.
+    fn send_snapshot<'a>(
+        &'a self,
+        state: MutexGuard<'a, RaftState>,
+        client: &mut Option<Client>,
+        peer: usize,
+    ) -> MutexGuard<'a, RaftState> {
+        let term: u64 = state.term();
+        drop(state);
+        let sent_at: Instant = Instant::now();
+        let reply: Result<(u64, RaftReply)> = self.transfer_snapshot(client, peer, term);
+        let mut state: MutexGuard<RaftState> = self.lock();
+        match reply {
+            Ok((
+                _,
+                RaftReply::Snapshot {
+                    term: reply_term, ..
+                },
+            )) if reply_term > state.term() => {
+                self.become_follower(&mut state, reply_term, None);
+                return state;
+            }
+            Ok((last_index, RaftReply::Snapshot { done: true, .. }))
+                if state.role == RaftRole::Leader && state.term() == term =>
+            {
+                state.last_ack[peer] = Instant::now();
+                let match_index: u64 = state.match_index[peer].max(last_index);
+                state.match_index[peer] = match_index;
+                state.next_index[peer] = match_index + 1;
+                self.advance_commit(&mut state);
+                return state;
+            }
+            Ok((_, reply)) => debug!("Snapshot is rejected by node {}: {:?}", peer, reply),
+            Err(e) => {
+                debug!("Send snapshot to node {} failed, reason: {:?}", peer, e);
+                *client = None;
+            }
+        }
+        // retry after a heartbeat interval.
+        let deadline: Instant = sent_at + self.heartbeat_interval;
+        loop {
+            let now: Instant = Instant::now();
+            if state.stopped || state.role != RaftRole::Leader || now >= deadline {
+                return state;
+            }
+            state = self.wait_timeout(state, deadline - now);
+        }
+    }
+
+    /// Copy the engine in chunks, with the index and the term of the last entry which is applied
+    /// to it.  Entries are not applied until it's copied.
+    fn take_snapshot(&self) -> Result<(u64, u64, Vec<Vec<SnapshotPair>>)> {
+        let engine: MutexGuard<AppliedEngine> = self.lock_engine();
+        let mut chunks: Vec<Vec<SnapshotPair>> = Vec::new();
+        loop {
+            let after: Option<&[u8]> = chunks
+                .last()
+                .and_then(|chunk| chunk.last())
+                .map(|(key, _, _)| key.as_slice());
+            let chunk: Vec<SnapshotPair> = engine.machine.snapshot_chunk(after)?;
+            if chunk.is_empty() {
+                return Ok((engine.index, engine.term, chunks));
+            }
+            chunks.push(chunk);
+        }
+    }
+
+    /// Send chunks of a snapshot until the peer replies it's done, or it rejects one.  Returns
+    /// the index of the snapshot with the reply.
+    fn transfer_snapshot(
+        &self,
+        client: &mut Option<Client>,
+        peer: usize,
+        term: u64,
+    ) -> Result<(u64, RaftReply)> {
+        let (last_index, last_term, chunks) = self.take_snapshot()?;
+        info!("Send the snapshot of index {} to node {}", last_index, peer);
+        let mut chunks: vec::IntoIter<Vec<SnapshotPair>> = chunks.into_iter();
+        let mut offset: u64 = 0;
+        loop {
+            let chunk: Vec<SnapshotPair> = chunks.next().unwrap_or_default();
+            let done: bool = chunk.is_empty();
+            let sent: u64 = chunk.len() as u64;
+            let message: RaftMessage = RaftMessage::InstallSnapshot {
+                term,
+                leader: self.id,
+                last_index,
+                last_term,
+                offset,
+                pairs: bincode::serialize(&chunk)?,
+                done,
+            };
+            match self.send(client, peer, message)? {
+                RaftReply::Snapshot {
+                    success: true,
+                    done: false,
+                    ..
+                } if !done => offset += sent,
+                reply => return Ok((last_index, reply)),
+            }
+        }
+    }
+
+    /// Send the message to the peer, the connection is reused and opened on demand.
+    fn send(
+        &self,
+        client: &mut Option<Client>,
+        peer: usize,
+        message: RaftMessage,
+    ) -> Result<RaftReply> {
+        let connection: &Client = match client {
+            Some(connection) => connection,
+            None => client.insert(Client::connect_with(&self.nodes[peer], &self.client)?),
+        };
+        let body: Vec<u8> = connection.request(&Instruction::Raft { message })?;
+        Ok(bincode::deserialize(&body)?)
+    }
+}
+
+/// Random election timeout between `timeout` and twice of it, so nodes rarely start elections
+/// at the same time.
+fn random_timeout(timeout: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    let now: Duration = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    let millis: u64 = timeout.as_millis().max(1) as u64;
+    timeout + Duration::from_millis(hasher.finish() % millis)
+}
+
+/// Handle of a node of a Raft cluster, to check its state from other threads.
+#[derive(Clone)]
+pub struct ClusterHandle {
+    node: Arc<RaftNode>,
+}
+
+impl ClusterHandle {
+    pub(crate) fn new(node: Arc<RaftNode>) -> ClusterHandle {
+        ClusterHandle { node }
+    }
+
+    /// Check if the node is the leader now.
+    pub fn is_leader(&self) -> bool {
+        self.node.lock().role == RaftRole::Leader
+    }
+
+    /// Address of the leader known by the node.
+    pub fn leader(&self) -> Option<String> {
+        let state: MutexGuard<RaftState> = self.node.lock();
+        state.leader.map(|leader| self.node.nodes[leader].clone())
+    }
+
+    /// Current term of the node.
+    pub fn term(&self) -> u64 {
+        self.node.lock().term()
+    }
+
+    /// Index of the last entry which is known to be committed.
+    pub fn commit_index(&self) -> u64 {
+        self.node.lock().commit_index
+    }
+
+    /// Index of the last entry in the log, committed or not.
+    pub fn last_index(&self) -> u64 {
+        self.node.lock().last_index()
+    }
+
+    /// Index of the last entry of the snapshot, entries up to it are dropped from the log.
+    pub fn snapshot_index(&self) -> u64 {
+        self.node.lock().snapshot_index()
+    }
+}
@@ -0,0 +1,812 @@
+//! Leader-follower replication.
+//!
+//! Every server is a leader.  While followers are attached, it keeps the latest writes in a
+//! `ReplicationLog`, in the order they are applied to the engine.  A follower sends
+//! `Instruction::Replicate` to the leader, then the connection streams `ReplicationMessage`s: a
+//! snapshot of all pairs if the follower has nothing to resume from, then records of writes from
+//! the offset, and heartbeats if nothing is written for a while.  Once no follower has been
+//! attached for a while, writes are not recorded.
+//!
+//! Followers keep their position in memory, so they resume from it after reconnecting as long as
+//! the leader still buffers the records.  The position isn't durable on either side, so a full
+//! snapshot is transferred again:
+//! - after the leader or the follower restarts,
+//! - if no follower has been attached for `RESUME_WINDOW` (30 seconds),
+//! - if the follower lags behind more than `LOG_CAPACITY` (16 MiB) of records.
+//!
+//! Followers are read-only, writes of their clients are rejected.
+//!
+//! The replication log is kept in memory, apart from logs of engines, and it's identified by the
+//! start time of the leader process.  It's a deliberate deviation from resuming by the log of the
+//! engine: sled has no log which can be read, and `KvStore` drops records on compaction, so
+//! followers of a restarted leader always transfer a snapshot.
+//!
+//! Nodes of a Raft cluster wrap their engine the same way, writes are proposed to the Raft log
+//! instead, see the `raft` module.
+//!
+//! Writes are published to the `WatchHub` of the server once they are applied, whatever the
+//! role is.
+use super::client::{Client, ClientConfig};
+use super::frame::write_frame;
+use super::raft::RaftNode;
+use super::shutdown::{Connection, ShutdownHandle};
+use super::watch::WatchHub;
+use super::Response;
+use crate::command::{expire_at, now_millis, BatchOp, Instruction, RaftMessage, WriteBatch};
+use crate::engine::{BytesScanIter, KvsEngine};
+use crate::error::{KvsError, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::TcpStream;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// records are dropped from the front once they take more bytes than it.
+const LOG_CAPACITY: usize = 16 * 1024 * 1024;
+// bytes taken by a record besides it's keys and values.
+const RECORD_OVERHEAD: usize = 64;
+// how many records are sent before the writer is flushed.
+const RECORD_BATCH_SIZE: usize = 1024;
+// how many pairs are sent by a snapshot message.
+const SNAPSHOT_CHUNK_SIZE: usize = 1024;
+// the leader sends heartbeats if nothing is written, so followers can tell if it's gone.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// writes are still recorded for a while after the last follower is gone, so it can resume after
+// reconnecting.
+const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+/// Message streamed by the leader once it accepts `Instruction::Replicate`.
+#[derive(Debug, Serialize, Deserialize)]
+enum ReplicationMessage {
+    /// Pairs of a snapshot follow, then records from `offset`.
+    SnapshotStart { log_id: u64, offset: u64 },
+    /// `SnapshotPair`s encoded by bincode.
+    SnapshotChunk {
+        #[serde(with = "serde_bytes")]
+        pairs: Vec<u8>,
+    },
+    /// All pairs are sent, keys of the follower which are not in the snapshot are removed.
+    SnapshotEnd,
+    /// A write which is applied by the leader.
+    Record {
+        offset: u64,
+        instruction: Instruction,
+    },
+    /// Nothing is written for a while.
+    Heartbeat,
+}
+
+// key, value, and milliseconds since unix epoch when the key expires.
+pub(crate) type SnapshotPair = (Vec<u8>, Vec<u8>, Option<u64>);
+
+// log id, and offset of the next record in the log.
+type Position = (u64, u64);
+
+/// Latest writes of the leader, they are numbered by offsets from 0.
+pub(crate) struct ReplicationLog {
+    // identifies the log of this process, offsets of other logs are meaningless.
+    log_id: u64,
+    state: Mutex<LogState>,
+    appended: Condvar,
+    // writers share it, and a follower takes it alone to attach, so every write is either
+    // recorded or done before the snapshot of the follower.
+    writers: RwLock<()>,
+    // writes are applied and published one by one, so records and watch events are in the order
+    // of the engine.
+    order: Mutex<()>,
+    followers: AtomicUsize,
+    // milliseconds since unix epoch when the last follower is gone.
+    detached_at: AtomicU64,
+    // some writes are not recorded since the last follower is gone.
+    skipped: AtomicBool,
+}
+
+/// A follower which is attached to the log, writes are recorded until it's dropped.
+struct Attached<'a>(&'a ReplicationLog);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        self.0.detached_at.store(now_millis(), Ordering::SeqCst);
+        self.0.followers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct LogState {
+    // records and their sizes, the last one is at `next_offset - 1`.
+    records: VecDeque<(Instruction, usize)>,
+    next_offset: u64,
+    bytes: usize,
+    // followers stop streaming once the server is stopped.
+    closed: bool,
+}
+
+impl LogState {
+    fn first_offset(&self) -> u64 {
+        self.next_offset - self.records.len() as u64
+    }
+
+    fn append(&mut self, instruction: Instruction) {
+        let size: usize = record_size(&instruction);
+        self.records.push_back((instruction, size));
+        self.next_offset += 1;
+        self.bytes += size;
+        while self.bytes > LOG_CAPACITY && self.records.len() > 1 {
+            if let Some((_, size)) = self.records.pop_front() {
+                self.bytes -= size;
+            }
+        }
+    }
+}
+
+// Approximate memory taken by a record.
+fn record_size(instruction: &Instruction) -> usize {
+    let op_size = |op: &BatchOp| match op {
+        BatchOp::Set { key, value } => key.len() + value.len(),
+        BatchOp::Rm { key } => key.len(),
+    };
+    RECORD_OVERHEAD
+        + match instruction {
+            Instruction::Set { key, value, .. } => key.len() + value.len(),
+            Instruction::Rm { key } => key.len(),
+            Instruction::Batch { batch } => batch.ops().iter().map(op_size).sum(),
+            _ => 0,
+        }
+}
+
+impl ReplicationLog {
+    fn new() -> ReplicationLog {
+        // the start time is unique enough between runs of the leader.
+        let log_id: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time is before unix epoch")
+            .as_nanos() as u64;
+        ReplicationLog {
+            log_id,
+            state: Mutex::new(LogState {
+                records: VecDeque::new(),
+                next_offset: 0,
+                bytes: 0,
+                closed: false,
+            }),
+            appended: Condvar::new(),
+            writers: RwLock::new(()),
+            order: Mutex::new(()),
+            followers: AtomicUsize::new(0),
+            detached_at: AtomicU64::new(0),
+            skipped: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().expect("Lock replication log failed.")
+    }
+
+    /// Do the write, and append the record it returns if it succeeds and followers are
+    /// attached.  Writes are done one by one, so records and events they publish are in the same
+    /// order as writes are applied, but the log itself isn't locked while the engine is written.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<(T, Option<Instruction>)>,
+    {
+        let _writer = self.writers.read().expect("Lock replication log failed.");
+        let _order: MutexGuard<()> = self.order.lock().expect("Lock replication log failed.");
+        let (result, record) = f()?;
+        let record: Instruction = match record {
+            Some(record) => record,
+            None => return Ok(result),
+        };
+        let detached_at: u64 = self.detached_at.load(Ordering::SeqCst);
+        if self.followers.load(Ordering::SeqCst) == 0
+            && now_millis().saturating_sub(detached_at) > RESUME_WINDOW.as_millis() as u64
+        {
+            self.skipped.store(true, Ordering::SeqCst);
+        } else {
+            self.lock().append(record);
+            self.appended.notify_all();
+        }
+        Ok(result)
+    }
+
+    /// Attach a follower, writes are recorded from now on until it's dropped.
+    fn attach(&self) -> Attached<'_> {
+        let _writers = self.writers.write().expect("Lock replication log failed.");
+        if self.skipped.swap(false, Ordering::SeqCst) {
+            // records are missing since the last follower is gone, so no one can resume from
+            // buffered records.
+            let mut state: MutexGuard<LogState> = self.lock();
+            state.records.clear();
+            state.bytes = 0;
+            state.next_offset += 1;
+        }
+        self.followers.fetch_add(1, Ordering::SeqCst);
+        Attached(self)
+    }
+
+    /// Check if records from `offset` of the log are still buffered.
+    fn can_resume(&self, log_id: Option<u64>, offset: u64) -> bool {
+        let state: MutexGuard<LogState> = self.lock();
+        log_id == Some(self.log_id) && state.first_offset() <= offset && offset <= state.next_offset
+    }
+
+    /// Records from `offset`, it waits for `timeout` if there is none.  Returns `None` if the
+    /// log is closed.
+    ///
+    /// # Errors
+    /// It fails if records from `offset` are already dropped.
+    fn read_from(&self, offset: u64, timeout: Duration) -> Result<Option<Vec<Instruction>>> {
+        let mut state: MutexGuard<LogState> = self.lock();
+        if state.next_offset == offset && !state.closed {
+            state = self
+                .appended
+                .wait_timeout(state, timeout)
+                .expect("Lock replication log failed.")
+                .0;
+        }
+        if state.closed {
+            return Ok(None);
+        }
+        let first_offset: u64 = state.first_offset();
+        if offset < first_offset {
+            return Err(KvsError::invalid_request(
+                "Records are dropped, the follower lags too far",
+            ));
+        }
+        Ok(Some(
+            state
+                .records
+                .iter()
+                .skip((offset - first_offset) as usize)
+                .take(RECORD_BATCH_SIZE)
+                .map(|(instruction, _)| instruction.clone())
+                .collect(),
+        ))
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.appended.notify_all();
+    }
+}
+
+/// Engine of a server, writes are recorded to the replication log if it's a leader, rejected
+/// if it's a follower, and proposed to the Raft log if it's a node of a cluster.
+#[derive(Clone)]
+pub(crate) struct ReplicatedEngine<E: KvsEngine> {
+    engine: E,
+    role: Role,
+    hub: WatchHub,
+}
+
+#[derive(Clone)]
+enum Role {
+    Leader(Arc<ReplicationLog>),
+    Follower,
+    Cluster(Arc<RaftNode>),
+}
+
+impl<E: KvsEngine> ReplicatedEngine<E> {
+    pub(crate) fn leader(engine: E) -> ReplicatedEngine<E> {
+        ReplicatedEngine {
+            engine,
+            role: Role::Leader(Arc::new(ReplicationLog::new())),
+            hub: WatchHub::new(),
+        }
+    }
+
+    /// Writes are applied by the `Follower`, which publishes them to the hub.
+    pub(crate) fn follower(engine: E, hub: WatchHub) -> ReplicatedEngine<E> {
+        ReplicatedEngine {
+            engine,
+            role: Role::Follower,
+            hub,
+        }
+    }
+
+    /// Writes are applied by the state machine of the node, which publishes them to the hub.
+    pub(crate) fn cluster(engine: E, node: Arc<RaftNode>, hub: WatchHub) -> ReplicatedEngine<E> {
+        ReplicatedEngine {
+            engine,
+            role: Role::Cluster(node),
+            hub,
+        }
+    }
+
+    /// The wrapped engine, writes to it are not recorded.
+    pub(crate) fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    /// Hub which applied writes are published to.
+    pub(crate) fn hub(&self) -> &WatchHub {
+        &self.hub
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader(_))
+    }
+
+    /// Start the Raft node if it's in a cluster.
+    pub(crate) fn start(&self) {
+        if let Role::Cluster(node) = &self.role {
+            node.start();
+        }
+    }
+
+    /// Stop streaming the log to followers, or stop the Raft node, and disconnect watchers.
+    pub(crate) fn close(&self) {
+        self.hub.close();
+        match &self.role {
+            Role::Leader(log) => log.close(),
+            Role::Follower => {}
+            Role::Cluster(node) => node.stop(),
+        }
+    }
+
+    /// Answer `Instruction::Replicate` of a follower, then stream the log to it until the log is
+    /// closed or the follower is gone.
+    pub(crate) fn serve_follower<W: Write>(
+        &self,
+        log_id: Option<u64>,
+        offset: u64,
+        writer: &mut W,
+    ) -> Result<()> {
+        let log: &ReplicationLog = match &self.role {
+            Role::Leader(log) => log,
+            _ => return Err(KvsError::invalid_request("The server can't be followed")),
+        };
+        let _attached: Attached = log.attach();
+        write_frame(writer, &Response::new_ok())?;
+        let mut offset: u64 = offset;
+        if !log.can_resume(log_id, offset) {
+            offset = self.send_snapshot(log, writer)?;
+        }
+        writer.flush()?;
+        while let Some(records) = log.read_from(offset, HEARTBEAT_INTERVAL)? {
+            if records.is_empty() {
+                write_frame(writer, &ReplicationMessage::Heartbeat)?;
+            }
+            for instruction in records {
+                write_frame(
+                    writer,
+                    &ReplicationMessage::Record {
+                        offset,
+                        instruction,
+                    },
+                )?;
+                offset += 1;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send all pairs of the engine, returns the offset which records start from.
+    ///
+    /// Pairs are scanned while writes go on, the records applied after the snapshot make it
+    /// consistent, because they are idempotent for followers.
+    fn send_snapshot<W: Write>(&self, log: &ReplicationLog, writer: &mut W) -> Result<u64> {
+        // writes before the offset are already applied to the engine.
+        let offset: u64 = log.lock().next_offset;
+        debug!("Send snapshot of offset {}", offset);
+        write_frame(
+            writer,
+            &ReplicationMessage::SnapshotStart {
+                log_id: log.log_id,
+                offset,
+            },
+        )?;
+        let mut pairs: BytesScanIter = self.engine.scan_bytes(..)?;
+        loop {
+            let chunk: Vec<SnapshotPair> = pairs
+                .by_ref()
+                .take(SNAPSHOT_CHUNK_SIZE)
+                .map(|pair| {
+                    let (key, value) = pair?;
+                    let expire: Option<u64> = self.engine.ttl_bytes(&key)?.map(expire_at);
+                    Ok((key, value, expire))
+                })
+                .collect::<Result<_>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            write_frame(
+                writer,
+                &ReplicationMessage::SnapshotChunk {
+                    pairs: bincode::serialize(&chunk)?,
+                },
+            )?;
+        }
+        write_frame(writer, &ReplicationMessage::SnapshotEnd)?;
+        Ok(offset)
+    }
+
+    /// Answer a message of another node of the cluster.
+    pub(crate) fn handle_raft(&self, message: &RaftMessage) -> Response {
+        let result: Result<Vec<u8>> = match &self.role {
+            Role::Cluster(node) => node.handle(message),
+            _ => Err(not_in_cluster()),
+        };
+        match result {
+            Ok(body) => Response::new_ok_with_body(body),
+            Err(e) => Response::from_error(&e),
+        }
+    }
+
+    // Do the write on the engine if it's a leader, or propose it to the cluster.  Returns the
+    // result of a compare-and-swap, other writes return true.
+    fn write(&self, instruction: Instruction) -> Result<bool> {
+        match &self.role {
+            Role::Leader(log) => {
+                log.write(|| apply_and_publish(&self.engine, &self.hub, instruction))
+            }
+            Role::Follower => Err(KvsError::invalid_request("Read-only replica")),
+            Role::Cluster(node) => node.propose(instruction),
+        }
+    }
+
+    // Only the leader of a cluster serves reads, once entries of former terms are applied.
+    fn check_read(&self) -> Result<()> {
+        match &self.role {
+            Role::Cluster(node) => node.read_barrier(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReplicatedEngine<E> {
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.write(Instruction::Set {
+            key,
+            value: val,
+            expire_at: None,
+        })
+        .map(|_| ())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(Instruction::Set {
+            key,
+            value: val,
+            expire_at: Some(expire_at(ttl)),
+        })
+        .map(|_| ())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_read()?;
+        self.engine.get_bytes(key)
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.check_read()?;
+        self.engine.ttl_bytes(key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(Instruction::Rm { key: key.to_vec() })
+            .map(|_| ())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(Instruction::CompareAndSwap { key, expected, new })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(Instruction::Batch { batch }).map(|_| ())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a,
+    {
+        self.check_read()?;
+        self.engine.scan_bytes(range)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter<'_>> {
+        self.check_read()?;
+        self.engine.scan_prefix_bytes(prefix)
+    }
+}
+
+fn not_in_cluster() -> KvsError {
+    KvsError::invalid_request("The server is not in a cluster")
+}
+
+// What the leader records for a write, compare-and-swaps are recorded as the resulting set or
+// rm, so followers don't compare values.
+fn record_of(instruction: &Instruction) -> Option<Instruction> {
+    match instruction {
+        Instruction::CompareAndSwap { key, expected, new } => match (expected, new) {
+            (_, Some(value)) => Some(Instruction::Set {
+                key: key.clone(),
+                value: value.clone(),
+                expire_at: None,
+            }),
+            (Some(_), None) => Some(Instruction::Rm { key: key.clone() }),
+            (None, None) => None,
+        },
+        instruction => Some(instruction.clone()),
+    }
+}
+
+/// Apply a write instruction to the engine, returns the result of a compare-and-swap, other
+/// writes return true.
+pub(crate) fn apply_write<E: KvsEngine>(engine: &E, instruction: Instruction) -> Result<bool> {
+    match instruction {
+        Instruction::Set {
+            key,
+            value,
+            expire_at,
+        } => match expire_at {
+            // the key is expired at the same time as where it's written.
+            Some(expire_at) => {
+                let ttl: u64 = expire_at.saturating_sub(now_millis());
+                engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl))
+            }
+            None => engine.set_bytes(key, value),
+        }
+        .map(|_| true),
+        Instruction::Rm { key } => engine.remove_bytes(&key).map(|_| true),
+        Instruction::Batch { batch } => engine.write_batch(batch).map(|_| true),
+        Instruction::CompareAndSwap { key, expected, new } => {
+            engine.compare_and_swap_bytes(key, expected, new)
+        }
+        _ => Err(KvsError::invalid_request("Not a write instruction")),
+    }
+}
+
+/// Apply a write instruction to the engine like `apply_write`, and publish it to the hub if
+/// anything is changed.  Returns the result of a compare-and-swap, and the record of the write
+/// if anything is changed.
+pub(crate) fn apply_and_publish<E: KvsEngine>(
+    engine: &E,
+    hub: &WatchHub,
+    instruction: Instruction,
+) -> Result<(bool, Option<Instruction>)> {
+    let record: Option<Instruction> = record_of(&instruction);
+    let swapped: bool = apply_write(engine, instruction)?;
+    let record: Option<Instruction> = record.filter(|_| swapped);
+    if let Some(record) = &record {
+        hub.publish(record);
+    }
+    Ok((swapped, record))
+}
+
+/// Progress of a replica, it's updated by the thread which follows the leader.
+#[derive(Clone, Default)]
+pub struct ReplicaStatus {
+    state: Arc<ReplicaState>,
+}
+
+#[derive(Default)]
+struct ReplicaState {
+    connected: AtomicBool,
+    offset: AtomicU64,
+    snapshots: AtomicU64,
+}
+
+impl ReplicaStatus {
+    /// Check if the replica is streaming from the leader.
+    pub fn is_connected(&self) -> bool {
+        self.state.connected.load(Ordering::SeqCst)
+    }
+
+    /// Offset of the next record in the log of the leader, records before it are applied.
+    pub fn offset(&self) -> u64 {
+        self.state.offset.load(Ordering::SeqCst)
+    }
+
+    /// How many snapshots are received, it's not increased when the replica resumes.
+    pub fn snapshots(&self) -> u64 {
+        self.state.snapshots.load(Ordering::SeqCst)
+    }
+}
+
+/// Replicate writes of the leader to the local engine, it reconnects until the server is
+/// stopped.
+pub(crate) struct Follower<E: KvsEngine> {
+    leader: String,
+    config: ClientConfig,
+    engine: E,
+    // applied records and changes of snapshots are published to it.
+    hub: WatchHub,
+    status: ReplicaStatus,
+    // it's `None` until a snapshot is received.
+    position: Option<Position>,
+}
+
+impl<E: KvsEngine> Follower<E> {
+    pub(crate) fn new(leader: &str, config: ClientConfig, engine: E, hub: WatchHub) -> Follower<E> {
+        Follower {
+            leader: leader.to_owned(),
+            config,
+            engine,
+            hub,
+            status: ReplicaStatus::default(),
+            position: None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> ReplicaStatus {
+        self.status.clone()
+    }
+
+    pub(crate) fn run(mut self, shutdown: ShutdownHandle) {
+        let mut backoff: Duration = self.config.backoff;
+        while !shutdown.is_shutdown() {
+            match self.follow(&shutdown) {
+                Ok(()) => info!("Connection to leader {} is closed", self.leader),
+                Err(e) => error!("Follow leader {} failed, reason: {:?}", self.leader, e),
+            }
+            if self.status.state.connected.swap(false, Ordering::SeqCst) {
+                backoff = self.config.backoff;
+            }
+            if shutdown.is_shutdown() {
+                break;
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn follow(&mut self, shutdown: &ShutdownHandle) -> Result<()> {
+        let mut client: Client = Client::connect_with(&self.leader, &self.config)?;
+        let stream: TcpStream = client.tcp_stream()?;
+        stream.set_read_timeout(Some(LEADER_TIMEOUT))?;
+        // it's closed by `shutdown` like connections of clients, so the server can stop.
+        let _connection: Connection = shutdown.track(&stream)?;
+        let (log_id, offset) = match self.position {
+            Some((log_id, offset)) => (Some(log_id), offset),
+            None => (None, 0),
+        };
+        client.execute(&Instruction::Replicate { log_id, offset })?;
+        self.status.state.connected.store(true, Ordering::SeqCst);
+        info!("Following leader {} from offset {}", self.leader, offset);
+
+        // keys of the snapshot being received, and the position after it.
+        let mut snapshot: Option<(HashSet<Vec<u8>>, Position)> = None;
+        while let Some(message) = client.read_message()? {
+            match message {
+                ReplicationMessage::SnapshotStart { log_id, offset } => {
+                    self.position = None;
+                    snapshot = Some((HashSet::new(), (log_id, offset)));
+                }
+                ReplicationMessage::SnapshotChunk { pairs } => {
+                    let keys: &mut HashSet<Vec<u8>> = match &mut snapshot {
+                        Some((keys, _)) => keys,
+                        None => return Err(unexpected_message()),
+                    };
+                    let pairs: Vec<SnapshotPair> = bincode::deserialize(&pairs)?;
+                    for (key, value, expire_at) in pairs {
+                        keys.insert(key.clone());
+                        // pairs which are not changed are sent again after reconnecting.
+                        let changed: bool = self.engine.get_bytes(&key)?.as_ref() != Some(&value);
+                        let set: Instruction = Instruction::Set {
+                            key,
+                            value,
+                            expire_at,
+                        };
+                        apply(&self.engine, set.clone())?;
+                        if changed {
+                            self.hub.publish(&set);
+                        }
+                    }
+                }
+                ReplicationMessage::SnapshotEnd => {
+                    let (keys, position) = snapshot.take().ok_or_else(unexpected_message)?;
+                    self.remove_stale_keys(&keys)?;
+                    self.position = Some(position);
+                    self.status.state.offset.store(position.1, Ordering::SeqCst);
+                    self.status.state.snapshots.fetch_add(1, Ordering::SeqCst);
+                    info!("Snapshot of {} keys is received", keys.len());
+                }
+                ReplicationMessage::Record {
+                    offset,
+                    instruction,
+                } => {
+                    let next: &mut u64 = match &mut self.position {
+                        Some((_, next)) if *next == offset => next,
+                        _ => {
+                            self.position = None;
+                            return Err(unexpected_message());
+                        }
+                    };
+                    apply(&self.engine, instruction.clone())?;
+                    self.hub.publish(&instruction);
+                    *next += 1;
+                    self.status.state.offset.store(*next, Ordering::SeqCst);
+                }
+                ReplicationMessage::Heartbeat => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Remove keys which are not in the snapshot, they are removed by the leader.
+    fn remove_stale_keys(&self, keys: &HashSet<Vec<u8>>) -> Result<()> {
+        let stale: Vec<Vec<u8>> = self
+            .engine
+            .scan_bytes(..)?
+            .filter(|pair| match pair {
+                Ok((key, _)) => !keys.contains(key),
+                Err(_) => true,
+            })
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        for key in stale {
+            ignore_not_found(self.engine.remove_bytes(&key))?;
+            self.hub.publish(&Instruction::Rm { key });
+        }
+        Ok(())
+    }
+}
+
+fn unexpected_message() -> KvsError {
+    KvsError::invalid_request("Unexpected replication message")
+}
+
+/// Apply a record of the leader, it's idempotent because pairs of a snapshot may be newer than
+/// records, so removing an absent key is fine.
+fn apply<E: KvsEngine>(engine: &E, instruction: Instruction) -> Result<()> {
+    match instruction {
+        Instruction::Rm { key } => ignore_not_found(engine.remove_bytes(&key)),
+        Instruction::Batch { batch } => {
+            let batch: WriteBatch = skip_absent_removes(engine, batch)?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            engine.write_batch(batch)
+        }
+        instruction @ Instruction::Set { .. } => apply_write(engine, instruction).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// Drop removes of absent keys from the batch, taking earlier operations of it into account.
+fn skip_absent_removes<E: KvsEngine>(engine: &E, batch: WriteBatch) -> Result<WriteBatch> {
+    let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+    let mut result: WriteBatch = WriteBatch::new();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                exists.insert(key.clone(), true);
+                result.set(key, value);
+            }
+            BatchOp::Rm { key } => {
+                let found: bool = match exists.get(&key) {
+                    Some(&found) => found,
+                    None => engine.get_bytes(&key)?.is_some(),
+                };
+                if found {
+                    exists.insert(key.clone(), false);
+                    result.remove(key);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+pub(crate) fn ignore_not_found(result: Result<()>) -> Result<()> {
+    match result {
+        Err(ref e) if e.is_key_not_found() => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,430 @@
+//! Redis serialization protocol (RESP2), so redis clients can talk to kvs-server.
+//!
+//! Supported commands are GET, SET, DEL, EXISTS, PING, SCAN, MGET and MSET, they are mapped
+//! onto `KvsEngine`.
+use super::stream::Stream;
+use crate::command::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::error::{KvsError, Result};
+use log::debug;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::time::Duration;
+
+// same limits as redis, commands are read before the client is known.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_ARGS: i64 = 1024 * 1024;
+const MAX_LINE_LEN: usize = 64 * 1024;
+// total length of bulk strings of a command.
+const MAX_COMMAND_LEN: i64 = 512 * 1024 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Reply to redis clients.
+#[derive(Debug, PartialEq)]
+enum RespValue {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            RespValue::Simple(s) => write!(writer, "+{}\r\n", s),
+            RespValue::Error(message) => {
+                // the message is a single line.
+                let message: String = message.replace(['\r', '\n'], " ");
+                write!(writer, "-{}\r\n", message)
+            }
+            RespValue::Integer(i) => write!(writer, ":{}\r\n", i),
+            RespValue::Bulk(None) => write!(writer, "$-1\r\n"),
+            RespValue::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            RespValue::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Handle commands of a redis client in order, until the client closes the connection.
+pub fn handle_client(client_stream: Stream, engine: &impl KvsEngine) -> Result<()> {
+    let peer_addr = client_stream.tcp().peer_addr()?;
+    debug!("Waiting RESP data from {}", peer_addr);
+
+    let mut reader: BufReader<Stream> = BufReader::new(client_stream.try_clone()?);
+    let mut writer: BufWriter<Stream> = BufWriter::new(client_stream);
+    loop {
+        let args: Vec<Vec<u8>> = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => {
+                debug!(
+                    "Connection closed by peer {}, so this connection is closed.",
+                    peer_addr
+                );
+                return Ok(());
+            }
+            // the stream can't be parsed any more, report it and close the connection.
+            Err(e) => {
+                RespValue::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        debug!(
+            "Peer: {}, RESP command: {}",
+            peer_addr,
+            String::from_utf8_lossy(&args[0])
+        );
+        execute_command(args, engine).write_to(&mut writer)?;
+        // pipelined commands are answered together, once all received ones are handled.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Read a command, it's either an array of bulk strings or an inline command.
+///
+/// Returns `None` if the client closes the connection between commands.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line: Vec<u8> = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args: Vec<Vec<u8>> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count: i64 = parse_int(&line[1..])
+        .filter(|count| *count <= MAX_ARGS)
+        .ok_or_else(|| protocol_error("invalid array length"))?;
+    let mut args: Vec<Vec<u8>> = Vec::with_capacity(count.clamp(0, 1024) as usize);
+    let mut total: i64 = 0;
+    for _ in 0..count {
+        let line: Vec<u8> = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected bulk string"));
+        }
+        let len: i64 = parse_int(&line[1..])
+            .filter(|len| (0..=MAX_BULK_LEN).contains(len))
+            .ok_or_else(|| protocol_error("invalid bulk length"))?;
+        total += len;
+        if total > MAX_COMMAND_LEN {
+            return Err(protocol_error("command is too large"));
+        }
+        // the bulk string is allocated as it arrives, not by the length which the client claims.
+        let mut arg: Vec<u8> = Vec::new();
+        (&mut *reader).take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len as usize + 2 {
+            return Err(protocol_error("unexpected end"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated"));
+        }
+        arg.truncate(len as usize);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line ending with "\r\n", the ending is not included.  Lines are at most
+/// `MAX_LINE_LEN` bytes.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line: Vec<u8> = Vec::new();
+    let limit: u64 = MAX_LINE_LEN as u64 + 2;
+    if (&mut *reader).take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() as u64 == limit && !line.ends_with(b"\n") {
+        return Err(protocol_error("line is too long"));
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_error("line is not terminated"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::invalid_request(message)
+}
+
+fn execute_command(mut args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> RespValue {
+    let name: String = String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase();
+    let result: Result<RespValue> = match name.as_str() {
+        "PING" => ping(args),
+        "GET" => get(args, engine),
+        "SET" => set(args, engine),
+        "DEL" => del(args, engine),
+        "EXISTS" => exists(args, engine),
+        "MGET" => mget(args, engine),
+        "MSET" => mset(args, engine),
+        "SCAN" => scan(args, engine),
+        _ => Ok(RespValue::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        ))),
+    };
+    result.unwrap_or_else(|e| RespValue::Error(format!("ERR {}", e)))
+}
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+fn syntax_error() -> RespValue {
+    RespValue::Error(String::from("ERR syntax error"))
+}
+
+/// Some engines report a missing key by an error, it's taken as `None` here.
+fn get_value(engine: &impl KvsEngine, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match engine.get_bytes(key) {
+        Ok(value) => Ok(value),
+        Err(ref e) if e.is_key_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn ping(mut args: Vec<Vec<u8>>) -> Result<RespValue> {
+    match args.len() {
+        0 => Ok(RespValue::Simple("PONG")),
+        1 => Ok(RespValue::Bulk(args.pop())),
+        _ => Ok(wrong_arity("ping")),
+    }
+}
+
+fn get(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.len() != 1 {
+        return Ok(wrong_arity("get"));
+    }
+    Ok(RespValue::Bulk(get_value(engine, &args[0])?))
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`, a conditional set can't expire.
+fn set(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.len() < 2 {
+        return Ok(wrong_arity("set"));
+    }
+    let mut args = args.into_iter();
+    let key: Vec<u8> = args.next().unwrap();
+    let value: Vec<u8> = args.next().unwrap();
+
+    let mut ttl: Option<Duration> = None;
+    let mut condition: Option<String> = None;
+    while let Some(option) = args.next() {
+        let option: String = String::from_utf8_lossy(&option).to_ascii_uppercase();
+        match option.as_str() {
+            "EX" | "PX" if ttl.is_none() => {
+                let amount: u64 = match args.next().as_deref().and_then(parse_int) {
+                    Some(amount) if amount > 0 => amount as u64,
+                    _ => {
+                        return Ok(RespValue::Error(String::from(
+                            "ERR invalid expire time in 'set' command",
+                        )))
+                    }
+                };
+                ttl = Some(if option == "EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            "NX" | "XX" if condition.is_none() => condition = Some(option),
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    match (condition.as_deref(), ttl) {
+        (None, None) => engine.set_bytes(key, value)?,
+        (None, Some(ttl)) => engine.set_bytes_with_ttl(key, value, ttl)?,
+        (Some(_), Some(_)) => {
+            return Ok(RespValue::Error(String::from(
+                "ERR expire time is not supported with NX or XX",
+            )))
+        }
+        (Some("NX"), None) => {
+            if !engine.set_if_absent_bytes(key, value)? {
+                return Ok(RespValue::Bulk(None));
+            }
+        }
+        (Some(_), None) => loop {
+            // XX: swap the current value, retry if it's changed by others.
+            let current: Option<Vec<u8>> = get_value(engine, &key)?;
+            if current.is_none() {
+                return Ok(RespValue::Bulk(None));
+            }
+            if engine.compare_and_swap_bytes(key.clone(), current, Some(value.clone()))? {
+                break;
+            }
+        },
+    }
+    Ok(RespValue::Simple("OK"))
+}
+
+fn del(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.is_empty() {
+        return Ok(wrong_arity("del"));
+    }
+    let mut removed: i64 = 0;
+    for key in args {
+        match engine.remove_bytes(&key) {
+            Ok(()) => removed += 1,
+            Err(ref e) if e.is_key_not_found() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(RespValue::Integer(removed))
+}
+
+fn exists(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.is_empty() {
+        return Ok(wrong_arity("exists"));
+    }
+    let mut found: i64 = 0;
+    for key in args {
+        if get_value(engine, &key)?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(RespValue::Integer(found))
+}
+
+fn mget(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.is_empty() {
+        return Ok(wrong_arity("mget"));
+    }
+    let mut values: Vec<RespValue> = Vec::with_capacity(args.len());
+    for key in args {
+        values.push(RespValue::Bulk(get_value(engine, &key)?));
+    }
+    Ok(RespValue::Array(values))
+}
+
+fn mset(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Ok(wrong_arity("mset"));
+    }
+    let mut batch: WriteBatch = WriteBatch::new();
+    let mut args = args.into_iter();
+    while let (Some(key), Some(value)) = (args.next(), args.next()) {
+        batch.set(key, value);
+    }
+    engine.write_batch(batch)?;
+    Ok(RespValue::Simple("OK"))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+///
+/// The cursor is how many keys are already visited in key order, like redis, `MATCH` filters
+/// keys after they are visited.
+fn scan(args: Vec<Vec<u8>>, engine: &impl KvsEngine) -> Result<RespValue> {
+    if args.is_empty() {
+        return Ok(wrong_arity("scan"));
+    }
+    let mut args = args.into_iter();
+    let cursor: usize = match args.next().as_deref().and_then(parse_int) {
+        Some(cursor) if cursor >= 0 => cursor as usize,
+        _ => return Ok(RespValue::Error(String::from("ERR invalid cursor"))),
+    };
+    let mut pattern: Option<Vec<u8>> = None;
+    let mut count: usize = DEFAULT_SCAN_COUNT;
+    while let Some(option) = args.next() {
+        match String::from_utf8_lossy(&option)
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "MATCH" => match args.next() {
+                Some(arg) => pattern = Some(arg),
+                None => return Ok(syntax_error()),
+            },
+            "COUNT" => match args.next().as_deref().and_then(parse_int) {
+                Some(arg) if arg > 0 => count = arg as usize,
+                _ => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let mut keys: Vec<RespValue> = Vec::new();
+    let mut visited: usize = 0;
+    for pair in engine.scan_bytes(..)?.skip(cursor).take(count) {
+        let (key, _) = pair?;
+        visited += 1;
+        if pattern.as_ref().is_none_or(|p| glob_match(p, &key)) {
+            keys.push(RespValue::Bulk(Some(key)));
+        }
+    }
+    // a zero cursor means the iteration is finished.
+    let next_cursor: usize = if visited < count { 0 } else { cursor + visited };
+    Ok(RespValue::Array(vec![
+        RespValue::Bulk(Some(next_cursor.to_string().into_bytes())),
+        RespValue::Array(keys),
+    ]))
+}
+
+/// Glob style matching, `*` matches any bytes, `?` matches a single byte, and `\` escapes the
+/// next byte.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume if the bytes after the last `*` don't match.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(&b) if b != b'\\' && b == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((star_p, star_t)) => {
+                backtrack = Some((star_p, star_t + 1));
+                p = star_p;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
@@ -1,3 +1,4 @@
+use crate::error::{ErrorCode, KvsError, Result};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -7,60 +8,109 @@ pub struct Response {
     status: Status,
     /// Relative message.
     message: String,
-    /// Response body.
-    body: String,
+    /// Kind of the error, it's `None` if the request succeeds.
+    #[serde(default)]
+    code: Option<ErrorCode>,
+    /// Response body, it's bytes so binary values can be returned.
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Status {
     OK,
     ERROR,
 }
 
 impl Response {
-    pub fn new(status: Status, message: String, body: String) -> Response {
+    pub fn new(status: Status, message: String, body: Vec<u8>) -> Response {
+        let code: Option<ErrorCode> = match status {
+            Status::OK => None,
+            Status::ERROR => Some(ErrorCode::Internal),
+        };
         Response {
             status,
             message,
+            code,
             body,
         }
     }
 
     pub fn new_ok() -> Response {
-        Response {
-            status: Status::OK,
-            message: String::from(""),
-            body: String::from(""),
-        }
+        Response::new(Status::OK, String::from(""), Vec::new())
     }
 
-    pub fn new_ok_with_body(body: String) -> Response {
-        Response {
-            status: Status::OK,
-            message: String::from(""),
-            body,
-        }
+    pub fn new_ok_with_body(body: Vec<u8>) -> Response {
+        Response::new(Status::OK, String::from(""), body)
     }
+
     pub fn new_err(message: String) -> Response {
+        Response::new(Status::ERROR, message, Vec::new())
+    }
+
+    /// Error response of the given kind.
+    pub fn new_err_with_code(code: ErrorCode, message: String) -> Response {
         Response {
             status: Status::ERROR,
             message,
-            body: String::from(""),
+            code: Some(code),
+            body: Vec::new(),
         }
     }
 
-    pub fn is_ok(&self) -> bool {
-        match self.status {
-            Status::OK => true,
-            _ => false,
+    /// Error response of a `KvsError`, the client restores the error by it's code.
+    pub fn from_error(error: &KvsError) -> Response {
+        let mut response: Response = Response::new_err_with_code(error.code(), error.to_string());
+        if let Some(leader) = error.leader() {
+            response.body = leader.as_bytes().to_vec();
         }
+        response
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, Status::OK)
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        self.get_code() == Some(ErrorCode::Conflict)
+    }
+
+    pub fn is_denied(&self) -> bool {
+        self.get_code() == Some(ErrorCode::Unauthorized)
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        self.get_code() == Some(ErrorCode::NotLeader)
     }
 
     pub fn get_message(&self) -> &String {
         &self.message
     }
 
-    pub fn get_body(&self) -> &String {
+    /// Kind of the error, it's `None` if the request succeeds.
+    pub fn get_code(&self) -> Option<ErrorCode> {
+        match self.status {
+            Status::OK => None,
+            // responses of old servers have no code.
+            Status::ERROR => Some(self.code.unwrap_or(ErrorCode::Internal)),
+        }
+    }
+
+    pub fn get_body(&self) -> &[u8] {
         &self.body
     }
+
+    /// Returns the body if the request succeeds, otherwise the error sent by the server.
+    pub fn into_result(self) -> Result<Vec<u8>> {
+        match self.get_code() {
+            None => Ok(self.body),
+            Some(ErrorCode::NotLeader) => Err(KvsError::not_leader(
+                String::from_utf8(self.body)
+                    .ok()
+                    .filter(|leader| !leader.is_empty()),
+            )),
+            Some(code) => Err(KvsError::from_code(code, &self.message)),
+        }
+    }
 }
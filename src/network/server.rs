@@ -1,103 +1,534 @@
-use super::Response;
-use crate::command::Instruction;
+use super::auth::{AuthConfig, Session};
+use super::client::ClientConfig;
+use super::frame::{read_frame, write_frame};
+use super::raft::{ClusterHandle, RaftConfig, RaftNode};
+use super::replication::{Follower, ReplicaStatus, ReplicatedEngine};
+use super::shutdown::{Connection, ShutdownHandle};
+use super::stream::Stream;
+use super::tls::ServerTls;
+use super::watch::{self, WatchHub};
+use super::{http, resp, Response};
+use crate::command::{now_millis, Instruction};
 use crate::engine::KvsEngine;
-use crate::error::Result;
+use crate::error::{ErrorCode, KvsError, Result};
 use crate::thread_pool::ThreadPool;
-use log::{debug, error};
+use crate::Protocol;
+use log::{debug, error, info};
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// followers and watchers hold their connections until they are gone, so they are served by
+// threads of their own instead of the thread pool, at most this many at the same time.
+const MAX_STREAMS: usize = 256;
+
+/// Rest of a connection which streams the log to a follower or changes to a watcher.
+type LongLivedStream = Box<dyn FnOnce() -> Result<()> + Send>;
 
 pub struct Server<E: KvsEngine, P: ThreadPool> {
     listener: TcpListener,
-    engine: E,
-    thread_pool: P,
+    // optional listener of the HTTP gateway, it shares the engine and the thread pool.
+    http_listener: Option<TcpListener>,
+    // writes are recorded for followers, rejected if the server is a replica, or proposed to
+    // the Raft log if the server is a node of a cluster.
+    engine: ReplicatedEngine<E>,
+    // replicates from the leader if the server is a replica.
+    follower: Option<Follower<E>>,
+    thread_pool: Arc<P>,
+    protocol: Protocol,
+    // clients are served over TLS if it's set, on the HTTP gateway as well.
+    tls: Option<ServerTls>,
+    // clients must log in if it's set, it's shared by all connections.
+    auth: Option<Arc<AuthConfig>>,
+    streams: StreamCount,
+    shutdown: ShutdownHandle,
 }
 
-impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
+/// Count of long-lived streams, a stream is counted until its `StreamPermit` is dropped.
+#[derive(Clone, Default)]
+struct StreamCount(Arc<AtomicUsize>);
+
+struct StreamPermit(Arc<AtomicUsize>);
+
+impl StreamCount {
+    fn acquire(&self) -> Result<StreamPermit> {
+        let count: &AtomicUsize = &self.0;
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < MAX_STREAMS {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| KvsError::invalid_request("Too many followers and watchers"))?;
+        Ok(StreamPermit(self.0.clone()))
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Server<E, P> {
     pub fn new<T>(addr: T, engine: E, thread_pool: P) -> Result<Server<E, P>>
     where
         T: ToSocketAddrs,
     {
+        let listener: TcpListener = TcpListener::bind(addr)?;
+        let shutdown: ShutdownHandle = ShutdownHandle::new();
+        shutdown.add_listener(listener.local_addr()?);
         Ok(Server {
-            listener: TcpListener::bind(addr)?,
-            engine,
-            thread_pool,
+            listener,
+            http_listener: None,
+            engine: ReplicatedEngine::leader(engine),
+            follower: None,
+            thread_pool: Arc::new(thread_pool),
+            protocol: Protocol::Kvs,
+            tls: None,
+            auth: None,
+            streams: StreamCount::default(),
+            shutdown,
         })
     }
 
+    /// Set the protocol spoken with clients, it's `Protocol::Kvs` by default.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Serve clients over TLS, plain TCP connections are rejected then.
+    pub fn set_tls(&mut self, tls: ServerTls) {
+        self.tls = Some(tls);
+    }
+
+    /// Require clients to log in, and check their requests by ACLs of the config.
+    ///
+    /// It's only supported by `Protocol::Kvs`, `serve_forever` fails if the server speaks RESP or
+    /// has the HTTP gateway.
+    pub fn set_auth(&mut self, auth: AuthConfig) {
+        self.auth = Some(Arc::new(auth));
+    }
+
+    /// Follow the leader at `addr` as a read-only replica, writes of the leader are replicated to
+    /// the engine once the server is started.  It connects to the leader by the config, and
+    /// reconnects with the backoff of the config until the server is stopped.  The position in the
+    /// leader's log is kept in memory, see the `replication` module for when a full snapshot is
+    /// transferred again.
+    ///
+    /// Returns the status which is updated as the replica follows the leader.
+    pub fn set_replica_of(&mut self, addr: &str, config: ClientConfig) -> ReplicaStatus {
+        let engine: E = self.engine.inner().clone();
+        let hub: WatchHub = self.engine.hub().clone();
+        self.engine = ReplicatedEngine::follower(engine.clone(), hub.clone());
+        let follower: Follower<E> = Follower::new(addr, config, engine, hub);
+        let status: ReplicaStatus = follower.status();
+        self.follower = Some(follower);
+        status
+    }
+
+    /// Join a Raft cluster as the node `config.id`, the engine is the snapshot of the Raft log,
+    /// entries after it are applied once the server is started.  Only the leader serves clients,
+    /// other nodes redirect them to it.
+    ///
+    /// Returns a handle to check the state of the node.
+    ///
+    /// # Errors
+    /// It fails if the Raft log can't be opened, or the node id is out of the cluster.
+    pub fn set_cluster(&mut self, config: RaftConfig) -> Result<ClusterHandle> {
+        let engine: E = self.engine.inner().clone();
+        let hub: WatchHub = self.engine.hub().clone();
+        let node: Arc<RaftNode> = RaftNode::open(config, engine.clone(), hub.clone())?;
+        self.engine = ReplicatedEngine::cluster(engine, node.clone(), hub);
+        self.follower = None;
+        Ok(ClusterHandle::new(node))
+    }
+
+    /// Listen on `addr` for the HTTP gateway as well, returns the address listened on.
+    pub fn listen_http<T>(&mut self, addr: T) -> Result<SocketAddr>
+    where
+        T: ToSocketAddrs,
+    {
+        let listener: TcpListener = TcpListener::bind(addr)?;
+        let local_addr: SocketAddr = listener.local_addr()?;
+        self.shutdown.add_listener(local_addr);
+        self.http_listener = Some(listener);
+        Ok(local_addr)
+    }
+
+    /// Address which the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Handle to stop the server from other threads.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serve until the server is stopped by the `ShutdownHandle`, then wait for connections to
+    /// be closed and flush the engine.
     pub fn serve_forever(&mut self) -> Result<()> {
+        if self.auth.is_some() && (self.protocol != Protocol::Kvs || self.http_listener.is_some()) {
+            return Err(KvsError::invalid_request(
+                "Authentication is only supported by the kvs protocol",
+            ));
+        }
+        let http_thread = self.http_listener.take().map(|http_listener| {
+            let engine: ReplicatedEngine<E> = self.engine.clone();
+            let thread_pool: Arc<P> = self.thread_pool.clone();
+            let tls: Option<ServerTls> = self.tls.clone();
+            let shutdown: ShutdownHandle = self.shutdown.clone();
+            thread::spawn(move || serve_http(http_listener, engine, thread_pool, tls, shutdown))
+        });
+        let follower_thread = self.follower.take().map(|follower| {
+            let shutdown: ShutdownHandle = self.shutdown.clone();
+            thread::spawn(move || follower.run(shutdown))
+        });
+        self.engine.start();
         debug!("Waiting for connections...");
         for stream in self.listener.incoming() {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
             match stream {
                 Ok(client_stream) => {
                     debug!(
                         "New connection established from {}",
                         client_stream.peer_addr()?
                     );
+                    let connection: Connection = match self.shutdown.track(&client_stream) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            error!("Connection failed, reason: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let client_stream: Stream = match accept(&self.tls, client_stream) {
+                        Ok(client_stream) => client_stream,
+                        Err(e) => {
+                            error!("Connection failed, reason: {:?}", e);
+                            continue;
+                        }
+                    };
                     let engine_work = self.engine.clone();
+                    let protocol: Protocol = self.protocol;
+                    let session: Session = Session::new(self.auth.clone());
+                    let streams: StreamCount = self.streams.clone();
                     self.thread_pool.spawn(move || {
-                        Self::handle_client(client_stream, &engine_work).unwrap();
+                        let result = match protocol {
+                            Protocol::Kvs => {
+                                handle_stream(client_stream, &engine_work, session, &streams)
+                            }
+                            Protocol::Resp => {
+                                resp::handle_client(client_stream, &engine_work).map(|_| None)
+                            }
+                        };
+                        match result {
+                            // the worker is given back to the pool, the connection is still
+                            // tracked by the stream thread.
+                            Ok(Some(stream)) => {
+                                thread::spawn(move || {
+                                    let _connection: Connection = connection;
+                                    if let Err(e) = stream() {
+                                        error!("Handle client failed, reason: {:?}", e);
+                                    }
+                                });
+                            }
+                            Ok(None) => {}
+                            Err(e) => error!("Handle client failed, reason: {:?}", e),
+                        }
                     })
                 }
                 Err(e) => error!("Connection failed, reason: {:?}", e),
             }
         }
+        if let Some(http_thread) = http_thread {
+            if http_thread.join().is_err() {
+                error!("HTTP gateway thread panicked");
+            }
+        }
+        if let Some(follower_thread) = follower_thread {
+            if follower_thread.join().is_err() {
+                error!("Follower thread panicked");
+            }
+        }
+        // connections of followers and watchers are closed once they see the log or the hub is
+        // closed, and pending writes of a cluster fail once the Raft node is stopped.
+        self.engine.close();
+        debug!("Waiting for connections to be closed...");
+        self.shutdown.wait_drained();
+        self.engine.flush()?;
+        info!("Server is stopped");
         Ok(())
     }
 
-    pub fn handle_client(mut client_stream: TcpStream, engine: &impl KvsEngine) -> Result<()> {
-        let peer_addr = client_stream.peer_addr()?;
-        debug!("Waiting data from {}", peer_addr);
+    pub fn handle_client(client_stream: TcpStream, engine: &impl KvsEngine) -> Result<()> {
+        let engine = ReplicatedEngine::leader(engine.clone());
+        let streams: StreamCount = StreamCount::default();
+        match handle_stream(
+            Stream::Tcp(client_stream),
+            &engine,
+            Session::default(),
+            &streams,
+        )? {
+            Some(stream) => stream(),
+            None => Ok(()),
+        }
+    }
+}
 
-        let mut buf: [u8; 1024] = [0; 1024];
-        loop {
-            let bytes: usize = client_stream.read(&mut buf)?;
-            if bytes == 0 {
+// Wrap the accepted TCP stream with TLS if it's enabled.
+fn accept(tls: &Option<ServerTls>, client_stream: TcpStream) -> Result<Stream> {
+    match tls {
+        Some(tls) => tls.accept(client_stream),
+        None => Ok(Stream::Tcp(client_stream)),
+    }
+}
+
+/// Handle instructions of a client until the connection is closed, or it turns into a
+/// long-lived stream which is returned to be served by another thread.
+fn handle_stream<E: KvsEngine>(
+    client_stream: Stream,
+    engine: &ReplicatedEngine<E>,
+    mut session: Session,
+    streams: &StreamCount,
+) -> Result<Option<LongLivedStream>> {
+    let peer_addr = client_stream.tcp().peer_addr()?;
+    debug!("Waiting data from {}", peer_addr);
+
+    let mut reader: BufReader<Stream> = BufReader::new(client_stream.try_clone()?);
+    let mut writer: BufWriter<Stream> = BufWriter::new(client_stream);
+    loop {
+        let instruction: Instruction = match read_frame(&mut reader)? {
+            Some(instruction) => instruction,
+            None => {
                 debug!(
                     "Connection closed by peer {}, so this connection is closed.",
                     peer_addr
                 );
                 break;
-            } else {
-                debug!("Receive data from peer: {}", peer_addr);
-                let user_command: String = String::from_utf8_lossy(&buf[..bytes]).into_owned();
-                let instruction: Instruction = serde_json::from_str(&user_command)?;
-                debug!("Peer: {}, Instruction: {:?}", peer_addr, instruction);
-                // handle for user request.
-                let response: Response = Self::execute_instruction(instruction, engine);
-                // TODO: here we need to check serde_json result..  What if it goes into fail..
-                let bytes: Vec<u8> = serde_json::to_vec(&response)?;
-                client_stream.write_all(&bytes)?;
-                debug!("Solve complete for peer: {}", peer_addr);
             }
+        };
+        debug!("Peer: {}, Instruction: {:?}", peer_addr, instruction);
+        // handle for user request.
+        let response: Response = match instruction {
+            Instruction::Replicate { log_id, offset }
+                if engine.is_leader() && session.authorize(&instruction).is_ok() =>
+            {
+                match streams.acquire() {
+                    // the connection streams the log to the follower from now on.
+                    Ok(permit) => {
+                        let engine: ReplicatedEngine<E> = engine.clone();
+                        return Ok(Some(Box::new(move || {
+                            let _permit: StreamPermit = permit;
+                            engine.serve_follower(log_id, offset, &mut writer)
+                        })));
+                    }
+                    Err(e) => Response::from_error(&e),
+                }
+            }
+            Instruction::Raft { ref message } if session.authorize(&instruction).is_ok() => {
+                engine.handle_raft(message)
+            }
+            Instruction::Watch { prefix } if session.authorize(&instruction).is_ok() => {
+                match streams.acquire() {
+                    // the connection streams changes to the watcher from now on.
+                    Ok(permit) => {
+                        let hub: WatchHub = engine.hub().clone();
+                        return Ok(Some(Box::new(move || {
+                            let _permit: StreamPermit = permit;
+                            watch::serve_watcher(&hub, &prefix, &session, &mut writer)
+                        })));
+                    }
+                    Err(e) => Response::from_error(&e),
+                }
+            }
+            instruction => execute_instruction(instruction, engine, &mut session),
+        };
+        write_frame(&mut writer, &response)?;
+        // pipelined requests are answered together, once all received ones are handled.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
         }
-        Ok(())
+        debug!("Solve complete for peer: {}", peer_addr);
     }
+    Ok(None)
+}
 
-    fn execute_instruction(instruction: Instruction, engine: &impl KvsEngine) -> Response {
-        match instruction {
-            Instruction::Set { key, value } => {
-                let result = engine.set(key, value);
-                match result {
-                    Ok(_) => Response::new_ok(),
-                    Err(e) => Response::new_err(e.to_string()),
+/// Execute the instruction on the engine if the session is allowed to, errors are returned as
+/// responses.
+pub(crate) fn execute_instruction(
+    instruction: Instruction,
+    engine: &impl KvsEngine,
+    session: &mut Session,
+) -> Response {
+    if let Err(e) = session.authorize(&instruction) {
+        return Response::from_error(&e);
+    }
+    match instruction {
+        Instruction::Set {
+            key,
+            value,
+            expire_at,
+        } => {
+            // clients before `SetWithTtl` send when the key expires, the rest of ttl is counted
+            // from now.
+            let result = match expire_at {
+                Some(expire_at) => {
+                    let ttl: u64 = expire_at.saturating_sub(now_millis());
+                    engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl))
                 }
+                None => engine.set_bytes(key, value),
+            };
+            match result {
+                Ok(_) => Response::new_ok(),
+                Err(e) => Response::from_error(&e),
             }
-            Instruction::Get { key } => {
-                let result = engine.get(key);
-                match result {
-                    Ok(Some(s)) => Response::new_ok_with_body(s),
-                    Ok(None) => Response::new_err(String::from("Key not found")),
-                    Err(e) => Response::new_err(e.to_string()),
-                }
+        }
+        Instruction::SetWithTtl { key, value, ttl_ms } => {
+            match engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
+                Ok(_) => Response::new_ok(),
+                Err(e) => Response::from_error(&e),
             }
-            Instruction::Rm { key } => {
-                let result = engine.remove(key);
-                match result {
-                    Ok(_) => Response::new_ok(),
-                    Err(e) => Response::new_err(e.to_string()),
-                }
+        }
+        Instruction::Get { key } => {
+            let result = engine.get_bytes(&key);
+            match result {
+                Ok(Some(s)) => Response::new_ok_with_body(s),
+                Ok(None) => Response::from_error(&KvsError::key_not_found()),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::Rm { key } => {
+            let result = engine.remove_bytes(&key);
+            match result {
+                Ok(_) => Response::new_ok(),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::Batch { batch } => {
+            let result = engine.write_batch(batch);
+            match result {
+                Ok(_) => Response::new_ok(),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::CompareAndSwap { key, expected, new } => {
+            let result = engine.compare_and_swap_bytes(key, expected, new);
+            match result {
+                Ok(true) => Response::new_ok(),
+                Ok(false) => Response::new_err_with_code(
+                    ErrorCode::Conflict,
+                    String::from("Value is changed"),
+                ),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::Ttl { key } => {
+            let result = engine.ttl_bytes(&key).and_then(|ttl| {
+                let millis: Option<u64> = ttl.map(|ttl| ttl.as_millis() as u64);
+                Ok(bincode::serialize(&millis)?)
+            });
+            match result {
+                Ok(body) => Response::new_ok_with_body(body),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::Scan { start, end, limit } => {
+            let result = scan(engine, start, end, limit, session);
+            match result {
+                Ok(pairs) => Response::new_ok_with_body(pairs),
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Instruction::Auth { credentials } => match session.login(&credentials) {
+            Ok(_) => Response::new_ok(),
+            Err(e) => Response::from_error(&e),
+        },
+        // leaders stream the log on the connection instead.
+        Instruction::Replicate { .. } => {
+            Response::from_error(&KvsError::invalid_request("The server can't be followed"))
+        }
+        // nodes of a cluster handle it with the Raft node instead.
+        Instruction::Raft { .. } => {
+            Response::from_error(&KvsError::invalid_request("The server is not in a cluster"))
+        }
+        // connections of the kvs protocol stream changes instead.
+        Instruction::Watch { .. } => {
+            Response::from_error(&KvsError::invalid_request("The server can't be watched"))
+        }
+    }
+}
+
+/// Scan a page of pairs which the session can read, they are encoded by bincode as the response
+/// body.
+fn scan(
+    engine: &impl KvsEngine,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: u32,
+    session: &Session,
+) -> Result<Vec<u8>> {
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = engine
+        .scan_bytes((start, end))?
+        .filter(|pair| match pair {
+            Ok((key, _)) => session.can_read(key),
+            Err(_) => true,
+        })
+        .take(limit as usize)
+        .collect::<Result<_>>()?;
+    Ok(bincode::serialize(&pairs)?)
+}
+
+/// Accept connections of the HTTP gateway, they are handled in the thread pool.
+fn serve_http<E, P>(
+    listener: TcpListener,
+    engine: E,
+    thread_pool: Arc<P>,
+    tls: Option<ServerTls>,
+    shutdown: ShutdownHandle,
+) where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    debug!("Waiting for HTTP connections...");
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        match stream {
+            Ok(client_stream) => {
+                let connection: Connection = match shutdown.track(&client_stream) {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("HTTP connection failed, reason: {:?}", e);
+                        continue;
+                    }
+                };
+                let client_stream: Stream = match accept(&tls, client_stream) {
+                    Ok(client_stream) => client_stream,
+                    Err(e) => {
+                        error!("HTTP connection failed, reason: {:?}", e);
+                        continue;
+                    }
+                };
+                let engine_work = engine.clone();
+                thread_pool.spawn(move || {
+                    let _connection: Connection = connection;
+                    if let Err(e) = http::handle_client(client_stream, &engine_work) {
+                        error!("Handle HTTP client failed, reason: {:?}", e);
+                    }
+                })
             }
+            Err(e) => error!("HTTP connection failed, reason: {:?}", e),
         }
     }
 }
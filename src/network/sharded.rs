@@ -0,0 +1,350 @@
+//! Client-side sharding of keys across servers.
+//!
+//! Keys are routed by consistent hashing: every server is placed on a hash ring at many points,
+//! its virtual nodes, and a key belongs to the first virtual node at or after the hash of the
+//! key.  So when a server is added, only keys which fall on its virtual nodes move to it, about
+//! `1 / servers` of all keys, and they are moved by `ShardedClient::rebalance`.
+use super::pool::ClientPool;
+use super::ClientConfig;
+use crate::command::{BatchOp, WriteBatch};
+use crate::engine::{BytesScanIter, KvsEngine};
+use crate::error::{KvsError, Result};
+use log::debug;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// how many points each server takes on the ring, more points spread keys more evenly.
+const VIRTUAL_NODES: usize = 128;
+
+// values read from a server, with indexes of their keys.
+type ShardValues = Vec<(usize, Option<Vec<u8>>)>;
+
+type Pair = (Vec<u8>, Vec<u8>);
+
+/// Client of several servers, each key is stored on one of them, picked by consistent hashing.
+/// It implements `KvsEngine` like `Client`.
+///
+/// Requests to a server go through a `ClientPool` of it.  Batches and `get_many` are split by
+/// servers and sent to them in parallel, a batch is only atomic on each server.  Scans are sent
+/// to every server, and their pairs are merged in key order.
+///
+/// All clients must list the same servers, in any order, or they disagree on where keys are.
+#[derive(Clone)]
+pub struct ShardedClient {
+    inner: Arc<ShardedInner>,
+}
+
+struct ShardedInner {
+    servers: Vec<String>,
+    pools: Vec<ClientPool>,
+    // hash of virtual nodes to indexes of servers.
+    ring: BTreeMap<u64, usize>,
+}
+
+impl ShardedClient {
+    /// Create a client of the servers, connections are opened on demand.
+    ///
+    /// # Errors
+    /// It fails if there is no server.
+    pub fn new(servers: Vec<String>, config: ClientConfig) -> Result<ShardedClient> {
+        if servers.is_empty() {
+            return Err(KvsError::invalid_request("No servers to shard keys to"));
+        }
+        let mut ring: BTreeMap<u64, usize> = BTreeMap::new();
+        for (index, server) in servers.iter().enumerate() {
+            for i in 0..VIRTUAL_NODES {
+                ring.insert(hash(format!("{}#{}", server, i).as_bytes()), index);
+            }
+        }
+        let pools: Vec<ClientPool> = servers
+            .iter()
+            .map(|server| ClientPool::new(server, config.clone()))
+            .collect();
+        Ok(ShardedClient {
+            inner: Arc::new(ShardedInner {
+                servers,
+                pools,
+                ring,
+            }),
+        })
+    }
+
+    /// Addresses of the servers.
+    pub fn servers(&self) -> &[String] {
+        &self.inner.servers
+    }
+
+    /// Address of the server which the key belongs to.
+    pub fn server_of(&self, key: &[u8]) -> &str {
+        &self.inner.servers[self.shard_of(key)]
+    }
+
+    // Index of the server which the key belongs to.
+    fn shard_of(&self, key: &[u8]) -> usize {
+        let ring: &BTreeMap<u64, usize> = &self.inner.ring;
+        ring.range(hash(key)..)
+            .next()
+            .or_else(|| ring.iter().next())
+            .map(|(_, &index)| index)
+            .expect("Ring has no servers")
+    }
+
+    fn pool_of(&self, key: &[u8]) -> &ClientPool {
+        &self.inner.pools[self.shard_of(key)]
+    }
+
+    /// Get values of the keys, in the same order as the keys.  Keys of different servers are
+    /// read in parallel.
+    ///
+    /// # Errors
+    /// It fails if any of the servers fails.
+    pub fn get_many_bytes(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut shards: Vec<Vec<usize>> = vec![Vec::new(); self.inner.pools.len()];
+        for (i, key) in keys.iter().enumerate() {
+            shards[self.shard_of(key)].push(i);
+        }
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        let results: Vec<Result<ShardValues>> = thread::scope(|scope| {
+            let handles: Vec<_> = shards
+                .iter()
+                .zip(&self.inner.pools)
+                .filter(|(indexes, _)| !indexes.is_empty())
+                .map(|(indexes, pool)| {
+                    scope.spawn(move || {
+                        indexes
+                            .iter()
+                            .map(|&i| Ok((i, pool.get_bytes(&keys[i])?)))
+                            .collect()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Shard thread panicked"))
+                .collect()
+        });
+        for result in results {
+            for (i, value) in result? {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Same as `get_many_bytes`, for string keys and values.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        self.get_many_bytes(&keys)?
+            .into_iter()
+            .map(|value| {
+                value
+                    .map(String::from_utf8)
+                    .transpose()
+                    .map_err(KvsError::from)
+            })
+            .collect()
+    }
+
+    /// Move keys which are stored on a server other than the one they belong to, it's called
+    /// after servers are added or removed, on a client of the new servers.  Returns how many
+    /// keys are moved.
+    ///
+    /// Every key of every server is scanned, a key is written to the server it belongs to, then
+    /// removed from the former one.  Expire times of moved keys are not kept, because servers
+    /// don't tell them.  Writes of clients which still use the former servers may be lost
+    /// meanwhile.
+    ///
+    /// # Errors
+    /// It fails if any of the servers fails, keys moved before that stay moved, so it can be
+    /// called again.
+    pub fn rebalance(&self) -> Result<usize> {
+        let mut moved: usize = 0;
+        for (index, pool) in self.inner.pools.iter().enumerate() {
+            for pair in pool.scan_bytes(..)? {
+                let (key, value) = pair?;
+                let owner: usize = self.shard_of(&key);
+                if owner == index {
+                    continue;
+                }
+                // keys keep their expire times on the new server.
+                let target: &ClientPool = &self.inner.pools[owner];
+                match pool.ttl_bytes(&key)? {
+                    Some(ttl) => target.set_bytes_with_ttl(key.clone(), value, ttl)?,
+                    None => target.set_bytes(key.clone(), value)?,
+                }
+                match pool.remove_bytes(&key) {
+                    Err(ref e) if e.is_key_not_found() => {}
+                    result => result?,
+                }
+                moved += 1;
+            }
+            debug!(
+                "Keys of server {} are rebalanced",
+                self.inner.servers[index]
+            );
+        }
+        Ok(moved)
+    }
+}
+
+/// FNV-1a hash of the bytes, mixed by the finalizer of MurmurHash3.  It's stable across
+/// processes and versions, unlike the hasher of std.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl KvsEngine for ShardedClient {
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.pool_of(&key).set_bytes(key, val)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.pool_of(&key).set_bytes_with_ttl(key, val, ttl)
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.pool_of(key).ttl_bytes(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.pool_of(key).get_bytes(key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.pool_of(key).remove_bytes(key)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.pool_of(&key)
+            .compare_and_swap_bytes(key, expected, new)
+    }
+
+    // the batch is split by servers, each part is applied atomically by its server.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut batches: Vec<WriteBatch> = vec![WriteBatch::new(); self.inner.pools.len()];
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => batches[self.shard_of(&key)].set(key, value),
+                BatchOp::Rm { key } => batches[self.shard_of(&key)].remove(key),
+            };
+        }
+        thread::scope(|scope| {
+            let handles: Vec<_> = batches
+                .into_iter()
+                .zip(&self.inner.pools)
+                .filter(|(batch, _)| !batch.is_empty())
+                .map(|(batch, pool)| scope.spawn(move || pool.write_batch(batch)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Shard thread panicked"))
+                .collect::<Vec<Result<()>>>()
+        })
+        .into_iter()
+        .collect()
+    }
+
+    // writes are flushed by servers before they are answered.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan_bytes<'a, R>(&'a self, range: R) -> Result<BytesScanIter<'a>>
+    where
+        R: RangeBounds<Vec<u8>> + 'a,
+    {
+        let start: Bound<Vec<u8>> = range.start_bound().cloned();
+        let end: Bound<Vec<u8>> = range.end_bound().cloned();
+        let scans: Vec<BytesScanIter<'a>> = self
+            .inner
+            .pools
+            .iter()
+            .map(|pool| pool.scan_bytes((start.clone(), end.clone())))
+            .collect::<Result<_>>()?;
+        Ok(Box::new(MergedScan::new(self, scans)))
+    }
+}
+
+/// Pairs of scans of all servers, in key order.
+///
+/// A key is on more than one server while it's being moved by `rebalance`, the pair of the
+/// server it belongs to is returned then.
+struct MergedScan<'a> {
+    client: &'a ShardedClient,
+    scans: Vec<BytesScanIter<'a>>,
+    // the next pair of each scan, `None` once the scan ends.
+    heads: Vec<Option<Result<Pair>>>,
+}
+
+impl<'a> MergedScan<'a> {
+    fn new(client: &'a ShardedClient, mut scans: Vec<BytesScanIter<'a>>) -> MergedScan<'a> {
+        let heads: Vec<Option<Result<Pair>>> = scans.iter_mut().map(|scan| scan.next()).collect();
+        MergedScan {
+            client,
+            scans,
+            heads,
+        }
+    }
+
+    fn advance(&mut self, shard: usize) -> Option<Result<Pair>> {
+        let next: Option<Result<Pair>> = self.scans[shard].next();
+        std::mem::replace(&mut self.heads[shard], next)
+    }
+}
+
+impl<'a> Iterator for MergedScan<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(shard) = self
+            .heads
+            .iter()
+            .position(|head| matches!(head, Some(Err(_))))
+        {
+            return self.advance(shard);
+        }
+        let key: Vec<u8> = self
+            .heads
+            .iter()
+            .filter_map(|head| match head {
+                Some(Ok((key, _))) => Some(key),
+                _ => None,
+            })
+            .min()?
+            .clone();
+        let shards: Vec<usize> = (0..self.heads.len())
+            .filter(|&shard| matches!(&self.heads[shard], Some(Ok((k, _))) if *k == key))
+            .collect();
+        let owner: usize = self.client.shard_of(&key);
+        let chosen: usize = if shards.contains(&owner) {
+            owner
+        } else {
+            shards[0]
+        };
+        let mut pair: Option<Self::Item> = None;
+        for shard in shards {
+            let head: Option<Self::Item> = self.advance(shard);
+            if shard == chosen {
+                pair = head;
+            }
+        }
+        pair
+    }
+}
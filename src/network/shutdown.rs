@@ -0,0 +1,140 @@
+use log::{debug, error};
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Handle to stop a running `Server`, it can be cloned and sent to other threads.
+///
+/// After `shutdown` is called, the server stops accepting connections, requests which are
+/// already received are still handled, then `Server::serve_forever` returns once all
+/// connections are closed.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    shutdown: AtomicBool,
+    // addresses of the listeners, they are connected to wake up blocking `accept` calls.
+    addrs: Mutex<Vec<SocketAddr>>,
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+    drained: Condvar,
+}
+
+/// Connection which is accepted by the server, it's untracked when dropped.
+pub(crate) struct Connection {
+    id: u64,
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::new(ShutdownState {
+                shutdown: AtomicBool::new(false),
+                addrs: Mutex::new(Vec::new()),
+                connections: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                drained: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Stop the server, it's ok to call it more than once.
+    pub fn shutdown(&self) {
+        if self.state.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        debug!("Shutting down the server...");
+        for addr in self.state.addrs.lock().expect("Lock addrs failed.").iter() {
+            if let Err(e) = TcpStream::connect(wake_addr(*addr)) {
+                error!("Wake up listener {} failed, reason: {:?}", addr, e);
+            }
+        }
+        // peers are taken as closed after the received data is read.
+        let connections = self
+            .state
+            .connections
+            .lock()
+            .expect("Lock connections failed.");
+        for stream in connections.values() {
+            stream.shutdown(Shutdown::Read).unwrap_or(());
+        }
+    }
+
+    /// Check if `shutdown` is called.
+    pub fn is_shutdown(&self) -> bool {
+        self.state.shutdown.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn add_listener(&self, addr: SocketAddr) {
+        self.state
+            .addrs
+            .lock()
+            .expect("Lock addrs failed.")
+            .push(addr);
+    }
+
+    /// Track the connection until the returned `Connection` is dropped.
+    pub(crate) fn track(&self, stream: &TcpStream) -> std::io::Result<Connection> {
+        let stream: TcpStream = stream.try_clone()?;
+        let id: u64 = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut connections = self
+            .state
+            .connections
+            .lock()
+            .expect("Lock connections failed.");
+        // the flag is checked with the lock held, so the connection can't be missed by `shutdown`.
+        if self.is_shutdown() {
+            stream.shutdown(Shutdown::Read).unwrap_or(());
+        }
+        connections.insert(id, stream);
+        Ok(Connection {
+            id,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Block until all tracked connections are closed.
+    pub(crate) fn wait_drained(&self) {
+        let mut connections = self
+            .state
+            .connections
+            .lock()
+            .expect("Lock connections failed.");
+        while !connections.is_empty() {
+            connections = self
+                .state
+                .drained
+                .wait(connections)
+                .expect("Lock connections failed.");
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self
+            .state
+            .connections
+            .lock()
+            .expect("Lock connections failed.");
+        connections.remove(&self.id);
+        if connections.is_empty() {
+            self.state.drained.notify_all();
+        }
+    }
+}
+
+// A listener on the unspecified address can't be connected directly, use the loopback instead.
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip([127, 0, 0, 1].into()),
+            SocketAddr::V6(_) => addr.set_ip(std::net::Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
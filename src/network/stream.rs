@@ -0,0 +1,95 @@
+use rustls::Connection;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Connection between a client and a server, it's plain TCP, or TLS over TCP.
+///
+/// Clones share the same connection, so it can be split into a reader and a writer like
+/// `TcpStream`.  TLS state is shared by a lock, so a blocking read of a clone blocks writes of
+/// others, reads and writes should be done by the same thread.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls {
+        tls: Arc<Mutex<Connection>>,
+        sock: TcpStream,
+    },
+}
+
+impl Stream {
+    /// Wrap the TCP stream with the TLS connection, the handshake is done by the first read or
+    /// write.
+    pub(crate) fn new_tls(sock: TcpStream, tls: Connection) -> Stream {
+        Stream::Tls {
+            tls: Arc::new(Mutex::new(tls)),
+            sock,
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(sock) => Ok(Stream::Tcp(sock.try_clone()?)),
+            Stream::Tls { tls, sock } => Ok(Stream::Tls {
+                tls: tls.clone(),
+                sock: sock.try_clone()?,
+            }),
+        }
+    }
+
+    /// The underlying TCP stream.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(sock) | Stream::Tls { sock, .. } => sock,
+        }
+    }
+}
+
+fn lock(tls: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    tls.lock().expect("Lock TLS connection failed.")
+}
+
+// `rustls::Stream` works on client or server connections, but not on `Connection`.
+macro_rules! tls_stream {
+    ($tls:expr, $sock:expr, |$stream:ident| $body:expr) => {
+        match &mut *lock($tls) {
+            Connection::Client(conn) => {
+                let mut $stream = rustls::Stream::new(conn, $sock);
+                $body
+            }
+            Connection::Server(conn) => {
+                let mut $stream = rustls::Stream::new(conn, $sock);
+                $body
+            }
+        }
+    };
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.read(buf),
+            Stream::Tls { tls, sock } => match tls_stream!(tls, sock, |stream| stream.read(buf)) {
+                // peers may close the connection without close_notify, it's fine because frames
+                // are checked by their length.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.write(buf),
+            Stream::Tls { tls, sock } => tls_stream!(tls, sock, |stream| stream.write(buf)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(sock) => sock.flush(),
+            Stream::Tls { tls, sock } => tls_stream!(tls, sock, |stream| stream.flush()),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! TLS of connections between `Client` and `Server`, by rustls.
+//!
+//! Certificates and keys are loaded from PEM files.  The server can require clients to present
+//! certificates as well, that's mutual TLS.
+use super::stream::Stream;
+use crate::error::{KvsError, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+/// TLS settings of a `Server`.
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Serve with the certificate chain and the private key in the PEM files.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> Result<ServerTls> {
+        ServerTls::build(cert, key, None)
+    }
+
+    /// Same as `from_pem_files`, and clients must present certificates which are signed by the
+    /// CAs in `client_ca`.
+    pub fn with_client_auth(cert: &Path, key: &Path, client_ca: &Path) -> Result<ServerTls> {
+        ServerTls::build(cert, key, Some(client_ca))
+    }
+
+    fn build(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerTls> {
+        let provider: Arc<CryptoProvider> = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(load_roots(client_ca)?, provider)
+                        .build()
+                        .map_err(|e| KvsError::from_string(&format!("Invalid client CA: {}", e)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config: ServerConfig =
+            builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        // sessions are never resumed, and tickets sent after the handshake would be taken as
+        // data by `ClientPool`, which checks if idle connections are closed by the server.
+        config.send_tls13_tickets = 0;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Wrap the accepted TCP stream, the handshake is done by the first read.
+    pub(crate) fn accept(&self, sock: TcpStream) -> Result<Stream> {
+        let conn: ServerConnection = ServerConnection::new(self.config.clone())?;
+        Ok(Stream::new_tls(sock, conn.into()))
+    }
+}
+
+/// TLS settings of a `Client`.
+///
+/// The server certificate must be valid for the host of the address connected to, which is a
+/// domain name or an IP address.
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    /// Trust servers whose certificates are signed by the CAs in the PEM file.
+    pub fn from_pem_file(ca: &Path) -> Result<ClientTls> {
+        let config: ClientConfig = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca)?)
+            .with_no_client_auth();
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Same as `from_pem_file`, and present the certificate chain and the private key in the PEM
+    /// files to the server, for servers which require client certificates.
+    pub fn with_identity(ca: &Path, cert: &Path, key: &Path) -> Result<ClientTls> {
+        let config: ClientConfig = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca)?)
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Wrap the connected TCP stream, `addr` is the address connected to, in the form of
+    /// `HOST:PORT`, the host is verified by the server certificate.
+    pub(crate) fn connect(&self, addr: &str, sock: TcpStream) -> Result<Stream> {
+        let host: &str = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host: &str = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name: ServerName<'static> = ServerName::try_from(host.to_owned())
+            .map_err(|e| KvsError::from_string(&format!("Invalid server name {}: {}", host, e)))?;
+        let conn: ClientConnection = ClientConnection::new(self.config.clone(), server_name)?;
+        Ok(Stream::new_tls(sock, conn.into()))
+    }
+}
+
+// Use ring explicitly, so it doesn't depend on the default provider of the process.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(KvsError::from_string(&format!(
+            "No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots: RootCertStore = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> KvsError {
+    KvsError::from_string(&format!("Invalid PEM file {}: {}", path.display(), e))
+}
@@ -0,0 +1,218 @@
+//! Notifications of key changes.
+//!
+//! Writes are published to the `WatchHub` of the server once they are applied to the engine,
+//! by the leader, by replicas as they follow the leader, and by every node of a cluster as
+//! entries are committed.  A client sends `Instruction::Watch`, then the connection streams a
+//! `Response` for every change of keys with the prefix, whose body is the `WatchEvent` encoded by
+//! bincode, and a `Response` with an empty body as a heartbeat if nothing changes for a while.
+//!
+//! Keys which expire are not published, they are only removed once they are read.
+use super::auth::Session;
+use super::client::Client;
+use super::frame::write_frame;
+use super::Response;
+use crate::command::{BatchOp, Instruction};
+use crate::error::{KvsError, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// how many events are buffered for a watcher, it's dropped if it lags more.
+const WATCH_BUFFER_SIZE: usize = 4096;
+// watchers receive heartbeats if nothing changes, so the server can tell if they are gone.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Change of a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The key is set to the value.
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// The key is removed.
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// The changed key.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// Watchers of a server, writes are published to those whose prefix matches.
+#[derive(Clone)]
+pub(crate) struct WatchHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+struct HubState {
+    watchers: Vec<(Vec<u8>, SyncSender<WatchEvent>)>,
+    // watchers are disconnected once the server is stopped.
+    closed: bool,
+}
+
+impl WatchHub {
+    pub(crate) fn new() -> WatchHub {
+        WatchHub {
+            state: Arc::new(Mutex::new(HubState {
+                watchers: Vec::new(),
+                closed: false,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HubState> {
+        self.state.lock().expect("Lock watch hub failed.")
+    }
+
+    /// Receive changes of keys with the prefix, returns `None` if the hub is closed.
+    fn subscribe(&self, prefix: &[u8]) -> Option<Receiver<WatchEvent>> {
+        let mut state: MutexGuard<HubState> = self.lock();
+        if state.closed {
+            return None;
+        }
+        let (sender, receiver) = mpsc::sync_channel(WATCH_BUFFER_SIZE);
+        state.watchers.push((prefix.to_vec(), sender));
+        Some(receiver)
+    }
+
+    /// Publish changes of a write which is applied, other instructions are ignored.
+    ///
+    /// It never blocks, watchers which are gone or lag too far are dropped.
+    pub(crate) fn publish(&self, instruction: &Instruction) {
+        let mut state: MutexGuard<HubState> = self.lock();
+        if state.watchers.is_empty() {
+            return;
+        }
+        let events: Vec<WatchEvent> = match instruction {
+            Instruction::Set { key, value, .. } => vec![WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }],
+            Instruction::Rm { key } => vec![WatchEvent::Remove { key: key.clone() }],
+            Instruction::Batch { batch } => batch
+                .ops()
+                .iter()
+                .map(|op| match op {
+                    BatchOp::Set { key, value } => WatchEvent::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                    BatchOp::Rm { key } => WatchEvent::Remove { key: key.clone() },
+                })
+                .collect(),
+            _ => return,
+        };
+        state.watchers.retain(|(prefix, sender)| {
+            events
+                .iter()
+                .filter(|event| event.key().starts_with(prefix))
+                .all(|event| match sender.try_send(event.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        debug!("Watcher lags too far, it's dropped");
+                        false
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                })
+        });
+    }
+
+    fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Disconnect all watchers, following subscriptions fail.
+    pub(crate) fn close(&self) {
+        let mut state: MutexGuard<HubState> = self.lock();
+        state.closed = true;
+        state.watchers.clear();
+    }
+}
+
+/// Answer `Instruction::Watch` of a client, then stream changes of keys with the prefix which
+/// the session can read, until the hub is closed or the client is gone.
+pub(crate) fn serve_watcher<W: Write>(
+    hub: &WatchHub,
+    prefix: &[u8],
+    session: &Session,
+    writer: &mut W,
+) -> Result<()> {
+    let events: Receiver<WatchEvent> = match hub.subscribe(prefix) {
+        Some(events) => events,
+        None => return Ok(()),
+    };
+    write_frame(writer, &Response::new_ok())?;
+    writer.flush()?;
+    loop {
+        match events.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => {
+                // events which are already buffered are sent together.
+                for event in Some(event).into_iter().chain(events.try_iter()) {
+                    if session.can_read(event.key()) {
+                        let body: Vec<u8> = bincode::serialize(&event)?;
+                        write_frame(writer, &Response::new_ok_with_body(body))?;
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => write_frame(writer, &Response::new_ok())?,
+            Err(RecvTimeoutError::Disconnected) => {
+                if !hub.is_closed() {
+                    let error: KvsError =
+                        KvsError::invalid_request("Events are dropped, the watcher lags too far");
+                    write_frame(writer, &Response::from_error(&error))?;
+                    writer.flush()?;
+                }
+                return Ok(());
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+/// Changes of keys streamed by a server, it's returned by `Client::watch`.
+///
+/// The iterator blocks until the next change, and ends once the server closes the connection.
+/// The server sends heartbeats every second, so a read timeout of the client longer than that
+/// only expires if the server is gone.
+pub struct Watcher {
+    client: Client,
+}
+
+impl Watcher {
+    pub(crate) fn new(client: Client) -> Watcher {
+        Watcher { client }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let response: Response = match self.client.read_message() {
+                Ok(Some(response)) => response,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            match response.into_result() {
+                // heartbeat.
+                Ok(body) if body.is_empty() => continue,
+                Ok(body) => return Some(bincode::deserialize(&body).map_err(KvsError::from)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
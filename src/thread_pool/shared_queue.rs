@@ -14,7 +14,7 @@ struct Sentinel<'a> {
 }
 
 impl<'a> Sentinel<'a> {
-    pub fn new(shared_data: &Arc<SharedData>) -> Sentinel {
+    pub fn new(shared_data: &Arc<SharedData>) -> Sentinel<'_> {
         Sentinel { shared_data, active: true }
     }
 
@@ -0,0 +1,201 @@
+#![cfg(feature = "async")]
+
+use kvs::command::Instruction;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{
+    AsyncClient, AsyncKvsEngine, AsyncServer, Client, KvStore, KvsEngine, Result, Server,
+    SledKvsEngine, WriteBatch,
+};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start an async server on a random port in background, it runs until the test exits.
+async fn start_async_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let server = AsyncServer::bind("127.0.0.1:0", KvStore::open(temp_dir.path())?).await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(async move { server.serve_forever().await.unwrap() });
+    Ok(addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_async_server(&temp_dir).await?;
+    let mut client = AsyncClient::connect(addr).await?;
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+    assert!(client
+        .remove("key2".to_owned())
+        .await
+        .unwrap_err()
+        .is_key_not_found());
+    assert!(
+        !client
+            .compare_and_swap_bytes(b"key1".to_vec(), None, Some(b"value2".to_vec()))
+            .await?
+    );
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1");
+    client.write_batch(batch).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    client
+        .set_bytes_with_ttl(
+            b"key3".to_vec(),
+            b"value3".to_vec(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    assert_eq!(client.get_bytes(b"key3").await?, Some(b"value3".to_vec()));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(client.get_bytes(b"key3").await?, None);
+    Ok(())
+}
+
+// A frame longer than the limit closes the connection before its payload is read.
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_frame() -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_async_server(&temp_dir).await?;
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.write_all(&u32::MAX.to_le_bytes()).await?;
+    assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+
+    let mut client = AsyncClient::connect(addr).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// The async server and client speak the same protocol as the sync ones.
+#[tokio::test(flavor = "multi_thread")]
+async fn mix_sync_and_async() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let async_addr = start_async_server(&temp_dir).await?;
+    let sync_result = tokio::task::spawn_blocking(move || -> Result<Option<String>> {
+        let client = Client::connect(&async_addr.to_string())?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        let mut client2 = client.clone();
+        // pipelined requests are answered in order.
+        let responses = client2.pipeline(&[
+            Instruction::Get {
+                key: b"key1".to_vec(),
+            },
+            Instruction::Get {
+                key: b"key2".to_vec(),
+            },
+        ])?;
+        assert_eq!(responses[0].get_body(), b"value1");
+        assert!(!responses[1].is_ok());
+        client.get("key1".to_owned())
+    })
+    .await
+    .unwrap()?;
+    assert_eq!(sync_result, Some("value1".to_owned()));
+
+    let sync_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Server::new(
+        "127.0.0.1:0",
+        KvStore::open(sync_dir.path())?,
+        NaiveThreadPool::new(4)?,
+    )?;
+    let sync_addr = server.local_addr()?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    let mut client = AsyncClient::connect(sync_addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Idle connections don't hold threads, so many of them can be served at the same time.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn many_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_async_server(&temp_dir).await?;
+
+    let mut clients: Vec<AsyncClient> = Vec::new();
+    for _ in 0..500 {
+        clients.push(AsyncClient::connect(addr).await?);
+    }
+    let mut tasks = Vec::new();
+    for (i, mut client) in clients.into_iter().enumerate() {
+        tasks.push(tokio::spawn(async move {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+            client.get(format!("key{}", i)).await
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_engine_adapter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(SledKvsEngine::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    engine
+        .set_bytes(b"key2".to_vec(), b"value2".to_vec())
+        .await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(
+        engine
+            .compare_and_swap_bytes(
+                b"key1".to_vec(),
+                Some(b"value1".to_vec()),
+                Some(b"value3".to_vec())
+            )
+            .await?
+    );
+    assert_eq!(
+        engine.scan_prefix_bytes(b"key".to_vec()).await?,
+        vec![
+            (b"key1".to_vec(), b"value3".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+    engine.remove("key1".to_owned()).await?;
+    assert!(engine
+        .remove_bytes(b"key1".to_vec())
+        .await
+        .unwrap_err()
+        .is_key_not_found());
+    engine.flush().await?;
+    assert_eq!(
+        engine.engine().get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    // futures are spawned even if the engine is not `Sync`.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+    let task = tokio::spawn(engine.set("key1".to_owned(), "value1".to_owned()));
+    task.await.unwrap()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
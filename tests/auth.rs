@@ -0,0 +1,295 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{new_server, spawn_server};
+use kvs::command::Instruction;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{
+    AuthConfig, Client, ClientConfig, ClientPool, Credentials, ErrorCode, KvStore, KvsEngine,
+    Protocol, Result, Server, WriteBatch,
+};
+use predicates::str::contains;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const AUTH_CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "admin",
+            "password": "admin-secret",
+            "rules": [{"prefix": "", "access": "read_write"}]
+        },
+        {
+            "name": "billing",
+            "password": "billing-secret",
+            "rules": [{"prefix": "billing/", "access": "read_write"}]
+        },
+        {
+            "name": "reports",
+            "token": "reports-token",
+            "rules": [
+                {"prefix": "billing/", "access": "read"},
+                {"prefix": "reports/", "access": "write"}
+            ]
+        }
+    ]
+}"#;
+
+fn write_config(temp_dir: &TempDir) -> PathBuf {
+    let path = temp_dir.path().join("auth.json");
+    fs::write(&path, AUTH_CONFIG).unwrap();
+    path
+}
+
+// Start a server which requires authentication on a random port in background, it runs until
+// the test exits.
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut server = new_server(temp_dir)?;
+    server.set_auth(AuthConfig::from_file(&write_config(temp_dir))?);
+    spawn_server(server)
+}
+
+fn password(user: &str, password: &str) -> Credentials {
+    Credentials::Password {
+        user: user.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+fn connect(addr: SocketAddr, credentials: Credentials) -> Result<Client> {
+    let config = ClientConfig {
+        credentials: Some(credentials),
+        ..ClientConfig::default()
+    };
+    Client::connect_with(&addr.to_string(), &config)
+}
+
+fn is_denied<T>(result: Result<T>) -> bool {
+    matches!(result, Err(ref e) if e.code() == ErrorCode::Unauthorized)
+}
+
+// Clients must log in before any request.
+#[test]
+fn login_required() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    let mut client = Client::connect(&addr.to_string())?;
+    assert!(is_denied(client.get("billing/a".to_owned())));
+    client.send_instruction(&Instruction::Rm {
+        key: b"billing/a".to_vec(),
+    })?;
+    let response = client.read_response()?;
+    assert!(response.is_denied());
+    assert_eq!(response.get_code(), Some(ErrorCode::Unauthorized));
+
+    assert!(is_denied(client.login(&password("billing", "wrong"))));
+    assert!(is_denied(
+        client.login(&password("nobody", "billing-secret"))
+    ));
+    assert!(is_denied(client.login(&Credentials::Token(String::new()))));
+    assert!(is_denied(connect(
+        addr,
+        password("admin", "billing-secret")
+    )));
+
+    client.login(&password("billing", "billing-secret"))?;
+    client.set("billing/a".to_owned(), "1".to_owned())?;
+    // a failed login logs out the former user.
+    assert!(is_denied(client.login(&password("billing", "wrong"))));
+    assert!(is_denied(client.get("billing/a".to_owned())));
+    Ok(())
+}
+
+// Requests are checked by ACLs of key prefixes.
+#[test]
+fn prefix_acls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let billing = connect(addr, password("billing", "billing-secret"))?;
+    let reports = connect(addr, Credentials::Token("reports-token".to_owned()))?;
+
+    billing.set("billing/a".to_owned(), "1".to_owned())?;
+    assert_eq!(billing.get("billing/a".to_owned())?, Some("1".to_owned()));
+    assert!(is_denied(
+        billing.set("reports/a".to_owned(), "1".to_owned())
+    ));
+    assert!(is_denied(billing.get("reports/a".to_owned())));
+    assert!(is_denied(billing.remove("billing".to_owned())));
+
+    // read only, and write only prefixes.
+    assert_eq!(reports.get("billing/a".to_owned())?, Some("1".to_owned()));
+    assert!(is_denied(
+        reports.set("billing/a".to_owned(), "2".to_owned())
+    ));
+    assert!(is_denied(reports.remove("billing/a".to_owned())));
+    reports.set("reports/a".to_owned(), "1".to_owned())?;
+    assert!(is_denied(reports.get("reports/a".to_owned())));
+    // compare-and-swap needs both read and write.
+    assert!(is_denied(reports.compare_and_swap(
+        "billing/a".to_owned(),
+        Some("1".to_owned()),
+        Some("2".to_owned())
+    )));
+    assert!(is_denied(reports.compare_and_swap(
+        "reports/a".to_owned(),
+        Some("1".to_owned()),
+        Some("2".to_owned())
+    )));
+    assert!(billing.compare_and_swap(
+        "billing/a".to_owned(),
+        Some("1".to_owned()),
+        Some("2".to_owned())
+    )?);
+
+    // batches are denied as a whole.
+    let mut batch = WriteBatch::new();
+    batch.set("billing/b", "1").set("reports/b", "1");
+    assert!(is_denied(billing.write_batch(batch)));
+    assert_eq!(billing.get("billing/b".to_owned())?, None);
+    Ok(())
+}
+
+// Scans only return keys which can be read.
+#[test]
+fn scan_readable_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let admin = connect(addr, password("admin", "admin-secret"))?;
+    for i in 0..200 {
+        admin.set(format!("billing/{:03}", i), i.to_string())?;
+        admin.set(format!("other/{:03}", i), i.to_string())?;
+        admin.set(format!("reports/{:03}", i), i.to_string())?;
+    }
+    assert_eq!(admin.scan_prefix("")?.count(), 600);
+
+    let billing = connect(addr, password("billing", "billing-secret"))?;
+    let keys: Vec<String> = billing
+        .scan_prefix("")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    let expected: Vec<String> = (0..200).map(|i| format!("billing/{:03}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let reports = connect(addr, Credentials::Token("reports-token".to_owned()))?;
+    assert_eq!(reports.scan_prefix("reports/")?.count(), 0);
+    assert_eq!(reports.scan_prefix("")?.count(), 200);
+
+    let anonymous = Client::connect(&addr.to_string())?;
+    assert!(anonymous.scan_prefix("")?.all(is_denied));
+    Ok(())
+}
+
+// `ClientPool` logs in for each connection.
+#[test]
+fn client_pool_login() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let config = ClientConfig {
+        credentials: Some(password("billing", "billing-secret")),
+        ..ClientConfig::default()
+    };
+    let pool = ClientPool::new(&addr.to_string(), config);
+    let clones: Vec<thread::JoinHandle<Result<()>>> = (0..4)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for j in 0..20 {
+                    pool.set(format!("billing/{}-{}", i, j), j.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for clone in clones {
+        clone.join().unwrap()?;
+    }
+    assert_eq!(pool.scan_prefix("billing/")?.count(), 80);
+    assert!(is_denied(pool.get("other/a".to_owned())));
+    Ok(())
+}
+
+// Authentication is only supported by the kvs protocol, and config files are checked.
+#[test]
+fn invalid_auth_setups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(1)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)?;
+    server.set_auth(AuthConfig::from_file(&write_config(&temp_dir))?);
+    server.set_protocol(Protocol::Resp);
+    assert!(server.serve_forever().is_err());
+
+    let path = temp_dir.path().join("invalid.json");
+    fs::write(&path, r#"{"users": [{"name": "a", "rules": []}]}"#)?;
+    assert!(AuthConfig::from_file(&path).is_err());
+    fs::write(
+        &path,
+        r#"{"users": [{"name": "a", "token": "t", "rules": [{"prefix": "", "access": "all"}]}]}"#,
+    )?;
+    assert!(AuthConfig::from_file(&path).is_err());
+    assert!(AuthConfig::from_file(&temp_dir.path().join("missing.json")).is_err());
+
+    // an open server accepts any login.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(1)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    let client = connect(addr, Credentials::Token("anything".to_owned()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// `kvs-client` logs in with `--user` and `--password`, or `--token`.
+#[test]
+fn cli_login() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = write_config(&temp_dir);
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4013", "--auth-config"])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "billing/a", "1", "--addr", "127.0.0.1:4013"])
+        .args(["--user", "billing", "--password", "billing-secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "billing/a", "--addr", "127.0.0.1:4013"])
+        .args(["--token", "reports-token"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "billing/a", "--addr", "127.0.0.1:4013"])
+        .args(["--token", "reports-token"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "billing/a", "--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
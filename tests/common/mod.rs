@@ -0,0 +1,47 @@
+//! Fixtures shared by integration tests, each test crate uses some of them.
+#![allow(dead_code)]
+
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result, Server};
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A server of the store in the directory, it listens on a random port.
+pub fn new_server(temp_dir: &TempDir) -> Result<Server<KvStore, NaiveThreadPool>> {
+    let pool = NaiveThreadPool::new(4)?;
+    Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)
+}
+
+// Serve in background, the server runs until the test exits.
+pub fn spawn_server<E: KvsEngine>(mut server: Server<E, NaiveThreadPool>) -> Result<SocketAddr> {
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    Ok(addr)
+}
+
+// Start a server on a random port in background, it runs until the test exits.
+pub fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    spawn_server(new_server(temp_dir)?)
+}
+
+// Wait for at most 10 seconds until the condition holds.
+pub fn wait_until<F: FnMut() -> bool>(mut condition: F) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("Condition is not met in time");
+}
+
+// Copy one direction of a proxied connection, the other side is closed once it ends.
+pub fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        io::copy(&mut from, &mut to).unwrap_or(0);
+        to.shutdown(Shutdown::Both).unwrap_or(());
+    });
+}
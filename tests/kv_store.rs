@@ -1,6 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +212,691 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Readers should always see a valid value while compaction replaces the log file.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..10000 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    // Overwrite with the same values, so compaction is triggered several times.
+    for _ in 0..300 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Commands written while compaction runs in background should survive reopening.
+#[test]
+fn compaction_keeps_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        for key_id in iter..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let expected = if key_id < 50 {
+            None
+        } else {
+            Some("49".to_owned())
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    files.sort();
+    files
+}
+
+// A half-written record at the end of the log should be dropped on open.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&log_file)?.len();
+    let file = OpenOptions::new().write(true).open(&log_file)?;
+    file.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // New commands are appended after the last valid record.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A record with mismatched checksum and everything after it should be dropped on open.
+#[test]
+fn recover_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len_before = fs::metadata(log_files(temp_dir.path()).pop().unwrap())?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the second record.
+    let log_file = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&log_file)?;
+    let len_after = fs::metadata(&log_file)?.len();
+    let second_record_end = len_before + (len_after - len_before) / 2;
+    content[second_record_end as usize - 1] ^= 0xff;
+    fs::write(&log_file, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Log written by old versions is newline delimited JSON, a torn last line should be dropped.
+#[test]
+fn recover_torn_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.db"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n{\"Set\":{\"key\":\"key2\",\"val",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Framed JSON log written by old versions should be upgraded on open.
+#[test]
+fn upgrade_framed_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut content = b"KVSLOG01".to_vec();
+    for payload in &[
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}",
+        "{\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}",
+        "{\"Rm\":{\"key\":\"key1\"}}",
+    ] {
+        content.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        content.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_le_bytes());
+        content.extend_from_slice(payload.as_bytes());
+    }
+    fs::write(temp_dir.path().join("1.log"), content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVSLOG03"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Bincode log written before keys can expire should be upgraded on open.
+#[test]
+fn upgrade_log_without_expire_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut content = b"KVSLOG02".to_vec();
+    let mut set_payload = 1u32.to_le_bytes().to_vec();
+    for field in &["key1", "value1"] {
+        set_payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        set_payload.extend_from_slice(field.as_bytes());
+    }
+    content.extend_from_slice(&(set_payload.len() as u32).to_le_bytes());
+    content.extend_from_slice(&crc32fast::hash(&set_payload).to_le_bytes());
+    content.extend_from_slice(&set_payload);
+    fs::write(temp_dir.path().join("1.log"), content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVSLOG03"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn hint_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect()
+}
+
+// Compaction writes hint files, the store should open with valid, stale or missing hints.
+#[test]
+fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..30 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("29".to_owned()));
+        }
+        Ok(())
+    };
+
+    let hints = hint_files(temp_dir.path());
+    assert!(!hints.is_empty());
+    check()?;
+
+    // stale hint file is ignored.
+    for hint in &hints {
+        let len = fs::metadata(hint)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(hint)?
+            .set_len(len / 2)?;
+    }
+    check()?;
+
+    // missing hint file is ignored too.
+    for hint in &hints {
+        fs::remove_file(hint)?;
+    }
+    check()?;
+
+    Ok(())
+}
+
+fn scan_in_key_order(engine: impl KvsEngine) -> Result<()> {
+    for key in &["b2", "a1", "b1", "c1", "b3", "ba"] {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+    engine.remove("b3".to_owned())?;
+
+    let pairs = engine
+        .scan("a1".to_owned().."b3".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["a1", "b1", "b2"]);
+    assert_eq!(pairs[1].1, "value-b1");
+
+    let keys: Vec<String> = engine
+        .scan("b".to_owned()..)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["b1", "b2", "ba", "c1"]);
+
+    let keys: Vec<String> = engine
+        .scan_prefix("b")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["b1", "b2", "ba"]);
+    assert_eq!(engine.scan_prefix("d")?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn kvs_scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_in_key_order(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_in_key_order(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn binary_keys_and_values(engine: impl KvsEngine) -> Result<()> {
+    let key: Vec<u8> = vec![0, 159, 146, 150];
+    let value: Vec<u8> = vec![255, 0, 10, 13, 128];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(b"text".to_vec(), b"value".to_vec())?;
+
+    assert_eq!(engine.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+
+    let pairs = engine
+        .scan_prefix_bytes(&[0])?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![(key.clone(), value)]);
+    // the string api fails on non UTF-8 data.
+    assert!(engine.scan(..)?.any(|pair| pair.is_err()));
+
+    engine.remove_bytes(&key)?;
+    assert!(engine.remove_bytes(&key).is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![1, 2, 255], vec![0, 0])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[1, 2, 255])?, Some(vec![0, 0]));
+    Ok(())
+}
+
+#[test]
+fn sled_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn write_batch_all_or_nothing(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .remove("key1")
+        .set("key3", "value3");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Removing a missing key fails the whole batch.
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key2").remove("key1");
+    assert!(engine.write_batch(batch).is_err());
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+
+    // Keys set earlier in the batch can be removed.
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key5", "value5")
+        .remove("key5")
+        .set(vec![0, 255], vec![1]);
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key5".to_owned())?, None);
+    assert_eq!(engine.get_bytes(&[0, 255])?, Some(vec![1]));
+    Ok(())
+}
+
+#[test]
+fn kvs_write_batch_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_all_or_nothing(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get_bytes(&[0, 255])?, Some(vec![1]));
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_all_or_nothing(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A torn batch record should be dropped entirely on open.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .remove("key1")
+        .set("key3", "value3");
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log_file = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&log_file)?.len();
+    let file = OpenOptions::new().write(true).open(&log_file)?;
+    file.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Values written by batches should survive compaction.
+#[test]
+fn compaction_keeps_batch_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2000 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        store.write_batch(batch)?;
+    }
+    drop(store);
+    assert!(!hint_files(temp_dir.path()).is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("1999".to_owned())
+        );
+    }
+    Ok(())
+}
+
+fn expire_keys_with_ttl(engine: impl KvsEngine) -> Result<()> {
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    engine.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    let ttl = engine.ttl_bytes(b"key2")?.expect("key2 should expire");
+    assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+    // Setting a key again without ttl makes it persistent.
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.ttl_bytes(b"key3")?, None);
+    assert_eq!(engine.ttl_bytes(b"missing")?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.ttl_bytes(b"key1")?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    let keys = engine
+        .scan_prefix("key")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key2", "key3"]);
+
+    // An expired key can't be removed, but it can be set again.
+    engine.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(engine.remove("key4".to_owned()).is_err());
+    engine.set("key4".to_owned(), "value5".to_owned())?;
+    assert_eq!(engine.get("key4".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys_with_ttl(KvStore::open(temp_dir.path())?)?;
+
+    // Expire times survive restarts.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key5".to_owned(),
+        "value5".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys_with_ttl(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Expired entries should be dropped by compaction.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for key_id in 0..1000 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            value.clone(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("persistent".to_owned(), value.clone())?;
+    thread::sleep(Duration::from_millis(200));
+
+    for iter in 0..11000 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let dir_size: u64 = log_files(temp_dir.path())
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(dir_size < 200 * 1024, "logs take {} bytes", dir_size);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("persistent".to_owned())?, Some(value));
+    assert_eq!(store.get("counter".to_owned())?, Some("10999".to_owned()));
+    Ok(())
+}
+
+fn compare_and_swap_values(engine: impl KvsEngine) -> Result<()> {
+    assert!(engine.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(!engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value2".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, Some("value3".to_owned()))?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Swapping to `None` removes the key.
+    assert!(engine.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.compare_and_swap("key1".to_owned(), None, None)?);
+
+    // Expired keys are taken as absent.
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(engine.set_if_absent("key2".to_owned(), "value3".to_owned())?);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_compare_and_swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_values(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_values(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A swapped value should keep the expire time of the key.
+fn compare_and_swap_keeps_ttl(engine: impl KvsEngine) -> Result<()> {
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert!(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    let ttl = engine.ttl_bytes(b"key1")?.expect("key1 should expire");
+    assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+
+    // The swapped value still expires.
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(engine.compare_and_swap(
+        "key2".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_compare_and_swap_keeps_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_keeps_ttl(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_compare_and_swap_keeps_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_keeps_ttl(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Concurrent increments by compare-and-swap should not lose updates.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let barrier = Arc::new(Barrier::new(9));
+    for _ in 0..8 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                loop {
+                    let current = store.get("counter".to_owned()).unwrap().unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    if store
+                        .compare_and_swap("counter".to_owned(), Some(current), Some(next))
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+            }
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
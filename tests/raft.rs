@@ -0,0 +1,633 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{pipe, wait_until};
+use kvs::command::{Instruction, RaftMessage};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{
+    Client, ClientConfig, ClusterClient, ClusterHandle, Engine, ErrorCode, KvStore, KvsEngine,
+    RaftConfig, Result, Server, ShutdownHandle, SledKvsEngine, WriteBatch,
+};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Forward connections from one node to another, so the network between them can be cut.
+struct Link {
+    addr: SocketAddr,
+    blocked: Arc<AtomicBool>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Link {
+    fn start(target: SocketAddr) -> Link {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let blocked = Arc::new(AtomicBool::new(false));
+        let streams: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+        let (accept_blocked, accepted) = (blocked.clone(), streams.clone());
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                if accept_blocked.load(Ordering::SeqCst) {
+                    continue;
+                }
+                // the target may be stopped.
+                let server = match TcpStream::connect(target) {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                let mut accepted = accepted.lock().unwrap();
+                accepted.push(client.try_clone().unwrap());
+                accepted.push(server.try_clone().unwrap());
+                pipe(client.try_clone().unwrap(), server.try_clone().unwrap());
+                pipe(server, client);
+            }
+        });
+        Link {
+            addr,
+            blocked,
+            streams,
+        }
+    }
+
+    // Refuse new connections and close forwarded ones, or let them through again.
+    fn set_blocked(&self, blocked: bool) {
+        self.blocked.store(blocked, Ordering::SeqCst);
+        if blocked {
+            for stream in self.streams.lock().unwrap().drain(..) {
+                stream.shutdown(Shutdown::Both).unwrap_or(());
+            }
+        }
+    }
+}
+
+struct Node {
+    dir: TempDir,
+    addr: SocketAddr,
+    running: Option<Running>,
+}
+
+struct Running {
+    handle: ClusterHandle,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+// Server of a node, of either engine.
+enum NodeServer {
+    Kvs(Server<KvStore, NaiveThreadPool>),
+    Sled(Server<SledKvsEngine, NaiveThreadPool>),
+}
+
+impl NodeServer {
+    fn new(engine: &Engine, dir: &TempDir, addr: &str) -> Result<NodeServer> {
+        let pool = NaiveThreadPool::new(8)?;
+        Ok(match engine {
+            Engine::Kvs => NodeServer::Kvs(Server::new(addr, KvStore::open(dir.path())?, pool)?),
+            Engine::Sled => {
+                NodeServer::Sled(Server::new(addr, SledKvsEngine::open(dir.path())?, pool)?)
+            }
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            NodeServer::Kvs(server) => server.local_addr(),
+            NodeServer::Sled(server) => server.local_addr(),
+        }
+    }
+
+    fn run(self, config: RaftConfig) -> Result<Running> {
+        match self {
+            NodeServer::Kvs(server) => run_node(server, config),
+            NodeServer::Sled(server) => run_node(server, config),
+        }
+    }
+}
+
+// Join the cluster and serve in background.
+fn run_node<E: KvsEngine>(
+    mut server: Server<E, NaiveThreadPool>,
+    config: RaftConfig,
+) -> Result<Running> {
+    let handle = server.set_cluster(config)?;
+    let shutdown = server.shutdown_handle();
+    Ok(Running {
+        handle,
+        shutdown,
+        thread: thread::spawn(move || server.serve_forever()),
+    })
+}
+
+// Nodes of a cluster in this process, node `i` connects to node `j` by `links[i][j]`.
+struct Cluster {
+    engine: Engine,
+    nodes: Vec<Node>,
+    links: Vec<Vec<Option<Link>>>,
+    snapshot_entries: Option<u64>,
+}
+
+impl Cluster {
+    fn start(size: usize) -> Result<Cluster> {
+        Cluster::start_with(Engine::Kvs, size, None)
+    }
+
+    fn start_with(engine: Engine, size: usize, snapshot_entries: Option<u64>) -> Result<Cluster> {
+        let mut servers: Vec<NodeServer> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        for _ in 0..size {
+            let dir = TempDir::new().expect("unable to create temporary working directory");
+            let server = NodeServer::new(&engine, &dir, "127.0.0.1:0")?;
+            nodes.push(Node {
+                addr: server.local_addr()?,
+                dir,
+                running: None,
+            });
+            servers.push(server);
+        }
+        let links: Vec<Vec<Option<Link>>> = (0..size)
+            .map(|i| {
+                (0..size)
+                    .map(|j| Some(Link::start(nodes[j].addr)).filter(|_| i != j))
+                    .collect()
+            })
+            .collect();
+        let mut cluster = Cluster {
+            engine,
+            nodes,
+            links,
+            snapshot_entries,
+        };
+        for (id, server) in servers.into_iter().enumerate() {
+            cluster.serve(id, server)?;
+        }
+        Ok(cluster)
+    }
+
+    fn config(&self, id: usize) -> RaftConfig {
+        let nodes: Vec<String> = self.links[id]
+            .iter()
+            .enumerate()
+            .map(|(j, link)| match link {
+                Some(link) => link.addr.to_string(),
+                None => self.nodes[j].addr.to_string(),
+            })
+            .collect();
+        let mut config = RaftConfig::new(nodes, id, &self.nodes[id].dir.path().join("raft"));
+        if let Some(snapshot_entries) = self.snapshot_entries {
+            config.snapshot_entries = snapshot_entries;
+        }
+        config
+    }
+
+    fn serve(&mut self, id: usize, server: NodeServer) -> Result<()> {
+        self.nodes[id].running = Some(server.run(self.config(id))?);
+        Ok(())
+    }
+
+    fn restart(&mut self, id: usize) -> Result<()> {
+        let node = &self.nodes[id];
+        let server = NodeServer::new(&self.engine, &node.dir, &node.addr.to_string())?;
+        self.serve(id, server)
+    }
+
+    fn stop(&mut self, id: usize) -> Result<()> {
+        let running = self.nodes[id].running.take().unwrap();
+        running.shutdown.shutdown();
+        running.thread.join().unwrap()
+    }
+
+    fn handle(&self, id: usize) -> &ClusterHandle {
+        &self.nodes[id].running.as_ref().unwrap().handle
+    }
+
+    // Wait until a running node is the leader of the latest term.
+    fn leader(&self) -> usize {
+        let mut leader: usize = 0;
+        wait_until(|| {
+            let running = self.running();
+            let term = running.iter().map(|&id| self.handle(id).term()).max();
+            match running
+                .into_iter()
+                .find(|&id| self.handle(id).is_leader() && Some(self.handle(id).term()) == term)
+            {
+                Some(id) => {
+                    leader = id;
+                    true
+                }
+                None => false,
+            }
+        });
+        leader
+    }
+
+    fn running(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&id| self.nodes[id].running.is_some())
+            .collect()
+    }
+
+    // Addresses which reach the node, clients may be redirected to it by links of other nodes.
+    fn addrs_of(&self, id: usize) -> Vec<String> {
+        let mut addrs: Vec<String> = vec![self.nodes[id].addr.to_string()];
+        addrs.extend(
+            self.links
+                .iter()
+                .flat_map(|links| &links[id])
+                .map(|link| link.addr.to_string()),
+        );
+        addrs
+    }
+
+    // Cut the network between the node and others, or heal it.
+    fn isolate(&self, id: usize, isolated: bool) {
+        for j in 0..self.nodes.len() {
+            for link in [&self.links[id][j], &self.links[j][id]]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                link.set_blocked(isolated);
+            }
+        }
+    }
+
+    // Wait until logs of running nodes are the same and committed.
+    fn wait_converged(&self) {
+        wait_until(|| {
+            let running = self.running();
+            let last = self.handle(running[0]).last_index();
+            running.iter().all(|&id| {
+                self.handle(id).last_index() == last && self.handle(id).commit_index() == last
+            })
+        });
+    }
+
+    fn client(&self) -> ClusterClient {
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|node| node.addr.to_string())
+            .collect();
+        let config = ClientConfig {
+            connect_timeout: Some(Duration::from_secs(1)),
+            read_timeout: Some(Duration::from_secs(5)),
+            retries: 10,
+            ..ClientConfig::default()
+        };
+        ClusterClient::new(nodes, config)
+    }
+}
+
+fn new_server(dir: &TempDir, addr: &str) -> Result<Server<KvStore, NaiveThreadPool>> {
+    let pool = NaiveThreadPool::new(8)?;
+    Server::new(addr, KvStore::open(dir.path())?, pool)
+}
+
+// A leader is elected, writes of all kinds are replicated to every node.
+#[test]
+fn elect_and_replicate() -> Result<()> {
+    let cluster = Cluster::start(3)?;
+    let leader = cluster.leader();
+    let client = cluster.client();
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(client.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("swapped".to_owned())
+    )?);
+    assert!(!client.compare_and_swap("key2".to_owned(), Some("wrong".to_owned()), None)?);
+    let mut batch = WriteBatch::new();
+    batch.set("batch1", "1").remove("key3");
+    client.write_batch(batch)?;
+    client.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    assert!(cluster.addrs_of(leader).contains(&client.leader().unwrap()));
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("swapped".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.get("batch1".to_owned())?, Some("1".to_owned()));
+    assert_eq!(client.scan_prefix("key")?.count(), 98);
+    assert_eq!(client.get("ttl".to_owned())?, Some("value".to_owned()));
+    cluster.wait_converged();
+    Ok(())
+}
+
+// Followers redirect clients to the leader, for reads as well.
+#[test]
+fn redirect_to_leader() -> Result<()> {
+    let cluster = Cluster::start(3)?;
+    let leader = cluster.leader();
+    let follower = (leader + 1) % 3;
+    let client = Client::connect(&cluster.nodes[follower].addr.to_string())?;
+    let error = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::NotLeader);
+    // the follower knows the leader by its link.
+    let hint = error.leader().unwrap().to_owned();
+    assert_eq!(
+        hint,
+        cluster.links[follower][leader]
+            .as_ref()
+            .unwrap()
+            .addr
+            .to_string()
+    );
+    let error = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(error.code(), ErrorCode::NotLeader);
+
+    let redirected = Client::connect(&hint)?;
+    redirected.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        redirected.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    // a server which is not in a cluster rejects Raft messages of other nodes.
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = new_server(&dir, "127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    let mut client = Client::connect(&addr.to_string())?;
+    let message = RaftMessage::RequestVote {
+        term: 1,
+        candidate: 0,
+        last_log_index: 0,
+        last_log_term: 0,
+    };
+    assert!(client.execute(&Instruction::Raft { message }).is_err());
+
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RaftConfig::new(vec![addr.to_string()], 1, dir.path());
+    assert!(new_server(&dir, "127.0.0.1:0")?
+        .set_cluster(config)
+        .is_err());
+    Ok(())
+}
+
+// A partitioned leader steps down, the majority elects a new one, and the old leader catches up
+// once the partition heals.
+#[test]
+fn leader_partitioned() -> Result<()> {
+    let cluster = Cluster::start(5)?;
+    let old_leader = cluster.leader();
+    let client = cluster.client();
+    for i in 0..50 {
+        client.set(format!("key{}", i), "old".to_owned())?;
+    }
+
+    cluster.isolate(old_leader, true);
+    // writes to the isolated leader can't be committed.
+    let isolated = Client::connect(&cluster.nodes[old_leader].addr.to_string())?;
+    assert!(isolated.set("key0".to_owned(), "lost".to_owned()).is_err());
+    wait_until(|| !cluster.handle(old_leader).is_leader());
+
+    // the client may know the old leader by a cut link, writes on broken connections fail
+    // rather than being retried, then it finds the new leader.
+    wait_until(|| client.set("key0".to_owned(), "new".to_owned()).is_ok());
+    for i in 0..50 {
+        client.set(format!("key{}", i), "new".to_owned())?;
+    }
+    let new_leader = cluster.leader();
+    assert_ne!(new_leader, old_leader);
+    assert!(cluster
+        .addrs_of(new_leader)
+        .contains(&client.leader().unwrap()));
+
+    cluster.isolate(old_leader, false);
+    cluster.leader();
+    cluster.wait_converged();
+    let pairs: Vec<(String, String)> = client.scan_prefix("key")?.collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 50);
+    assert!(pairs.iter().all(|(_, value)| value == "new"));
+    Ok(())
+}
+
+// Nodes keep their engines and Raft logs after restarts, a stopped follower catches up.
+#[test]
+fn restart_nodes() -> Result<()> {
+    let mut cluster = Cluster::start(3)?;
+    let leader = cluster.leader();
+    let client = cluster.client();
+    for i in 0..100 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+
+    let follower = (leader + 1) % 3;
+    cluster.stop(follower)?;
+    for i in 0..100 {
+        client.remove(format!("key{}", i))?;
+        client.set(format!("other{}", i), i.to_string())?;
+    }
+    cluster.restart(follower)?;
+    cluster.wait_converged();
+
+    for id in 0..3 {
+        cluster.stop(id)?;
+    }
+    for id in 0..3 {
+        cluster.restart(id)?;
+    }
+    cluster.leader();
+    assert_eq!(client.scan_prefix("key")?.count(), 0);
+    assert_eq!(client.scan_prefix("other")?.count(), 100);
+    assert_eq!(client.get("other42".to_owned())?, Some("42".to_owned()));
+    client.set("key1".to_owned(), "again".to_owned())?;
+    cluster.wait_converged();
+    Ok(())
+}
+
+// Logs are compacted once entries are applied, a follower which lags behind the snapshot of the
+// leader receives it, keys which are removed meanwhile are removed from it too.
+fn compact_and_install_snapshot(engine: Engine) -> Result<()> {
+    let mut cluster = Cluster::start_with(engine, 3, Some(10))?;
+    let leader = cluster.leader();
+    let client = cluster.client();
+    for i in 0..50 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+
+    let follower = (leader + 1) % 3;
+    cluster.stop(follower)?;
+    for i in 0..50 {
+        client.remove(format!("key{}", i))?;
+        client.set(format!("other{}", i), i.to_string())?;
+    }
+    wait_until(|| cluster.handle(leader).snapshot_index() > 100);
+    cluster.restart(follower)?;
+    cluster.wait_converged();
+    assert!(cluster.handle(follower).snapshot_index() > 100);
+
+    for id in 0..3 {
+        cluster.stop(id)?;
+    }
+    let dir = cluster.nodes[follower].dir.path();
+    let counts = match cluster.engine {
+        Engine::Kvs => count_keys(KvStore::open(dir)?)?,
+        Engine::Sled => count_keys(SledKvsEngine::open(dir)?)?,
+    };
+    assert_eq!(counts, (0, 50));
+    for id in 0..3 {
+        cluster.restart(id)?;
+    }
+    cluster.leader();
+    assert_eq!(client.get("other42".to_owned())?, Some("42".to_owned()));
+    Ok(())
+}
+
+// How many keys of the stopped node start with "key" and "other".
+fn count_keys(engine: impl KvsEngine) -> Result<(usize, usize)> {
+    Ok((
+        engine.scan_prefix("key")?.count(),
+        engine.scan_prefix("other")?.count(),
+    ))
+}
+
+#[test]
+fn kvs_compact_and_install_snapshot() -> Result<()> {
+    compact_and_install_snapshot(Engine::Kvs)
+}
+
+#[test]
+fn sled_compact_and_install_snapshot() -> Result<()> {
+    compact_and_install_snapshot(Engine::Sled)
+}
+
+// A snapshot which is taken while compare-and-swaps go on is the engine at its index, so the
+// follower which receives it applies entries after it as the leader does.
+#[test]
+fn install_snapshot_with_compare_and_swaps() -> Result<()> {
+    let mut cluster = Cluster::start_with(Engine::Kvs, 3, Some(1000))?;
+    let leader = cluster.leader();
+    let follower = (leader + 1) % 3;
+    cluster.stop(follower)?;
+    // keys before the swapped ones, so the snapshot is sent in a few chunks.
+    let client = cluster.client();
+    for i in 0..1500 {
+        client.set(format!("bulk{}", i), i.to_string())?;
+    }
+
+    // each writer counts up by swaps, and tries a swap which expects the value two swaps ahead.
+    // It fails on the leader, but it succeeds on a follower whose snapshot is newer than its
+    // index, then later swaps fail there too.
+    let stopped = Arc::new(AtomicBool::new(false));
+    let writers: Vec<JoinHandle<Result<()>>> = (0..4)
+        .map(|t| {
+            let client = cluster.client();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let key = format!("cas{}", t);
+                client.set(key.clone(), "0".to_owned())?;
+                let mut count: u64 = 0;
+                while !stopped.load(Ordering::SeqCst) {
+                    let next = Some((count + 1).to_string());
+                    assert!(client.compare_and_swap(key.clone(), Some(count.to_string()), next)?);
+                    let ahead = Some((count + 2).to_string());
+                    let stale = Some("stale".to_owned());
+                    assert!(!client.compare_and_swap(key.clone(), ahead, stale)?);
+                    count += 1;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let restarted_at = cluster.handle(leader).commit_index();
+    cluster.restart(follower)?;
+    wait_until(|| cluster.handle(follower).commit_index() > restarted_at + 100);
+    stopped.store(true, Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    // the follower may still be receiving a snapshot, it appends entries once it's installed.
+    wait_until(|| {
+        client.set("last".to_owned(), "write".to_owned()).unwrap();
+        cluster.handle(follower).commit_index() > cluster.handle(follower).snapshot_index()
+    });
+    cluster.wait_converged();
+
+    for id in 0..3 {
+        cluster.stop(id)?;
+    }
+    let swapped = pairs_of(KvStore::open(cluster.nodes[leader].dir.path())?)?;
+    assert_eq!(swapped.len(), 4);
+    assert_eq!(
+        pairs_of(KvStore::open(cluster.nodes[follower].dir.path())?)?,
+        swapped
+    );
+    Ok(())
+}
+
+// Pairs of the stopped node which are written by compare-and-swaps.
+fn pairs_of(engine: impl KvsEngine) -> Result<Vec<(String, String)>> {
+    engine.scan_prefix("cas")?.collect()
+}
+
+// `kvs-server --cluster` joins a cluster, followers answer `kvs-client` with the leader.
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4016", "127.0.0.1:4017", "127.0.0.1:4018"];
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<std::process::Child> = addrs
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr, "--cluster", &addrs.join(",")])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let set = |addr: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(&dirs[0])
+            .output()
+            .unwrap()
+    };
+    let mut leader: usize = 0;
+    wait_until(|| match (0..3).find(|&i| set(addrs[i]).status.success()) {
+        Some(i) => {
+            leader = i;
+            true
+        }
+        None => false,
+    });
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addrs[leader]])
+        .current_dir(&dirs[0])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addrs[(leader + 1) % 3]])
+        .current_dir(&dirs[0])
+        .assert()
+        .failure()
+        .stderr(contains("NotLeader").and(contains(addrs[leader])));
+
+    for server in servers.iter_mut() {
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{new_server, pipe, spawn_server, wait_until};
+use kvs::command::Instruction;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{
+    AuthConfig, Client, ClientConfig, Credentials, ErrorCode, KvStore, KvsEngine, ReplicaStatus,
+    Result, Server, ShutdownHandle, SledKvsEngine, WriteBatch,
+};
+use predicates::str::contains;
+use std::fs;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct Leader {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Leader {
+    fn start(temp_dir: &TempDir, addr: &str) -> Result<Leader> {
+        let pool = NaiveThreadPool::new(4)?;
+        let mut server = Server::new(addr, KvStore::open(temp_dir.path())?, pool)?;
+        Ok(Leader {
+            addr: server.local_addr()?,
+            shutdown: server.shutdown_handle(),
+            thread: thread::spawn(move || server.serve_forever()),
+        })
+    }
+
+    fn stop(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.thread.join().unwrap()
+    }
+}
+
+// Start a replica of the leader on a random port in background, it runs until the test exits.
+fn start_replica(
+    temp_dir: &TempDir,
+    leader: SocketAddr,
+    config: ClientConfig,
+) -> Result<(SocketAddr, ReplicaStatus)> {
+    let mut server = new_server(temp_dir)?;
+    let status = server.set_replica_of(&leader.to_string(), config);
+    Ok((spawn_server(server)?, status))
+}
+
+fn wait_value(client: &Client, key: &str, value: Option<&str>) {
+    wait_until(|| client.get(key.to_owned()).unwrap().as_deref() == value);
+}
+
+// Forward connections to the target, so they can be cut in the middle.
+struct Proxy {
+    addr: SocketAddr,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn start(target: SocketAddr) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let streams: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+        let accepted = streams.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = TcpStream::connect(target).unwrap();
+                let mut accepted = accepted.lock().unwrap();
+                accepted.push(client.try_clone().unwrap());
+                accepted.push(server.try_clone().unwrap());
+                pipe(client.try_clone().unwrap(), server.try_clone().unwrap());
+                pipe(server, client);
+            }
+        });
+        Proxy { addr, streams }
+    }
+
+    // Close all forwarded connections.
+    fn cut(&self) {
+        for stream in self.streams.lock().unwrap().drain(..) {
+            stream.shutdown(Shutdown::Both).unwrap_or(());
+        }
+    }
+}
+
+// A replica receives a snapshot of the leader, then writes of the leader.
+#[test]
+fn snapshot_and_records() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = Leader::start(&leader_dir, "127.0.0.1:0")?;
+    let client = Client::connect(&leader.addr.to_string())?;
+    for i in 0..3000 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    client.set_with_ttl("ttl".to_owned(), "value".to_owned(), Duration::from_secs(2))?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, status) = start_replica(&replica_dir, leader.addr, ClientConfig::default())?;
+    wait_until(|| status.snapshots() == 1);
+    assert!(status.is_connected());
+    let replica = Client::connect(&addr.to_string())?;
+    assert_eq!(replica.scan_prefix("key")?.count(), 2999);
+    assert_eq!(replica.get("key0".to_owned())?, None);
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(replica.get("ttl".to_owned())?, Some("value".to_owned()));
+
+    // records of all kinds of writes.
+    client.set("key1".to_owned(), "new".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert!(client.compare_and_swap(
+        "key3".to_owned(),
+        Some("value3".to_owned()),
+        Some("swapped".to_owned())
+    )?);
+    assert!(!client.compare_and_swap("key4".to_owned(), None, Some("x".to_owned()))?);
+    let mut batch = WriteBatch::new();
+    batch.set("batch", "1").remove("key5");
+    client.write_batch(batch)?;
+    wait_value(&replica, "batch", Some("1"));
+    assert_eq!(replica.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+    assert_eq!(replica.get("key3".to_owned())?, Some("swapped".to_owned()));
+    assert_eq!(replica.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(replica.get("key5".to_owned())?, None);
+    // writes before the replica is attached are not recorded, the log starts after them.
+    assert_eq!(status.offset(), 5);
+
+    // keys expire at the same time as on the leader.
+    wait_value(&replica, "ttl", None);
+    assert_eq!(client.get("ttl".to_owned())?, None);
+    Ok(())
+}
+
+// Replicas of sled receive snapshots and records of a sled leader, keys which they don't have
+// yet are absent rather than errors.
+#[test]
+fn sled_snapshot_and_records() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let server = Server::new("127.0.0.1:0", SledKvsEngine::open(leader_dir.path())?, pool)?;
+    let leader_addr = spawn_server(server)?;
+    let client = Client::connect(&leader_addr.to_string())?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let mut server = Server::new(
+        "127.0.0.1:0",
+        SledKvsEngine::open(replica_dir.path())?,
+        pool,
+    )?;
+    let status = server.set_replica_of(&leader_addr.to_string(), ClientConfig::default());
+    let replica = Client::connect(&spawn_server(server)?.to_string())?;
+    wait_until(|| status.snapshots() == 1);
+    assert_eq!(replica.scan_prefix("key")?.count(), 99);
+    assert_eq!(replica.get("key0".to_owned())?, None);
+
+    client.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("batch", "1").remove("key2").remove("batch");
+    client.write_batch(batch)?;
+    client.set("key3".to_owned(), "new".to_owned())?;
+    wait_value(&replica, "key3", Some("new"));
+    assert_eq!(replica.get("key1".to_owned())?, None);
+    assert_eq!(replica.get("key2".to_owned())?, None);
+    assert_eq!(replica.get("batch".to_owned())?, None);
+    Ok(())
+}
+
+// Replicas reject writes, and they can't be followed.
+#[test]
+fn read_only_replica() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = Leader::start(&leader_dir, "127.0.0.1:0")?;
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, status) = start_replica(&replica_dir, leader.addr, ClientConfig::default())?;
+    wait_until(|| status.snapshots() == 1);
+
+    let mut replica = Client::connect(&addr.to_string())?;
+    let result = replica.set("key1".to_owned(), "value1".to_owned());
+    assert!(matches!(result, Err(ref e) if e.code() == ErrorCode::InvalidRequest));
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1");
+    assert!(replica.write_batch(batch).is_err());
+    assert!(replica
+        .execute(&Instruction::Replicate {
+            log_id: None,
+            offset: 0,
+        })
+        .is_err());
+    assert_eq!(replica.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A replica resumes from it's offset after reconnecting, without another snapshot.
+#[test]
+fn resume_after_reconnect() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = Leader::start(&leader_dir, "127.0.0.1:0")?;
+    let proxy = Proxy::start(leader.addr);
+    let client = Client::connect(&leader.addr.to_string())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, status) = start_replica(&replica_dir, proxy.addr, ClientConfig::default())?;
+    let replica = Client::connect(&addr.to_string())?;
+    wait_until(|| status.snapshots() == 1);
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    wait_value(&replica, "key2", Some("value2"));
+
+    proxy.cut();
+    wait_until(|| !status.is_connected());
+    for i in 3..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key1".to_owned())?;
+    wait_until(|| status.offset() == 100);
+    assert_eq!(status.snapshots(), 1);
+    assert_eq!(replica.get("key1".to_owned())?, None);
+    assert_eq!(replica.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// A restarted leader has a new log, so the replica receives a snapshot again, and removes keys
+// which are not in it.
+#[test]
+fn snapshot_after_leader_restart() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = Leader::start(&leader_dir, "127.0.0.1:0")?;
+    let leader_addr = leader.addr;
+    let client = Client::connect(&leader_addr.to_string())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, status) = start_replica(&replica_dir, leader_addr, ClientConfig::default())?;
+    let replica = Client::connect(&addr.to_string())?;
+    wait_until(|| status.snapshots() == 1);
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    leader.stop()?;
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(leader_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let _leader = Leader::start(&leader_dir, &leader_addr.to_string())?;
+
+    wait_until(|| status.snapshots() == 2);
+    assert_eq!(replica.get("key1".to_owned())?, None);
+    assert_eq!(replica.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Replicas of a leader which requires authentication log in as users which can read all keys.
+#[test]
+fn replicate_with_auth() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = leader_dir.path().join("auth.json");
+    fs::write(
+        &path,
+        r#"{"users": [
+            {"name": "replica", "token": "all", "rules": [{"prefix": "", "access": "read"}]},
+            {"name": "billing", "token": "billing", "rules": [{"prefix": "billing/", "access": "read_write"}]}
+        ]}"#,
+    )?;
+    let pool = NaiveThreadPool::new(4)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(leader_dir.path())?, pool)?;
+    server.set_auth(AuthConfig::from_file(&path)?);
+    let leader_addr = server.local_addr()?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    let config = |token: &str| ClientConfig {
+        credentials: Some(Credentials::Token(token.to_owned())),
+        ..ClientConfig::default()
+    };
+    let client = Client::connect_with(&leader_addr.to_string(), &config("billing"))?;
+    client.set("billing/a".to_owned(), "1".to_owned())?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, status) = start_replica(&replica_dir, leader_addr, config("billing"))?;
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, allowed) = start_replica(&replica_dir, leader_addr, config("all"))?;
+    let replica = Client::connect(&addr.to_string())?;
+    wait_value(&replica, "billing/a", Some("1"));
+    assert_eq!(allowed.snapshots(), 1);
+    assert_eq!(status.snapshots(), 0);
+    assert!(!status.is_connected());
+    Ok(())
+}
+
+// `kvs-server --replica-of` follows the leader.
+#[test]
+fn cli_replica_of() {
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4014"])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4015", "--replica-of", "127.0.0.1:4014"])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4014"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success();
+    wait_until(|| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", "127.0.0.1:4015"])
+            .current_dir(&replica_dir)
+            .output()
+            .unwrap()
+            .stdout
+            == b"value1\n"
+    });
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value2", "--addr", "127.0.0.1:4015"])
+        .current_dir(&replica_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Read-only replica"));
+
+    replica.kill().expect("server exited before killed");
+    replica.wait().unwrap();
+    leader.kill().expect("server exited before killed");
+    leader.wait().unwrap();
+}
+
+#[test]
+fn cli_replica_of_with_auth() {
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    fs::write(
+        leader_dir.path().join("auth.json"),
+        r#"{"users": [
+            {"name": "replica", "token": "all", "rules": [{"prefix": "", "access": "read"}]},
+            {"name": "admin", "token": "admin", "rules": [{"prefix": "", "access": "read_write"}]}
+        ]}"#,
+    )
+    .unwrap();
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4022", "--auth-config", "auth.json"])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4023", "--replica-of", "127.0.0.1:4022"])
+        .args(["--replica-token", "all"])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .args(["--token", "admin"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success();
+    wait_until(|| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", "127.0.0.1:4023"])
+            .current_dir(&replica_dir)
+            .output()
+            .unwrap()
+            .stdout
+            == b"value1\n"
+    });
+
+    replica.kill().expect("server exited before killed");
+    replica.wait().unwrap();
+    leader.kill().expect("server exited before killed");
+    leader.wait().unwrap();
+}
@@ -0,0 +1,770 @@
+mod common;
+
+use common::{new_server, spawn_server, start_server};
+use kvs::command::Instruction;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{
+    Client, ClientConfig, ClientPool, ErrorCode, KvStore, KvsEngine, Protocol, Response, Result,
+    Server, WriteBatch,
+};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+// Same as `start_server`, but the server speaks RESP.
+fn start_resp_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut server = new_server(temp_dir)?;
+    server.set_protocol(Protocol::Resp);
+    spawn_server(server)
+}
+
+fn frame(instruction: &Instruction) -> Vec<u8> {
+    let payload = serde_json::to_vec(instruction).unwrap();
+    let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// `Client` works like a local engine.
+#[test]
+fn client_as_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let client = Client::connect(&addr.to_string())?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(client
+        .remove("key2".to_owned())
+        .unwrap_err()
+        .is_key_not_found());
+
+    assert!(!client.compare_and_swap("key1".to_owned(), Some("other".to_owned()), None)?);
+    assert!(client.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert!(!client.set_if_absent("key1".to_owned(), "value3".to_owned())?);
+    assert!(client.set_if_absent("key2".to_owned(), "value2".to_owned())?);
+
+    client.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get("key3".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1").set("key4", "value4");
+    client.write_batch(batch)?;
+    // clones share the connection.
+    let other = client.clone();
+    assert_eq!(other.get("key1".to_owned())?, None);
+    assert_eq!(other.get("key4".to_owned())?, Some("value4".to_owned()));
+    other.remove("key4".to_owned())?;
+    client.remove("key2".to_owned())?;
+    Ok(())
+}
+
+// Clients send the ttl, expire times of older clients are still accepted.
+#[test]
+fn ttl_on_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = Client::connect(&addr.to_string())?;
+
+    client.send_instruction(&Instruction::SetWithTtl {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+        ttl_ms: 100,
+    })?;
+    assert!(client.read_response()?.is_ok());
+    let expire_at: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    client.send_instruction(&Instruction::Set {
+        key: b"key2".to_vec(),
+        value: b"value2".to_vec(),
+        expire_at: Some(expire_at + 100),
+    })?;
+    assert!(client.read_response()?.is_ok());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Scans are fetched page by page.
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let client = Client::connect(&addr.to_string())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 0..300 {
+        batch.set(format!("a{:03}", i), format!("value{}", i));
+    }
+    batch.set("b", "value");
+    client.write_batch(batch)?;
+
+    let pairs: Vec<(String, String)> = client
+        .scan_prefix("a")?
+        .collect::<Result<Vec<(String, String)>>>()?;
+    assert_eq!(pairs.len(), 300);
+    for (i, (key, value)) in pairs.iter().enumerate() {
+        assert_eq!(key, &format!("a{:03}", i));
+        assert_eq!(value, &format!("value{}", i));
+    }
+
+    let keys: Vec<String> = client
+        .scan("a128".to_owned()..="a130".to_owned())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<String>>>()?;
+    assert_eq!(keys, vec!["a128", "a129", "a130"]);
+    let keys: Vec<String> = client
+        .scan("a299".to_owned()..)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<String>>>()?;
+    assert_eq!(keys, vec!["a299", "b"]);
+    assert_eq!(client.scan(.."a".to_owned())?.count(), 0);
+    Ok(())
+}
+
+// Connections are reused, and they are replaced once the server is restarted.
+#[test]
+fn client_pool_reconnect() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Server::new(
+        "127.0.0.1:0",
+        KvStore::open(temp_dir.path())?,
+        NaiveThreadPool::new(4)?,
+    )?;
+    let addr = server.local_addr()?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.serve_forever());
+
+    let pool = ClientPool::new(&addr.to_string(), ClientConfig::default());
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(pool.idle_connections(), 1);
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    let mut server = Server::new(
+        addr,
+        KvStore::open(temp_dir.path())?,
+        NaiveThreadPool::new(4)?,
+    )?;
+    thread::spawn(move || server.serve_forever().unwrap());
+
+    // the idle connection is closed by the old server, a write is sent over a new one.
+    pool.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(pool.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(pool.idle_connections(), 1);
+    Ok(())
+}
+
+// A server which never answers doesn't block the client forever.
+#[test]
+fn client_pool_timeout() -> Result<()> {
+    // connections are accepted by the OS, but nothing is read or written.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let config = ClientConfig {
+        read_timeout: Some(Duration::from_millis(100)),
+        retries: 2,
+        backoff: Duration::from_millis(10),
+        ..ClientConfig::default()
+    };
+    let pool = ClientPool::new(&listener.local_addr()?.to_string(), config);
+
+    let start = Instant::now();
+    assert!(pool.get("key1".to_owned()).is_err());
+    // the first try and 2 retries.
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(pool.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(pool.idle_connections(), 0);
+    Ok(())
+}
+
+// Errors are sent with their codes, and the client restores them.
+#[test]
+fn error_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = Client::connect(&addr.to_string())?;
+
+    client.send_instruction(&Instruction::Get {
+        key: b"key1".to_vec(),
+    })?;
+    let response: Response = client.read_response()?;
+    assert!(!response.is_ok());
+    assert_eq!(response.get_code(), Some(ErrorCode::NotFound));
+    assert_eq!(response.get_message(), "Key not found");
+
+    let error = client
+        .execute(&Instruction::Rm {
+            key: b"key1".to_vec(),
+        })
+        .unwrap_err();
+    assert!(error.is_key_not_found());
+
+    client.execute(&Instruction::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+        expire_at: None,
+    })?;
+    assert_eq!(
+        client.execute(&Instruction::Get {
+            key: b"key1".to_vec(),
+        })?,
+        b"value1"
+    );
+    let error = client
+        .execute(&Instruction::CompareAndSwap {
+            key: b"key1".to_vec(),
+            expected: Some(b"other".to_vec()),
+            new: None,
+        })
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::Conflict);
+    Ok(())
+}
+
+// Responses of old servers have no error code, their errors are internal ones.
+#[test]
+fn error_code_of_old_response() -> Result<()> {
+    let response: Response =
+        serde_json::from_str(r#"{"status":"ERROR","message":"Key not found","body":[]}"#)?;
+    assert_eq!(response.get_code(), Some(ErrorCode::Internal));
+    // statuses only tell if the request succeeds, the kind of error is told by the code.
+    let response: Response = serde_json::from_str(
+        r#"{"status":"ERROR","message":"Value is changed","code":"Conflict","body":[]}"#,
+    )?;
+    assert!(response.is_conflict() && !response.is_ok());
+    let response: Response = serde_json::from_str(r#"{"status":"OK","message":"","body":[]}"#)?;
+    assert_eq!(response.into_result()?, b"");
+    Ok(())
+}
+
+// Values of several megabytes should be sent and returned intact.
+#[test]
+fn large_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = Client::connect(&addr.to_string())?;
+
+    let key: Vec<u8> = (0..16 * 1024).map(|i| (i % 251) as u8).collect();
+    let value: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 241) as u8).collect();
+    client.send_instruction(&Instruction::Set {
+        key: key.clone(),
+        value: value.clone(),
+        expire_at: None,
+    })?;
+    assert!(client.read_response()?.is_ok());
+
+    client.send_instruction(&Instruction::Get { key: key.clone() })?;
+    let response: Response = client.read_response()?;
+    assert!(response.is_ok());
+    assert_eq!(response.get_body(), value.as_slice());
+
+    // Another connection sees the value as well.
+    let mut client = Client::connect(&addr.to_string())?;
+    client.send_instruction(&Instruction::Get { key })?;
+    assert_eq!(client.read_response()?.get_body(), value.as_slice());
+    Ok(())
+}
+
+// A frame longer than the limit closes the connection before its payload is read.
+#[test]
+fn oversized_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&u32::MAX.to_le_bytes())?;
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    let client = Client::connect(&addr.to_string())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Messages coalesced into a single write, or split across writes, should be handled one by one.
+#[test]
+fn multiple_messages_per_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut stream = TcpStream::connect(addr)?;
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for (key, value) in &[("key1", "value1"), ("key2", "value2")] {
+        bytes.extend(frame(&Instruction::Set {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            expire_at: None,
+        }));
+    }
+    bytes.extend(frame(&Instruction::Get {
+        key: b"key2".to_vec(),
+    }));
+    let (first, second) = bytes.split_at(5);
+    stream.write_all(first)?;
+    stream.flush()?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(second)?;
+
+    let mut responses: Vec<Response> = Vec::new();
+    let mut buf: Vec<u8> = Vec::new();
+    while responses.len() < 3 {
+        let mut chunk = [0; 1024];
+        let bytes = stream.read(&mut chunk)?;
+        assert!(bytes > 0, "connection closed by server");
+        buf.extend_from_slice(&chunk[..bytes]);
+        while buf.len() >= 4 {
+            let mut len = [0; 4];
+            len.copy_from_slice(&buf[..4]);
+            let len = u32::from_le_bytes(len) as usize;
+            if buf.len() < 4 + len {
+                break;
+            }
+            responses.push(serde_json::from_slice(&buf[4..4 + len])?);
+            buf.drain(..4 + len);
+        }
+    }
+    assert!(responses.iter().all(Response::is_ok));
+    assert_eq!(responses[2].get_body(), b"value2");
+    Ok(())
+}
+
+// Pipelined instructions should be answered in order over one connection.
+#[test]
+fn pipeline_instructions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = Client::connect(&addr.to_string())?;
+
+    let sets: Vec<Instruction> = (0..1000)
+        .map(|i| Instruction::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+            expire_at: None,
+        })
+        .collect();
+    let responses = client.pipeline(&sets)?;
+    assert_eq!(responses.len(), 1000);
+    assert!(responses.iter().all(Response::is_ok));
+
+    let mut instructions: Vec<Instruction> = (0..1000)
+        .rev()
+        .map(|i| Instruction::Get {
+            key: format!("key{}", i).into_bytes(),
+        })
+        .collect();
+    instructions.push(Instruction::Rm {
+        key: b"missing".to_vec(),
+    });
+    let responses = client.pipeline(&instructions)?;
+    for (i, response) in (0..1000).rev().zip(responses.iter()) {
+        assert_eq!(response.get_body(), format!("value{}", i).as_bytes());
+    }
+    assert!(!responses[1000].is_ok());
+
+    // The connection is still usable afterwards.
+    client.send_instruction(&Instruction::Get {
+        key: b"key1".to_vec(),
+    })?;
+    assert_eq!(client.read_response()?.get_body(), b"value1");
+    Ok(())
+}
+
+fn resp_command(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    command
+}
+
+// Send a RESP command and check the reply.
+fn expect_reply(stream: &mut TcpStream, args: &[&str], expected: &str) -> Result<()> {
+    stream.write_all(&resp_command(args))?;
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        expected,
+        "reply of {:?}",
+        args
+    );
+    Ok(())
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_resp_server(&temp_dir)?;
+    let mut stream = TcpStream::connect(addr)?;
+
+    expect_reply(&mut stream, &["PING"], "+PONG\r\n")?;
+    expect_reply(&mut stream, &["ping", "hello"], "$5\r\nhello\r\n")?;
+    expect_reply(&mut stream, &["SET", "key1", "value1"], "+OK\r\n")?;
+    expect_reply(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n")?;
+    expect_reply(&mut stream, &["GET", "missing"], "$-1\r\n")?;
+    expect_reply(&mut stream, &["SET", "key1", "value2", "NX"], "$-1\r\n")?;
+    expect_reply(&mut stream, &["SET", "key2", "value2", "XX"], "$-1\r\n")?;
+    expect_reply(&mut stream, &["SET", "key1", "value2", "XX"], "+OK\r\n")?;
+    expect_reply(
+        &mut stream,
+        &["SET", "key2", "value2", "PX", "1"],
+        "+OK\r\n",
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    expect_reply(&mut stream, &["GET", "key2"], "$-1\r\n")?;
+    expect_reply(
+        &mut stream,
+        &["MSET", "key2", "value2", "key3", "value3"],
+        "+OK\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["MGET", "key1", "missing", "key3"],
+        "*3\r\n$6\r\nvalue2\r\n$-1\r\n$6\r\nvalue3\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["EXISTS", "key1", "missing", "key1"],
+        ":2\r\n",
+    )?;
+    expect_reply(&mut stream, &["DEL", "key1", "missing"], ":1\r\n")?;
+    expect_reply(&mut stream, &["EXISTS", "key1"], ":0\r\n")?;
+    expect_reply(
+        &mut stream,
+        &["MSET", "key1"],
+        "-ERR wrong number of arguments for 'mset' command\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["FLUSHALL"],
+        "-ERR unknown command 'flushall'\r\n",
+    )?;
+
+    // Inline and pipelined commands.
+    stream.write_all(b"PING\r\nGET key2\r\n")?;
+    let expected = "+PONG\r\n$6\r\nvalue2\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+    Ok(())
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_resp_server(&temp_dir)?;
+    let mut stream = TcpStream::connect(addr)?;
+
+    expect_reply(
+        &mut stream,
+        &["MSET", "a1", "v", "b1", "v", "b2", "v", "c1", "v"],
+        "+OK\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "3"],
+        "*2\r\n$1\r\n3\r\n*3\r\n$2\r\na1\r\n$2\r\nb1\r\n$2\r\nb2\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["SCAN", "3", "COUNT", "3"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nc1\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "b*"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$2\r\nb1\r\n$2\r\nb2\r\n",
+    )?;
+    expect_reply(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "?1"],
+        "*2\r\n$1\r\n0\r\n*3\r\n$2\r\na1\r\n$2\r\nb1\r\n$2\r\nc1\r\n",
+    )?;
+    Ok(())
+}
+
+// Oversized commands are rejected before their arguments are read.
+#[test]
+fn resp_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_resp_server(&temp_dir)?;
+    let requests: [Vec<u8>; 3] = [
+        b"*2000000\r\n".to_vec(),
+        b"*1\r\n$536870913\r\n".to_vec(),
+        // an inline command without line ending.
+        vec![b'a'; 64 * 1024 + 2],
+    ];
+    for request in requests.iter() {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(request)?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        assert!(reply.starts_with("-ERR Protocol error"), "{}", reply);
+    }
+    Ok(())
+}
+
+// Start a server with the HTTP gateway, returns the address of the gateway.
+fn start_http_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let mut server = new_server(temp_dir)?;
+    let addr = server.listen_http("127.0.0.1:0")?;
+    thread::spawn(move || server.serve_forever().unwrap());
+    Ok(addr)
+}
+
+// Send a request in a new connection, returns the status code and the body.
+fn http_request(addr: SocketAddr, method: &str, target: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status: u16 = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    Ok((status, body))
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_http_server(&temp_dir)?;
+
+    assert_eq!(http_request(addr, "GET", "/keys/key1", "")?.0, 404);
+    assert_eq!(http_request(addr, "DELETE", "/keys/key1", "")?.0, 404);
+    assert_eq!(
+        http_request(addr, "PUT", "/keys/key1", r#"{"value":"value1"}"#)?.0,
+        204
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/keys/key1", "")?,
+        (200, String::from(r#"{"key":"key1","value":"value1"}"#))
+    );
+    assert_eq!(
+        http_request(addr, "PUT", "/keys/a%20b", r#"{"value":"v","ttl":60}"#)?.0,
+        204
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/keys/a%20b", "")?,
+        (200, String::from(r#"{"key":"a b","value":"v"}"#))
+    );
+    assert_eq!(http_request(addr, "PUT", "/keys/key1", "{")?.0, 400);
+    assert_eq!(http_request(addr, "POST", "/keys/key1", "")?.0, 405);
+
+    // compare-and-swap
+    let conflict = r#"{"value":"value2","expected":"other"}"#;
+    assert_eq!(http_request(addr, "PUT", "/keys/key1", conflict)?.0, 409);
+    let swap = r#"{"value":"value2","expected":"value1"}"#;
+    assert_eq!(http_request(addr, "PUT", "/keys/key1", swap)?.0, 204);
+    let absent = r#"{"value":"value1","expected":null}"#;
+    assert_eq!(http_request(addr, "PUT", "/keys/key1", absent)?.0, 409);
+    assert_eq!(http_request(addr, "PUT", "/keys/key2", absent)?.0, 204);
+    let remove = r#"{"expected":"value1"}"#;
+    assert_eq!(http_request(addr, "DELETE", "/keys/key1", remove)?.0, 409);
+    assert_eq!(http_request(addr, "DELETE", "/keys/key2", remove)?.0, 204);
+
+    assert_eq!(
+        http_request(addr, "GET", "/keys?prefix=key", "")?,
+        (200, String::from(r#"[{"key":"key1","value":"value2"}]"#))
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/keys", "")?,
+        (
+            200,
+            String::from(r#"[{"key":"a b","value":"v"},{"key":"key1","value":"value2"}]"#)
+        )
+    );
+
+    // `+` is a space only in queries.
+    assert_eq!(
+        http_request(addr, "PUT", "/keys/a+b", r#"{"value":"plus"}"#)?.0,
+        204
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/keys?prefix=a+", "")?,
+        (200, String::from(r#"[{"key":"a b","value":"v"}]"#))
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/keys?prefix=a%2B", "")?,
+        (200, String::from(r#"[{"key":"a+b","value":"plus"}]"#))
+    );
+    Ok(())
+}
+
+// Keys are listed in pages of at most `limit` pairs.
+#[test]
+fn http_list_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_http_server(&temp_dir)?;
+    for key in &["a", "key1", "key2", "key3", "z"] {
+        let body = r#"{"value":"v"}"#;
+        assert_eq!(
+            http_request(addr, "PUT", &format!("/keys/{}", key), body)?.0,
+            204
+        );
+    }
+
+    let keys = |target: &str| -> Result<Vec<String>> {
+        let (status, body) = http_request(addr, "GET", target, "")?;
+        assert_eq!(status, 200, "{}", body);
+        let pairs: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+        Ok(pairs
+            .iter()
+            .map(|pair| pair["key"].as_str().unwrap().to_owned())
+            .collect())
+    };
+    assert_eq!(keys("/keys?prefix=key&limit=2")?, vec!["key1", "key2"]);
+    assert_eq!(keys("/keys?prefix=key&limit=2&after=key2")?, vec!["key3"]);
+    assert_eq!(
+        keys("/keys?prefix=key&after=a")?,
+        vec!["key1", "key2", "key3"]
+    );
+    assert_eq!(keys("/keys?after=key3")?, vec!["z"]);
+    assert_eq!(keys("/keys?limit=0")?, Vec::<String>::new());
+    assert_eq!(http_request(addr, "GET", "/keys?limit=1001", "")?.0, 400);
+    assert_eq!(http_request(addr, "GET", "/keys?limit=x", "")?.0, 400);
+    Ok(())
+}
+
+// Several requests can be sent over one connection.
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_http_server(&temp_dir)?;
+    let mut stream = TcpStream::connect(addr)?;
+
+    let body = r#"{"value":"value1"}"#;
+    write!(
+        stream,
+        "PUT /keys/key1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    write!(
+        stream,
+        "GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(
+        response,
+        "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Length: 31\r\nContent-Type: application/json\r\n\
+         Connection: close\r\n\r\n{\"key\":\"key1\",\"value\":\"value1\"}"
+    );
+    Ok(())
+}
+
+// The server stops after in-flight requests are answered, and the engine is left consistent.
+#[test]
+fn shutdown_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)?;
+    let addr = server.local_addr()?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.serve_forever());
+
+    // an idle connection doesn't block the shutdown.
+    let mut idle_client = Client::connect(&addr.to_string())?;
+    idle_client.send_instruction(&Instruction::Get {
+        key: b"key1".to_vec(),
+    })?;
+    assert!(!idle_client.read_response()?.is_ok());
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in 0..100 {
+        bytes.extend(frame(&Instruction::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+            expire_at: None,
+        }));
+    }
+    stream.write_all(&bytes)?;
+    // wait for the first response, so the requests are received by the server.
+    let mut response_len = [0; 4];
+    stream.read_exact(&mut response_len)?;
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(handle.is_shutdown());
+    assert!(idle_client.read_response().is_err());
+    let mut rest: Vec<u8> = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    // all pipelined requests are answered before the connection is closed.
+    let mut responses: usize = 1;
+    let mut payload_len: usize = u32::from_le_bytes(response_len) as usize;
+    while payload_len < rest.len() {
+        let mut len = [0; 4];
+        len.copy_from_slice(&rest[payload_len..payload_len + 4]);
+        payload_len += 4 + u32::from_le_bytes(len) as usize;
+        responses += 1;
+    }
+    assert_eq!(payload_len, rest.len());
+    assert_eq!(responses, 100);
+    assert!(TcpStream::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Connections of the HTTP gateway are closed as well.
+#[test]
+fn shutdown_http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)?;
+    let http_addr = server.listen_http("127.0.0.1:0")?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.serve_forever());
+
+    let mut stream = TcpStream::connect(http_addr)?;
+    let body = r#"{"value":"value1"}"#;
+    write!(
+        stream,
+        "PUT /keys/key1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    let mut response = [0; 12];
+    stream.read_exact(&mut response)?;
+    assert_eq!(&response, b"HTTP/1.1 204");
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(TcpStream::connect(http_addr).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
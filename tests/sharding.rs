@@ -0,0 +1,216 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::start_server;
+use kvs::{Client, ClientConfig, KvsEngine, Result, ShardedClient, WriteBatch};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_servers(temp_dirs: &[TempDir]) -> Result<Vec<String>> {
+    temp_dirs
+        .iter()
+        .map(|temp_dir| Ok(start_server(temp_dir)?.to_string()))
+        .collect()
+}
+
+fn temp_dirs(count: usize) -> Vec<TempDir> {
+    (0..count)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect()
+}
+
+// Keys stored on the server itself.
+fn keys_of(server: &str) -> Result<Vec<String>> {
+    Client::connect(server)?
+        .scan_prefix("")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect()
+}
+
+// Keys are spread across servers, each one is stored on the server it belongs to.
+#[test]
+fn route_keys() -> Result<()> {
+    let temp_dirs = temp_dirs(3);
+    let servers = start_servers(&temp_dirs)?;
+    let client = ShardedClient::new(servers.clone(), ClientConfig::default())?;
+    for i in 0..300 {
+        client.set(format!("key{:03}", i), i.to_string())?;
+    }
+    for i in 0..300 {
+        assert_eq!(client.get(format!("key{:03}", i))?, Some(i.to_string()));
+    }
+    let mut total: usize = 0;
+    for server in &servers {
+        let keys = keys_of(server)?;
+        assert!(keys.len() > 30, "{} has only {} keys", server, keys.len());
+        assert!(keys
+            .iter()
+            .all(|key| client.server_of(key.as_bytes()) == server));
+        total += keys.len();
+    }
+    assert_eq!(total, 300);
+
+    client.remove("key000".to_owned())?;
+    assert_eq!(client.get("key000".to_owned())?, None);
+    assert!(client.remove("key000".to_owned()).is_err());
+    assert!(client.compare_and_swap(
+        "key001".to_owned(),
+        Some("1".to_owned()),
+        Some("swapped".to_owned())
+    )?);
+    assert!(!client.compare_and_swap("key002".to_owned(), None, Some("new".to_owned()))?);
+
+    // the order of servers doesn't matter.
+    let mut reversed = servers.clone();
+    reversed.reverse();
+    let other = ShardedClient::new(reversed, ClientConfig::default())?;
+    assert_eq!(other.get("key001".to_owned())?, Some("swapped".to_owned()));
+    assert!(ShardedClient::new(Vec::new(), ClientConfig::default()).is_err());
+    Ok(())
+}
+
+// Batches and `get_many` are split by servers, scans are merged from all servers.
+#[test]
+fn fan_out_and_scan() -> Result<()> {
+    let temp_dirs = temp_dirs(3);
+    let servers = start_servers(&temp_dirs)?;
+    let client = ShardedClient::new(servers, ClientConfig::default())?;
+    let mut batch = WriteBatch::new();
+    for i in 0..500 {
+        batch.set(format!("key{:03}", i), i.to_string());
+    }
+    batch.remove("key000");
+    client.write_batch(batch)?;
+
+    let keys: Vec<String> = vec![
+        "key010".to_owned(),
+        "key000".to_owned(),
+        "key499".to_owned(),
+    ];
+    assert_eq!(
+        client.get_many(&keys)?,
+        vec![Some("10".to_owned()), None, Some("499".to_owned())]
+    );
+
+    let pairs: Vec<(String, String)> = client.scan_prefix("key")?.collect::<Result<_>>()?;
+    let expected: Vec<(String, String)> = (1..500)
+        .map(|i| (format!("key{:03}", i), i.to_string()))
+        .collect();
+    assert_eq!(pairs, expected);
+    let keys: Vec<String> = client
+        .scan("key100".to_owned().."key105".to_owned())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key100", "key101", "key102", "key103", "key104"]);
+    assert_eq!(client.scan_prefix("other")?.count(), 0);
+    Ok(())
+}
+
+// Adding a server moves a fraction of keys, `rebalance` moves exactly them.
+#[test]
+fn rebalance_after_adding_server() -> Result<()> {
+    let temp_dirs = temp_dirs(4);
+    let servers = start_servers(&temp_dirs)?;
+    let client = ShardedClient::new(servers[..3].to_vec(), ClientConfig::default())?;
+    for i in 0..1000 {
+        client.set(format!("key{:04}", i), i.to_string())?;
+    }
+    // odd keys expire.
+    for i in (1..1000).step_by(2) {
+        let ttl = Duration::from_secs(600);
+        client.set_with_ttl(format!("key{:04}", i), i.to_string(), ttl)?;
+    }
+
+    let grown = ShardedClient::new(servers.clone(), ClientConfig::default())?;
+    let moving: usize = (0..1000)
+        .map(|i| format!("key{:04}", i))
+        .filter(|key| client.server_of(key.as_bytes()) != grown.server_of(key.as_bytes()))
+        .count();
+    // about a quarter of keys belong to the new server, no key moves between old servers.
+    assert!(moving > 100 && moving < 400, "{} keys move", moving);
+    assert!((0..1000).map(|i| format!("key{:04}", i)).all(|key| {
+        let owner = grown.server_of(key.as_bytes());
+        owner == servers[3] || owner == client.server_of(key.as_bytes())
+    }));
+
+    assert_eq!(grown.rebalance()?, moving);
+    assert_eq!(keys_of(&servers[3])?.len(), moving);
+    for server in &servers {
+        assert!(keys_of(server)?
+            .iter()
+            .all(|key| grown.server_of(key.as_bytes()) == server));
+    }
+    for i in 0..1000 {
+        let key = format!("key{:04}", i);
+        assert_eq!(grown.get(key.clone())?, Some(i.to_string()));
+        // moved keys keep their expire times.
+        let ttl: Option<Duration> = grown.ttl_bytes(key.as_bytes())?;
+        assert_eq!(ttl.is_some(), i % 2 == 1, "ttl of {}", key);
+        assert!(ttl.is_none_or(|ttl| ttl > Duration::from_secs(500)));
+    }
+    assert_eq!(grown.rebalance()?, 0);
+    Ok(())
+}
+
+// `kvs-client --servers` shards keys across the servers.
+#[test]
+fn cli_servers() {
+    let temp_dirs = temp_dirs(2);
+    let mut servers: Vec<std::process::Child> = ["127.0.0.1:4019", "127.0.0.1:4020"]
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, temp_dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr])
+                .current_dir(temp_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let servers_arg = "127.0.0.1:4019,127.0.0.1:4020";
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), &i.to_string()])
+            .args(["--servers", servers_arg])
+            .current_dir(&temp_dirs[0])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key7", "--servers", servers_arg])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout("7\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key7", "--servers", servers_arg])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key7", "--servers", servers_arg])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    let counts: Vec<usize> = ["127.0.0.1:4019", "127.0.0.1:4020"]
+        .iter()
+        .map(|addr| keys_of(addr).unwrap().len())
+        .collect();
+    assert_eq!(counts.iter().sum::<usize>(), 19);
+    assert!(counts.iter().all(|&count| count > 0));
+
+    for server in servers.iter_mut() {
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
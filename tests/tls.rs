@@ -0,0 +1,262 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{new_server, spawn_server};
+use kvs::command::Instruction;
+use kvs::{Client, ClientConfig, ClientPool, ClientTls, KvsEngine, Result, ServerTls};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Self-signed certificates, they are generated for each test.
+struct Certs {
+    dir: TempDir,
+}
+
+impl Certs {
+    // Write PEM files of a CA, a server certificate for localhost and 127.0.0.1, and a client
+    // certificate which are signed by the CA, and another CA which signs nothing.
+    fn generate() -> Certs {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let (ca, ca_key) = new_ca("kvs test CA");
+        let (other_ca, _) = new_ca("other CA");
+        fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+        fs::write(dir.path().join("other-ca.pem"), other_ca.pem()).unwrap();
+
+        for (name, purpose) in &[
+            ("server", ExtendedKeyUsagePurpose::ServerAuth),
+            ("client", ExtendedKeyUsagePurpose::ClientAuth),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let mut params =
+                CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+                    .unwrap();
+            params.distinguished_name.push(DnType::CommonName, *name);
+            params.extended_key_usages = vec![purpose.clone()];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(dir.path().join(format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(
+                dir.path().join(format!("{}.key", name)),
+                key.serialize_pem(),
+            )
+            .unwrap();
+        }
+        Certs { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn server_tls(&self) -> Result<ServerTls> {
+        ServerTls::from_pem_files(&self.path("server.pem"), &self.path("server.key"))
+    }
+
+    fn client_config(&self, tls: ClientTls) -> ClientConfig {
+        ClientConfig {
+            tls: Some(tls),
+            ..ClientConfig::default()
+        }
+    }
+}
+
+fn new_ca(name: &str) -> (Certificate, KeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = params.self_signed(&key).unwrap();
+    (cert, key)
+}
+
+// Start a TLS server on a random port in background, it runs until the test exits.
+fn start_server(temp_dir: &TempDir, tls: ServerTls) -> Result<SocketAddr> {
+    let mut server = new_server(temp_dir)?;
+    server.set_tls(tls);
+    spawn_server(server)
+}
+
+// `Client` works over TLS, with pipelines as well.
+#[test]
+fn client_over_tls() -> Result<()> {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, certs.server_tls()?)?;
+    let tls = ClientTls::from_pem_file(&certs.path("ca.pem"))?;
+    let mut client = Client::connect_with(&addr.to_string(), &certs.client_config(tls.clone()))?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    let instructions: Vec<Instruction> = (0..300)
+        .map(|i| Instruction::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+            expire_at: None,
+        })
+        .collect();
+    let responses = client.pipeline(&instructions)?;
+    assert!(responses.iter().all(|response| response.is_ok()));
+    assert_eq!(client.scan_prefix("key")?.count(), 300);
+
+    // the host name is verified as well.
+    let addr = format!("localhost:{}", addr.port());
+    let client = Client::connect_with(&addr, &certs.client_config(tls))?;
+    assert_eq!(
+        client.get("key299".to_owned())?,
+        Some("value299".to_owned())
+    );
+    Ok(())
+}
+
+// `ClientPool` reuses TLS connections.
+#[test]
+fn client_pool_over_tls() -> Result<()> {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, certs.server_tls()?)?;
+    let tls = ClientTls::from_pem_file(&certs.path("ca.pem"))?;
+    let pool = ClientPool::new(&addr.to_string(), certs.client_config(tls));
+
+    for i in 0..10 {
+        pool.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(pool.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(pool.idle_connections(), 1);
+    Ok(())
+}
+
+// Plain TCP clients can't talk to TLS servers, and clients don't trust unknown servers.
+#[test]
+fn reject_plain_and_untrusted() -> Result<()> {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, certs.server_tls()?)?;
+    let config = ClientConfig {
+        read_timeout: Some(Duration::from_secs(5)),
+        ..ClientConfig::default()
+    };
+
+    let client = Client::connect_with(&addr.to_string(), &config)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let tls = ClientTls::from_pem_file(&certs.path("other-ca.pem"))?;
+    let client = Client::connect_with(&addr.to_string(), &certs.client_config(tls))?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    Ok(())
+}
+
+// Servers which require client certificates reject clients without them.
+#[test]
+fn mutual_tls() -> Result<()> {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let tls = ServerTls::with_client_auth(
+        &certs.path("server.pem"),
+        &certs.path("server.key"),
+        &certs.path("ca.pem"),
+    )?;
+    let addr = start_server(&temp_dir, tls)?;
+
+    let tls = ClientTls::from_pem_file(&certs.path("ca.pem"))?;
+    let client = Client::connect_with(&addr.to_string(), &certs.client_config(tls))?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let tls = ClientTls::with_identity(
+        &certs.path("ca.pem"),
+        &certs.path("client.pem"),
+        &certs.path("client.key"),
+    )?;
+    let client = Client::connect_with(&addr.to_string(), &certs.client_config(tls))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Invalid PEM files are reported.
+#[test]
+fn invalid_pem_files() {
+    let certs = Certs::generate();
+    assert!(
+        ServerTls::from_pem_files(&certs.path("server.key"), &certs.path("server.key")).is_err()
+    );
+    assert!(
+        ServerTls::from_pem_files(&certs.path("server.pem"), &certs.path("server.pem")).is_err()
+    );
+    assert!(ClientTls::from_pem_file(&certs.path("missing.pem")).is_err());
+    assert!(ClientTls::from_pem_file(Path::new("Cargo.toml")).is_err());
+}
+
+// `kvs-server` serves over TLS with `--tls-cert` and `--tls-key`, and `kvs-client` connects
+// with `--tls-ca`.
+#[test]
+fn cli_over_tls() {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().unwrap();
+    let path = |name: &str| certs.path(name).to_str().unwrap().to_owned();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4012"])
+        .args([
+            "--tls-cert",
+            &path("server.pem"),
+            "--tls-key",
+            &path("server.key"),
+        ])
+        .args(["--tls-client-ca", &path("ca.pem")])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let tls_args = [
+        "--tls-ca",
+        &path("ca.pem"),
+        "--tls-cert",
+        &path("client.pem"),
+        "--tls-key",
+        &path("client.key"),
+    ];
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4012"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4012"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // no client certificate.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4012"])
+        .args(["--tls-ca", &path("ca.pem"), "--timeout", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    // plain TCP.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4012", "--timeout", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
@@ -0,0 +1,345 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::new_server;
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AuthConfig, Client, ClientConfig, Credentials, ErrorCode, KvStore, KvsEngine, RaftConfig,
+    Result, Server, ShutdownHandle, WatchEvent, Watcher, WriteBatch,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    fn start(mut server: Server<KvStore, NaiveThreadPool>) -> Result<TestServer> {
+        Ok(TestServer {
+            addr: server.local_addr()?,
+            shutdown: server.shutdown_handle(),
+            thread: thread::spawn(move || server.serve_forever()),
+        })
+    }
+
+    fn stop(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.thread.join().unwrap()
+    }
+}
+
+// Watchers fail instead of hanging if an event never comes.
+fn watch(addr: SocketAddr, prefix: &str) -> Result<Watcher> {
+    let config = ClientConfig {
+        read_timeout: Some(Duration::from_secs(5)),
+        ..ClientConfig::default()
+    };
+    Client::connect_with(&addr.to_string(), &config)?.watch(prefix.as_bytes())
+}
+
+fn set(key: &str, value: &str) -> WatchEvent {
+    WatchEvent::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    }
+}
+
+fn remove(key: &str) -> WatchEvent {
+    WatchEvent::Remove {
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+fn next_events(watcher: &mut Watcher, count: usize) -> Result<Vec<WatchEvent>> {
+    watcher.take(count).collect()
+}
+
+// Changes of keys with the prefix are streamed in the order they are applied.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::start(new_server(&temp_dir)?)?;
+    let mut users: Watcher = watch(server.addr, "user/")?;
+    let mut all: Watcher = watch(server.addr, "")?;
+
+    let client = Client::connect(&server.addr.to_string())?;
+    client.set("user/1".to_owned(), "a".to_owned())?;
+    client.set("other".to_owned(), "b".to_owned())?;
+    client.remove("user/1".to_owned())?;
+    assert!(client.remove("user/1".to_owned()).is_err());
+    let mut batch = WriteBatch::new();
+    batch.set("user/2", "c");
+    batch.remove("other");
+    batch.set("user/3", "d");
+    client.write_batch(batch)?;
+    assert!(client.compare_and_swap(
+        "user/2".to_owned(),
+        Some("c".to_owned()),
+        Some("e".to_owned())
+    )?);
+    assert!(!client.compare_and_swap("user/2".to_owned(), None, Some("f".to_owned()))?);
+    assert!(client.compare_and_swap("user/3".to_owned(), Some("d".to_owned()), None)?);
+    client.set_with_ttl("user/4".to_owned(), "g".to_owned(), Duration::from_secs(60))?;
+
+    assert_eq!(
+        next_events(&mut users, 6)?,
+        vec![
+            set("user/1", "a"),
+            remove("user/1"),
+            set("user/2", "c"),
+            set("user/3", "d"),
+            set("user/2", "e"),
+            remove("user/3"),
+        ]
+    );
+    assert_eq!(
+        next_events(&mut all, 5)?,
+        vec![
+            set("user/1", "a"),
+            set("other", "b"),
+            remove("user/1"),
+            set("user/2", "c"),
+            remove("other"),
+        ]
+    );
+    assert_eq!(users.next().transpose()?, Some(set("user/4", "g")));
+
+    // heartbeats keep idle watchers alive.
+    thread::sleep(Duration::from_secs(2));
+    client.set("user/5".to_owned(), "h".to_owned())?;
+    assert_eq!(users.next().transpose()?, Some(set("user/5", "h")));
+
+    // watchers are disconnected once the server is stopped.
+    drop(all);
+    server.stop()?;
+    assert!(users.next().is_none());
+    Ok(())
+}
+
+// Events of concurrent writes to the same keys are in the order they are applied, so the last
+// event of each key is its final value.
+#[test]
+fn watch_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = TestServer::start(new_server(&temp_dir)?)?;
+    let mut watcher: Watcher = watch(server.addr, "key")?;
+
+    let writers: Vec<JoinHandle<Result<()>>> = (0..4)
+        .map(|i| {
+            let addr = server.addr;
+            thread::spawn(move || {
+                let client = Client::connect(&addr.to_string())?;
+                for j in 0..500 {
+                    client.set(format!("key{}", j / 10), i.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let mut last: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for event in next_events(&mut watcher, 2000)? {
+        if let WatchEvent::Set { key, value } = event {
+            last.insert(key, value);
+        }
+    }
+    let client = Client::connect(&server.addr.to_string())?;
+    for pair in client.scan_prefix_bytes(b"key")? {
+        let (key, value) = pair?;
+        assert_eq!(last.get(&key), Some(&value));
+    }
+    assert_eq!(last.len(), 50);
+    Ok(())
+}
+
+// Watchers don't hold workers of the thread pool, other clients are still served.
+#[test]
+fn watchers_on_own_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(1)?;
+    let mut server = Server::new("127.0.0.1:0", KvStore::open(temp_dir.path())?, pool)?;
+    let addr: SocketAddr = server.local_addr()?;
+    let shutdown: ShutdownHandle = server.shutdown_handle();
+    let thread = thread::spawn(move || server.serve_forever());
+
+    let mut watchers: Vec<Watcher> = (0..3).map(|_| watch(addr, "key")).collect::<Result<_>>()?;
+    let client = Client::connect(&addr.to_string())?;
+    client.set("key1".to_owned(), "a".to_owned())?;
+    for watcher in watchers.iter_mut() {
+        assert_eq!(watcher.next().transpose()?, Some(set("key1", "a")));
+    }
+    drop(client);
+    shutdown.shutdown();
+    thread.join().unwrap()
+}
+
+// Watchers only receive changes of keys they can read.
+#[test]
+fn watch_with_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("auth.json");
+    fs::write(
+        &path,
+        r#"{
+            "users": [
+                {"name": "admin", "token": "admin-token", "rules": [{"prefix": "", "access": "read_write"}]},
+                {"name": "billing", "token": "billing-token", "rules": [{"prefix": "billing/", "access": "read"}]}
+            ]
+        }"#,
+    )?;
+    let mut server = new_server(&temp_dir)?;
+    server.set_auth(AuthConfig::from_file(&path)?);
+    let server = TestServer::start(server)?;
+    let connect = |token: &str| {
+        let config = ClientConfig {
+            read_timeout: Some(Duration::from_secs(5)),
+            credentials: Some(Credentials::Token(token.to_owned())),
+            ..ClientConfig::default()
+        };
+        Client::connect_with(&server.addr.to_string(), &config)
+    };
+
+    let error = Client::connect(&server.addr.to_string())?
+        .watch(b"")
+        .err()
+        .unwrap();
+    assert_eq!(error.code(), ErrorCode::Unauthorized);
+
+    let mut watcher: Watcher = connect("billing-token")?.watch(b"")?;
+    let admin = connect("admin-token")?;
+    admin.set("orders/1".to_owned(), "a".to_owned())?;
+    admin.set("billing/1".to_owned(), "b".to_owned())?;
+    admin.remove("orders/1".to_owned())?;
+    admin.remove("billing/1".to_owned())?;
+    assert_eq!(
+        next_events(&mut watcher, 2)?,
+        vec![set("billing/1", "b"), remove("billing/1")]
+    );
+    Ok(())
+}
+
+// Replicas publish writes of the leader once they are replicated.
+#[test]
+fn watch_replica() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader_engine = KvStore::open(leader_dir.path())?;
+    leader_engine.set("key1".to_owned(), "old".to_owned())?;
+    leader_engine.set("key2".to_owned(), "same".to_owned())?;
+    drop(leader_engine);
+    // the replica has a stale key, and a key of the same value.
+    let replica_engine = KvStore::open(replica_dir.path())?;
+    replica_engine.set("key2".to_owned(), "same".to_owned())?;
+    replica_engine.set("stale".to_owned(), "value".to_owned())?;
+    drop(replica_engine);
+
+    // the leader is started once the watcher is connected, so it sees the snapshot.
+    let leader = new_server(&leader_dir)?;
+    let leader_addr: SocketAddr = leader.local_addr()?;
+    let mut replica = new_server(&replica_dir)?;
+    replica.set_replica_of(&leader_addr.to_string(), ClientConfig::default());
+    let replica = TestServer::start(replica)?;
+    let mut watcher: Watcher = watch(replica.addr, "")?;
+    let leader = TestServer::start(leader)?;
+    let client = Client::connect(&leader.addr.to_string())?;
+
+    // changes by the snapshot are published, unchanged pairs are not.
+    let mut events: Vec<WatchEvent> = next_events(&mut watcher, 2)?;
+    events.sort_by(|a, b| a.key().cmp(b.key()));
+    assert_eq!(events, vec![set("key1", "old"), remove("stale")]);
+
+    client.set("key1".to_owned(), "new".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert_eq!(
+        next_events(&mut watcher, 2)?,
+        vec![set("key1", "new"), remove("key2")]
+    );
+    replica.stop()?;
+    leader.stop()
+}
+
+// Nodes of a cluster publish entries once they are committed.
+#[test]
+fn watch_cluster() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = new_server(&temp_dir)?;
+    let addr = server.local_addr()?;
+    let raft_dir = temp_dir.path().join("raft");
+    let handle = server.set_cluster(RaftConfig::new(vec![addr.to_string()], 0, &raft_dir))?;
+    let server = TestServer::start(server)?;
+    for _ in 0..200 {
+        if handle.is_leader() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(handle.is_leader());
+
+    let mut watcher: Watcher = watch(addr, "key")?;
+    let client = Client::connect(&addr.to_string())?;
+    client.set("key1".to_owned(), "a".to_owned())?;
+    client.set("other".to_owned(), "b".to_owned())?;
+    assert!(client.compare_and_swap("key1".to_owned(), Some("a".to_owned()), None)?);
+    assert!(!client.compare_and_swap("key1".to_owned(), Some("a".to_owned()), None)?);
+    client.set("key2".to_owned(), "c".to_owned())?;
+    assert_eq!(
+        next_events(&mut watcher, 3)?,
+        vec![set("key1", "a"), remove("key1"), set("key2", "c")]
+    );
+    server.stop()
+}
+
+// `kvs-client watch` prints changes until the connection is closed.
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "user/", "--addr", "127.0.0.1:4021"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let stdout = watcher.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Client::connect("127.0.0.1:4021").unwrap();
+    client.set("user/1".to_owned(), "a".to_owned()).unwrap();
+    client.set("other".to_owned(), "b".to_owned()).unwrap();
+    client.remove("user/1".to_owned()).unwrap();
+    let lines: Vec<String> = (0..2)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(lines, vec!["set user/1 a", "rm user/1"]);
+
+    // the watcher exits once the connection is closed.
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    watcher.wait().unwrap();
+}